@@ -0,0 +1,284 @@
+//! Graph JSON batching, which packs several requests into one `POST /$batch`
+//! round trip. See <https://learn.microsoft.com/graph/json-batching>.
+
+use std::{collections::HashMap, marker::PhantomData, time::Duration};
+
+use reqwest::StatusCode;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::http::DurableClient;
+use crate::oauth::SharedAccessToken;
+use crate::status;
+
+/// The most requests Graph accepts in one batch
+const MAX_BATCH_SIZE: usize = 20;
+/// How often a throttled or failed request is sent before giving up on it
+const MAX_ATTEMPTS: usize = 3;
+/// How long to wait before a retry when Graph doesn't say
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Requests to send together. Each one hands out a `Slot` to take its typed
+/// result from the `BatchResponses`.
+#[derive(Clone, Debug, Default)]
+pub struct Batch {
+    requests: Vec<BatchRequest>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct BatchRequest {
+    id: String,
+    method: &'static str,
+    /// Relative to the Graph URL, e.g. `/me/presence`
+    url: String,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    headers: HashMap<String, String>,
+}
+
+/// Where the response to one request of a `Batch` ends up
+#[derive(Debug)]
+pub struct Slot<T> {
+    id: String,
+    response: PhantomData<fn() -> T>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct BatchResponseBody {
+    responses: Vec<SubResponse>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct SubResponse {
+    id: String,
+    status: u16,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    #[serde(default)]
+    body: serde_json::Value,
+}
+
+/// The responses to a sent `Batch`
+#[derive(Clone, Debug)]
+pub struct BatchResponses {
+    responses: HashMap<String, SubResponse>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Batch::default()
+    }
+
+    /// Adds a GET of `path`, which is relative to the Graph URL
+    pub fn get<T: DeserializeOwned>(&mut self, path: &str) -> Slot<T> {
+        self.get_with_headers(path, &[])
+    }
+
+    pub fn get_with_headers<T: DeserializeOwned>(
+        &mut self,
+        path: &str,
+        headers: &[(&str, &str)],
+    ) -> Slot<T> {
+        let id = (self.requests.len() + 1).to_string();
+        self.requests.push(BatchRequest {
+            id: id.clone(),
+            method: "GET",
+            url: path.into(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        });
+        Slot {
+            id,
+            response: PhantomData,
+        }
+    }
+
+    /// Sends the requests, `MAX_BATCH_SIZE` at a time. Requests that were
+    /// throttled or hit a server error are sent again in a smaller batch,
+    /// after the longest `Retry-After` among them. The batch itself failing
+    /// fails every request.
+    #[tracing::instrument(skip(self), fields(requests = self.requests.len()))]
+    pub async fn send(
+        self,
+        client: &DurableClient,
+        token: &SharedAccessToken,
+        graph_url: &str,
+    ) -> Result<BatchResponses> {
+        let mut responses = HashMap::new();
+        let mut pending = self.requests;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let mut retry = vec![];
+            let mut retry_after = Duration::ZERO;
+            for chunk in pending.chunks(MAX_BATCH_SIZE) {
+                let body: BatchResponseBody = status::graph_send(
+                    client
+                        .post(format!("{}/$batch", graph_url))
+                        .json(&serde_json::json!({ "requests": chunk })),
+                    token,
+                )
+                .await?;
+                for response in body.responses {
+                    let request = chunk.iter().find(|req| req.id == response.id);
+                    match request {
+                        Some(request) if response.is_retriable() && attempt < MAX_ATTEMPTS => {
+                            retry_after = retry_after
+                                .max(response.retry_after().unwrap_or(DEFAULT_RETRY_AFTER));
+                            retry.push(request.clone());
+                        }
+                        _ => {
+                            responses.insert(response.id.clone(), response);
+                        }
+                    }
+                }
+            }
+            if retry.is_empty() {
+                break;
+            }
+            tracing::warn!(
+                "Retrying {} of the batched Graph requests in {} seconds",
+                retry.len(),
+                retry_after.as_secs()
+            );
+            tokio::time::sleep(retry_after).await;
+            pending = retry;
+        }
+        Ok(BatchResponses { responses })
+    }
+}
+
+impl BatchResponses {
+    /// The result of the request behind `slot`. A failed request becomes an
+    /// `Error::GraphStatus`, just like an unbatched one.
+    pub fn take<T: DeserializeOwned>(&mut self, slot: Slot<T>) -> Result<T> {
+        let response = self.responses.remove(&slot.id).ok_or_else(|| {
+            Error::GraphDeserialize(serde::de::Error::custom(format!(
+                "The batch has no response for request {}",
+                slot.id
+            )))
+        })?;
+        let status = StatusCode::from_u16(response.status).map_err(|_| {
+            Error::GraphDeserialize(serde::de::Error::custom(format!(
+                "Request {} has an invalid status {}",
+                slot.id, response.status
+            )))
+        })?;
+        if !status.is_success() {
+            return Err(Error::GraphStatus {
+                status,
+                retry_after: response.retry_after(),
+                body: response.body.to_string(),
+            });
+        }
+        serde_json::from_value(response.body).map_err(Error::GraphDeserialize)
+    }
+}
+
+impl SubResponse {
+    fn is_retriable(&self) -> bool {
+        self.status == StatusCode::TOO_MANY_REQUESTS.as_u16() || self.status >= 500
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        self.headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("retry-after"))
+            .and_then(|(_, value)| value.parse::<u64>().ok())
+            .map(Duration::from_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{extract::Extension, routing::post, Json, Router};
+
+    use super::*;
+    use crate::http::build_graph_client;
+    use crate::oauth::test_token;
+    use crate::test_support::spawn_fake_graph;
+
+    /// The request URLs of each batch the fake received
+    type Batches = Arc<Mutex<Vec<Vec<String>>>>;
+
+    /// Throttles the calendar the first time it's asked for, and remembers
+    /// which requests each batch held
+    async fn fake_batch(
+        Extension(batches): Extension<Batches>,
+        Json(body): Json<serde_json::Value>,
+    ) -> Json<serde_json::Value> {
+        let requests = body["requests"].as_array().unwrap();
+        let first = batches.lock().unwrap().is_empty();
+        batches.lock().unwrap().push(
+            requests
+                .iter()
+                .map(|req| req["url"].as_str().unwrap().to_string())
+                .collect(),
+        );
+        let responses: Vec<serde_json::Value> = requests
+            .iter()
+            .map(|req| match req["url"].as_str().unwrap() {
+                "/me/presence" => serde_json::json!({
+                    "id": req["id"],
+                    "status": 200,
+                    "body": { "id": "1", "availability": "Busy", "activity": "InAMeeting" },
+                }),
+                "/me/calendarview" if first => serde_json::json!({
+                    "id": req["id"],
+                    "status": 429,
+                    "headers": { "Retry-After": "0" },
+                    "body": { "error": { "code": "TooManyRequests" } },
+                }),
+                "/me/calendarview" => serde_json::json!({
+                    "id": req["id"],
+                    "status": 200,
+                    "body": { "value": [] },
+                }),
+                _ => serde_json::json!({
+                    "id": req["id"],
+                    "status": 404,
+                    "body": { "error": { "code": "ResourceNotFound" } },
+                }),
+            })
+            .collect();
+        Json(serde_json::json!({ "responses": responses }))
+    }
+
+    #[tokio::test]
+    async fn test_retries_only_the_throttled_requests() {
+        let batches = Batches::default();
+        let graph_url = spawn_fake_graph(
+            Router::new()
+                .route("/$batch", post(fake_batch))
+                .layer(Extension(batches.clone())),
+        );
+        let token = test_token();
+
+        let mut batch = Batch::new();
+        let presence = batch.get::<status::Presence>("/me/presence");
+        let calendar = batch.get::<status::CalendarView>("/me/calendarview");
+        let missing = batch.get::<serde_json::Value>("/me/todo/lists");
+        let mut responses = batch
+            .send(&build_graph_client(), &token, &graph_url)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            responses.take(presence).unwrap().availability,
+            status::Availability::Busy
+        );
+        assert!(responses.take(calendar).unwrap().value.is_empty());
+        assert!(matches!(
+            responses.take(missing),
+            Err(Error::GraphStatus { status, .. }) if status == StatusCode::NOT_FOUND
+        ));
+        assert_eq!(
+            *batches.lock().unwrap(),
+            vec![
+                vec!["/me/presence", "/me/calendarview", "/me/todo/lists"],
+                vec!["/me/calendarview"],
+            ]
+        );
+    }
+}
@@ -1,13 +1,82 @@
+#[cfg(unix)]
+use crate::control::{self, ControlRequest, OverrideColor, Until};
 use crate::{
-    http::DurableClient,
-    oauth::{self, OAuthConfiguration, SharedAccessToken},
-    status,
+    client_auth::{ClientCertificate, ClientCredential, ClientSecret},
+    control::OverrideStore,
+    discovery::{self, DiscoveryOptions, Subnet},
+    error::{Error, Result},
+    http::{self, DurableClient},
+    notifications::{self, NotificationOptions},
+    oauth::{self, OAuthConfiguration, SharedAccessToken, TokenHealth},
+    pico::{DeliveredScreens, PicoTarget, ScreenLayout, SharedPicoTargets},
+    rules::Rules,
+    source::{
+        caldav::{CalDavSource, Password},
+        device::DeviceSource,
+        event_store::EventStore,
+        graph::GraphSource,
+        ics::{IcsLocation, IcsSource},
+        idle::{IdleSource, InputIdleHints, LogindHints},
+        slack::{SlackSource, SlackToken, DEFAULT_SLACK_API_URL},
+        Aggregator, MergeRule,
+    },
+    status::{self, Status},
+    template::{LineTemplate, Templates},
+    token_cache::TokenCache,
 };
+use chrono::{Local, Timelike, Utc};
 pub use clap::Parser;
-use tokio::time::Duration;
+use clap::{CommandFactory, ErrorKind, ValueEnum};
+use reqwest::Url;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::PathBuf,
+};
+use tokio::time::{Duration, Instant};
 use tracing::Level;
 
-pub fn init_tracing(cli: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+/// How long to back off when Graph throttles us without a `Retry-After`
+const THROTTLE_BACKOFF: Duration = Duration::from_secs(60);
+/// Consecutive failed pushes before a discovered Pico is looked up again
+const REDISCOVER_AFTER_FAILURES: usize = 3;
+const SIGN_IN_BACKOFF_MIN: Duration = Duration::from_secs(60);
+const SIGN_IN_BACKOFF_MAX: Duration = Duration::from_secs(3600);
+
+/// Spaces out sign in attempts that keep failing, as each one opens a browser
+/// or asks for a new device code
+#[derive(Debug)]
+struct SignInBackoff {
+    backoff: Duration,
+    next_attempt: Instant,
+}
+
+impl SignInBackoff {
+    fn new(now: Instant) -> Self {
+        SignInBackoff {
+            backoff: Duration::ZERO,
+            next_attempt: now,
+        }
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        now >= self.next_attempt
+    }
+
+    fn succeeded(&mut self, now: Instant) {
+        *self = SignInBackoff::new(now);
+    }
+
+    fn failed(&mut self, now: Instant) {
+        self.backoff = match self.backoff.is_zero() {
+            true => SIGN_IN_BACKOFF_MIN,
+            false => oauth::next_backoff(self.backoff, SIGN_IN_BACKOFF_MAX),
+        };
+        self.next_attempt = now + self.backoff;
+    }
+}
+
+pub fn init_tracing(cli: &Cli) -> Result<()> {
     let log_level = if cli.is_verbose() {
         Level::TRACE
     } else {
@@ -20,24 +89,167 @@ pub fn init_tracing(cli: &Cli) -> Result<(), Box<dyn std::error::Error>> {
         .with_file(true)
         .with_target(true)
         .finish();
-    tracing::subscriber::set_global_default(subscriber)?;
+    tracing::subscriber::set_global_default(subscriber)
+        .map_err(|err| Error::Config(err.to_string()))?;
     Ok(())
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum AuthMode {
+    /// Opens a local browser and receives the code on a local redirect server
+    Browser,
+    /// Prints a code to enter on another device. Suitable for headless Pis
+    DeviceCode,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Command {
+    /// Look for Picos on the local network, print their addresses and exit
+    Discover(DiscoveryArgs),
+    /// Show a manual status on the Pico instead of the computed one
+    #[cfg(unix)]
+    Override(OverrideArgs),
+    /// Cancel the manual status set with `override`
+    #[cfg(unix)]
+    Clear(ControlArgs),
+}
+
+#[cfg(unix)]
+#[derive(Clone, Debug, clap::Args)]
+pub struct ControlArgs {
+    #[clap(
+        long,
+        value_parser,
+        help = "The Unix socket the running pico-client listens on for overrides. Defaults to pico-client.sock in the runtime directory"
+    )]
+    control_socket: Option<PathBuf>,
+}
+
+#[cfg(unix)]
+impl ControlArgs {
+    pub fn get_socket_path(&self) -> PathBuf {
+        self.control_socket
+            .clone()
+            .unwrap_or_else(control::default_socket_path)
+    }
+}
+
+#[cfg(unix)]
+#[derive(Clone, Debug, clap::Args)]
+#[clap(group(
+    clap::ArgGroup::new("expiry")
+        .required(true)
+        .args(&["duration", "until-meeting-ends"])
+))]
+pub struct OverrideArgs {
+    #[clap(value_enum, help = "The color to show")]
+    color: OverrideColor,
+
+    #[clap(long, value_parser, help = "A message to show with it, e.g. \"Focus\"")]
+    text: Option<String>,
+
+    #[clap(
+        long = "for",
+        value_parser = control::parse_duration,
+        value_name = "DURATION",
+        help = "How long the override lasts, e.g. 45m or 1h30m"
+    )]
+    duration: Option<chrono::Duration>,
+
+    #[clap(
+        long,
+        action,
+        help = "Lasts until the meeting that is going on now ends"
+    )]
+    until_meeting_ends: bool,
+
+    #[clap(flatten)]
+    control: ControlArgs,
+}
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct DiscoveryArgs {
+    #[clap(
+        long,
+        value_parser,
+        default_value = "pico-status",
+        help = "The mDNS hostname the Pico firmware announces, without '.local'"
+    )]
+    mdns_hostname: String,
+
+    #[clap(
+        long,
+        action,
+        help = "When mDNS finds nothing, probe every host of the subnet for the Pico firmware"
+    )]
+    scan: bool,
+
+    #[clap(
+        long,
+        value_parser,
+        requires = "scan",
+        help = "The subnet to scan, e.g. 192.168.1.0/24. Defaults to the /24 of this machine"
+    )]
+    scan_subnet: Option<Subnet>,
+}
+
+impl DiscoveryArgs {
+    pub fn get_options(&self) -> Result<DiscoveryOptions> {
+        let scan_subnet = match (self.scan, self.scan_subnet) {
+            (false, _) => None,
+            (true, Some(subnet)) => Some(subnet),
+            (true, None) => Some(Subnet::local()?),
+        };
+        Ok(DiscoveryOptions {
+            mdns_hostname: self.mdns_hostname.clone(),
+            scan_subnet,
+        })
+    }
+}
+
 #[derive(Debug, Parser)]
-#[clap(version, about, long_about = None)]
+#[clap(
+    version,
+    about,
+    long_about = None,
+    subcommand_negates_reqs = true,
+    args_conflicts_with_subcommands = true
+)]
 pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    // The positionals are `Option`s so that a subcommand can stand in for
+    // them, and so that Graph can be left out entirely when `--ics` is given.
+    #[clap(
+        value_parser,
+        required = true,
+        help = "The IP address of the Pico your connecting to (e.g. 169.420.1.469), or 'auto' to discover it"
+    )]
+    pico_ip: Option<String>,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value = "full",
+        help = "The layout painted on the Pico at PICO_IP"
+    )]
+    layout: ScreenLayout,
+
     #[clap(
+        long = "pico",
         value_parser,
-        help = "The IP address of the Pico your connecting to (e.g. 169.420.1.469)"
+        value_name = "NAME=ADDR[,LAYOUT]",
+        help = "An additional Pico to update, e.g. door=192.168.1.51,summary. May be repeated"
     )]
-    pico_ip: String,
+    extra_picos: Vec<PicoTarget>,
 
     #[clap(
         value_parser,
-        help = "The OAuth Client ID of the registered application from Azure Portal"
+        required_unless_present_any = &["ics", "caldav", "slack-token"],
+        help = "The OAuth Client ID of the registered application from Azure Portal. Optional with --ics, --caldav or --slack-token, which then replace Graph"
     )]
-    client_id: String,
+    client_id: Option<String>,
 
     #[clap(
         value_parser,
@@ -46,12 +258,37 @@ pub struct Cli {
     )]
     tenant_id: String,
 
+    #[clap(
+        long,
+        value_parser,
+        env = "CLIENT_SECRET",
+        hide_env_values = true,
+        conflicts_with = "client-certificate",
+        help = "The client secret of a confidential app registration"
+    )]
+    client_secret: Option<ClientSecret>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "A PEM file with the certificate and private key of a confidential app registration, used to sign a JWT client assertion"
+    )]
+    client_certificate: Option<PathBuf>,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value = "browser",
+        help = "How to sign in when no cached token is available"
+    )]
+    auth_mode: AuthMode,
+
     #[clap(
         short,
         long,
         value_parser,
-        default_value = "3",
-        help = "The time, in seconds, that the pico-status tool will wait before killing the local server that supports OAuth"
+        default_value = "120",
+        help = "The time, in seconds, that the pico-status tool will wait for you to sign in before giving up"
     )]
     auth_wait_for: u64,
 
@@ -64,6 +301,61 @@ pub struct Cli {
     )]
     poll_after: u64,
 
+    #[clap(
+        long,
+        value_parser,
+        default_value = "300",
+        help = "The time, in seconds, after which an unchanged screen is pushed to the Pico again, e.g. in case it rebooted"
+    )]
+    refresh_after: u64,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value = status::DEFAULT_GRAPH_URL,
+        help = "The base URL of the Microsoft Graph API"
+    )]
+    graph_url: String,
+
+    #[clap(
+        long,
+        value_parser,
+        value_name = "URL",
+        help = "The public URL, e.g. of a tunnel to --notification-bind, where Graph sends change notifications. Enables recomputing the status as soon as presence or calendar events change"
+    )]
+    notification_url: Option<Url>,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value = "127.0.0.1:8470",
+        help = "The local address the change notification endpoint listens on"
+    )]
+    notification_bind: SocketAddr,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value = "900",
+        help = "The time, in seconds, between fallback polls while change notifications are up. Until then, --poll-after applies"
+    )]
+    fallback_poll_after: u64,
+
+    #[clap(
+        long,
+        action,
+        help = "Download the whole Graph calendarView on every poll instead of only the changes since the last one"
+    )]
+    no_calendar_delta: bool,
+
+    #[clap(
+        long,
+        value_parser,
+        conflicts_with = "no-calendar-delta",
+        help = "A file to keep the synced Graph calendar in, so a restart only downloads the changes. Kept in memory by default"
+    )]
+    calendar_store: Option<PathBuf>,
+
     #[clap(
         short,
         long,
@@ -82,6 +374,155 @@ pub struct Cli {
     )]
     scope: String,
 
+    #[clap(
+        long,
+        value_parser,
+        help = "The directory where the encrypted OAuth token cache is stored. Defaults to the platform cache directory"
+    )]
+    token_cache_dir: Option<PathBuf>,
+
+    #[clap(
+        long,
+        action,
+        help = "Do not read or write the OAuth token cache. Always sign in interactively"
+    )]
+    no_token_cache: bool,
+
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..),
+        default_value = "7",
+        help = "How many days ahead the calendars are searched for the next event"
+    )]
+    lookahead: u32,
+
+    #[clap(
+        long,
+        value_parser,
+        value_name = "PATH_OR_URL",
+        help = "An iCalendar file or http(s)/webcal URL to read events from. May be repeated"
+    )]
+    ics: Vec<IcsLocation>,
+
+    #[clap(
+        long,
+        value_parser,
+        value_name = "URL",
+        help = "A CalDAV server, principal or calendar URL (e.g. Nextcloud or Radicale) to read events from. May be repeated"
+    )]
+    caldav: Vec<Url>,
+
+    #[clap(
+        long,
+        value_parser,
+        requires_all = &["caldav", "caldav-password"],
+        help = "The username for the CalDAV servers"
+    )]
+    caldav_username: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        env = "CALDAV_PASSWORD",
+        hide_env_values = true,
+        requires = "caldav-username",
+        help = "The password for the CalDAV servers. Basic and digest auth are supported"
+    )]
+    caldav_password: Option<Password>,
+
+    #[clap(
+        long,
+        value_parser,
+        env = "SLACK_TOKEN",
+        hide_env_values = true,
+        help = "A Slack user token (xoxp-...) to read your Slack presence, Do Not Disturb and custom status"
+    )]
+    slack_token: Option<SlackToken>,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value = DEFAULT_SLACK_API_URL,
+        help = "The base URL of the Slack Web API"
+    )]
+    slack_api_url: String,
+
+    #[clap(
+        long,
+        action,
+        help = "Report being in a call while this machine's microphone or camera is in use (Linux only)"
+    )]
+    detect_calls: bool,
+
+    #[clap(
+        long,
+        action,
+        help = "Report being away while the screen is locked, from systemd-logind or input device activity (Linux only)"
+    )]
+    detect_idle: bool,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value = "auto",
+        help = "The logind session to watch for --detect-idle"
+    )]
+    logind_session: String,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value = "300",
+        help = "Without logind, the seconds without input after which --detect-idle reports you idle"
+    )]
+    idle_after: u64,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value = "/proc",
+        help = "Where procfs is mounted, for --detect-calls"
+    )]
+    proc_root: PathBuf,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value = "/dev",
+        help = "Where the device nodes live, for --detect-calls and --detect-idle"
+    )]
+    dev_root: PathBuf,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value = "busy-wins",
+        help = "How the availability of several status sources is merged"
+    )]
+    merge_rule: MergeRule,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "A TOML file with rules for the colors and texts, consulted before the built in ones"
+    )]
+    rules: Option<PathBuf>,
+
+    #[clap(
+        long = "line",
+        value_parser,
+        value_name = "LINE=TEMPLATE",
+        help = "Replaces the template of a line of the full layout, e.g. '1={now:%H:%M}' or '4={if message}{message}{end}'. May be repeated"
+    )]
+    lines: Vec<LineTemplate>,
+
+    #[clap(flatten)]
+    discovery: DiscoveryArgs,
+
+    #[cfg(unix)]
+    #[clap(flatten)]
+    control: ControlArgs,
+
     #[clap(short, long, action, help = "Include exxxtra verbose tracing")]
     verbose: bool,
 }
@@ -91,69 +532,605 @@ impl Cli {
         self.verbose
     }
 
-    pub fn get_pico_ip(&self) -> String {
-        String::from(&self.pico_ip)
+    /// The Pico at `PICO_IP` followed by every `--pico`
+    pub fn get_pico_targets(&self) -> Vec<PicoTarget> {
+        let pico_ip = self.pico_ip.as_deref().unwrap_or_default();
+        let mut targets = vec![PicoTarget::new("default", pico_ip, self.layout)];
+        targets.extend(self.extra_picos.iter().cloned());
+        targets
+    }
+
+    /// Checks what clap can't on its own. Every Pico needs its own name, as
+    /// the addresses and the screens they were sent are kept by name.
+    pub fn validate(&self) -> std::result::Result<(), clap::Error> {
+        let mut names = HashSet::new();
+        for target in self.extra_picos.iter() {
+            if target.name == "default" || !names.insert(&target.name) {
+                return Err(Cli::command().error(
+                    ErrorKind::ArgumentConflict,
+                    format!(
+                        "There is more than one Pico named '{}'. The one at PICO_IP is named 'default'.",
+                        target.name
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_lookahead(&self) -> chrono::Duration {
+        chrono::Duration::days(self.lookahead.into())
+    }
+
+    pub fn get_client_id(&self) -> Option<&str> {
+        self.client_id.as_deref()
+    }
+
+    pub fn get_caldav_credentials(&self) -> Option<(String, Password)> {
+        Some((self.caldav_username.clone()?, self.caldav_password.clone()?))
+    }
+
+    pub fn get_client_credential(&self) -> Result<Option<ClientCredential>> {
+        if let Some(secret) = &self.client_secret {
+            return Ok(Some(ClientCredential::Secret(secret.clone())));
+        }
+        match &self.client_certificate {
+            Some(path) => Ok(Some(ClientCredential::Certificate(
+                ClientCertificate::from_pem_file(path)?,
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_token_cache(&self) -> Option<TokenCache> {
+        if self.no_token_cache {
+            return None;
+        }
+        let dir = self
+            .token_cache_dir
+            .clone()
+            .or_else(TokenCache::default_dir)?;
+        Some(TokenCache::new(
+            &dir,
+            self.get_client_id().unwrap_or_default(),
+            &self.tenant_id,
+        ))
     }
 }
 
+/// Runs the `discover` subcommand
 #[tracing::instrument]
-pub async fn run(cli: Cli, client: &DurableClient) -> Result<(), Box<dyn std::error::Error>> {
-    tracing::info!("Received CLI Args: {:?}", cli);
+pub async fn discover(args: &DiscoveryArgs) -> Result<()> {
+    let found = discovery::discover(&args.get_options()?, &[]).await?;
+    if found.is_empty() {
+        println!("No Picos found.");
+    }
+    for addr in found {
+        println!("{}", addr);
+    }
+    Ok(())
+}
+
+/// Runs the `override` subcommand against the running pico-client
+#[cfg(unix)]
+#[tracing::instrument]
+pub async fn send_override(args: &OverrideArgs) -> Result<()> {
+    let until = match args.duration {
+        Some(duration) => Until::At(chrono::Utc::now() + duration),
+        None => Until::MeetingEnds,
+    };
+    let request = ControlRequest::Override {
+        color: args.color,
+        text: args.text.clone(),
+        until,
+    };
+    println!(
+        "{}",
+        control::send(&args.control.get_socket_path(), &request).await?
+    );
+    Ok(())
+}
 
-    let config = OAuthConfiguration::new(&cli.client_id, &cli.tenant_id, &cli.scope);
-    let token = oauth::flow(config.clone(), client, cli.auth_wait_for).await?;
-    SharedAccessToken::autorefresh(
-        token.clone(),
-        client.clone(),
-        config.clone(),
-        cli.refresh_expiry_padding,
+/// Runs the `clear` subcommand against the running pico-client
+#[cfg(unix)]
+#[tracing::instrument]
+pub async fn send_clear(args: &ControlArgs) -> Result<()> {
+    println!(
+        "{}",
+        control::send(&args.get_socket_path(), &ControlRequest::Clear).await?
     );
+    Ok(())
+}
+
+#[tracing::instrument]
+pub async fn run(cli: Cli, client: &DurableClient, targets: SharedPicoTargets) -> Result<()> {
+    tracing::info!("Received CLI Args: {:?}", cli);
+
+    let discovery_options = cli.discovery.get_options()?;
+    for target in targets.snapshot().iter().filter(|target| target.discovered) {
+        let claimed = targets.claimed_addrs(&target.name);
+        match discovery::discover_one(&discovery_options, &claimed).await {
+            Ok(addr) => {
+                tracing::info!("Discovered Pico {} at {}", target.name, addr);
+                targets.set_addr(&target.name, &addr.to_string());
+            }
+            // Pushes to it fail until then, which gets it rediscovered
+            Err(err) => tracing::warn!(
+                "Could not discover Pico {}: {}. Trying again later.",
+                target.name,
+                err
+            ),
+        }
+    }
+
+    let graph = match cli.get_client_id() {
+        Some(client_id) => Some(GraphSession::start(&cli, client_id, client, &targets).await?),
+        None => None,
+    };
+
+    let overrides = OverrideStore::load(OverrideStore::default_path());
+    if let Some(ovr) = overrides.current() {
+        tracing::info!("Restored the manual override until {}", ovr.until);
+    }
+    #[cfg(unix)]
+    tokio::spawn({
+        let socket_path = cli.control.get_socket_path();
+        let overrides = overrides.clone();
+        async move {
+            if let Err(err) = control::serve(&socket_path, overrides).await {
+                tracing::warn!("Overrides are unavailable: {}", err);
+            }
+        }
+    });
+
+    // Presence sources come before calendars, so they win under `MergeRule::Priority`
+    let mut aggregator = Aggregator::new(cli.merge_rule).with_overrides(overrides.clone());
+    if let Some(path) = &cli.rules {
+        aggregator = aggregator.with_rules(Rules::from_file(path)?);
+    }
+    let graph_client = http::build_graph_client();
+    if let Some(graph) = &graph {
+        let mut source = GraphSource::new(
+            graph_client.clone(),
+            &cli.graph_url,
+            graph.token.clone(),
+            cli.get_lookahead(),
+        );
+        if !cli.no_calendar_delta {
+            source = source.with_event_store(EventStore::load(
+                cli.calendar_store.clone(),
+                cli.get_lookahead(),
+            ));
+        }
+        aggregator = aggregator.with_source(source);
+    }
+    if let Some(token) = &cli.slack_token {
+        aggregator = aggregator.with_source(SlackSource::new(
+            client.clone(),
+            &cli.slack_api_url,
+            token.clone(),
+        ));
+    }
+    if cli.detect_idle {
+        aggregator = aggregator.with_source(IdleSource::new(vec![
+            Box::new(LogindHints::new(&cli.logind_session)),
+            Box::new(InputIdleHints::new(
+                &cli.dev_root,
+                Duration::from_secs(cli.idle_after),
+            )),
+        ]));
+    }
+    if cli.detect_calls {
+        aggregator = aggregator.with_source(DeviceSource::new(&cli.proc_root, &cli.dev_root));
+    }
+    for location in &cli.ics {
+        aggregator = aggregator.with_source(IcsSource::new(
+            client.clone(),
+            location.clone(),
+            cli.get_lookahead(),
+        ));
+    }
+    for url in &cli.caldav {
+        aggregator = aggregator.with_source(CalDavSource::new(
+            client.clone(),
+            url.clone(),
+            cli.get_caldav_credentials(),
+            cli.get_lookahead(),
+        ));
+    }
+
+    let templates = cli
+        .lines
+        .iter()
+        .fold(Templates::default(), |templates, line| {
+            templates.with_line(line.number, line.template.clone())
+        });
+    templates.validate().map_err(Error::Config)?;
+
+    let notifications = match (&graph, &cli.notification_url) {
+        (Some(graph), Some(notification_url)) => {
+            let options = NotificationOptions {
+                graph_url: cli.graph_url.clone(),
+                notification_url: notification_url.clone(),
+                bind: cli.notification_bind,
+            };
+            Some(notifications::start(&graph_client, &graph.token, &options))
+        }
+        (None, Some(_)) => {
+            return Err(Error::Config(
+                "--notification-url needs a Graph sign in (--client-id)".into(),
+            ))
+        }
+        _ => None,
+    };
 
     let err_tolerance = 5;
     let mut err_count = 0;
+    let mut push_failures: HashMap<String, usize> = HashMap::new();
+    let mut delivered = DeliveredScreens::new(Duration::from_secs(cli.refresh_after));
+    // The sources are only asked at `next_poll`. In between, the screen is
+    // rendered again whenever time alone changes it.
+    let mut next_poll = Instant::now();
+    let mut throttled_until = Instant::now();
+    let mut sign_in_backoff = SignInBackoff::new(Instant::now());
     loop {
-        let status = match status::get_status(client, &token).await {
-            Ok(status) => status,
-            Err(err) => {
-                tracing::warn!("An error occurred while fetching the status: {:#?}", err);
-                err_count += 1;
-                tracing::warn!(
-                    "This is the {} err occurrence. Tolerates {}.",
-                    err_count,
-                    err_tolerance
-                );
-                status::debug_status(client, &token).await.unwrap_or(());
-                if err_count > err_tolerance {
-                    tracing::error!("Err number {} has occurred! This means the tolerance of {} has been surpased. Exiting!", err_count, err_tolerance);
-                    return Err(err);
-                } else {
-                    continue;
+        let status = if Instant::now() >= next_poll {
+            // Notifications wake the loop early, so polling only needs to
+            // catch what they miss. Until they're up, it's the only way.
+            let poll_after = match &notifications {
+                Some(notifications) if notifications.is_subscribed() => cli.fallback_poll_after,
+                _ => cli.poll_after,
+            };
+            next_poll = Instant::now() + Duration::from_secs(poll_after);
+            // Whatever goes wrong below, a manual override is still shown
+            let health = graph.as_ref().map(|graph| graph.token.health());
+            match (&graph, health) {
+                (_, Some(TokenHealth::Expired)) => {
+                    tracing::warn!(
+                        "The token expired and is still being refreshed. Skipping this poll."
+                    );
+                    aggregator.render_override()
+                }
+                (Some(_), Some(TokenHealth::SignInRequired))
+                    if !sign_in_backoff.is_due(Instant::now()) =>
+                {
+                    tracing::info!("The last sign in failed. Waiting before trying again.");
+                    aggregator.render_override()
                 }
+                (Some(graph), Some(TokenHealth::SignInRequired)) => {
+                    tracing::warn!("The refresh token was rejected. Signing in again.");
+                    // The sign in screens replace whatever was delivered
+                    delivered.clear();
+                    if aggregator.render_override().is_none() {
+                        if let Err(err) =
+                            status::set_sign_in_required(client, &targets.snapshot()).await
+                        {
+                            tracing::warn!(
+                                "Could not show the sign in screen on the Pico: {:?}",
+                                err
+                            );
+                        }
+                    }
+                    let cache = graph.cache.as_ref();
+                    match sign_in(&cli, &graph.config, client, cache, &targets).await {
+                        Ok(fresh) => {
+                            graph.token.replace(&fresh);
+                            sign_in_backoff.succeeded(Instant::now());
+                            next_poll = Instant::now();
+                        }
+                        Err(err) => {
+                            tracing::warn!("Sign in failed: {:?}. Trying again later.", err);
+                            sign_in_backoff.failed(Instant::now());
+                        }
+                    }
+                    aggregator.render_override()
+                }
+                _ => match aggregator.get_status().await {
+                    Ok(status) => Some(status),
+                    Err(err) if err.is_unauthorized() => {
+                        tracing::warn!("Graph rejected the access token. Forcing a refresh.");
+                        if let Some(graph) = &graph {
+                            graph.token.force_refresh();
+                        }
+                        aggregator.render_override()
+                    }
+                    Err(err) if err.throttled_for().is_some() => {
+                        let retry_after = err.throttled_for().flatten().unwrap_or(THROTTLE_BACKOFF);
+                        tracing::warn!(
+                            "Graph is throttling requests. Backing off for {} seconds.",
+                            retry_after.as_secs()
+                        );
+                        throttled_until = Instant::now() + retry_after;
+                        next_poll = next_poll.max(throttled_until);
+                        aggregator.render_override()
+                    }
+                    Err(err) => {
+                        tracing::warn!("An error occurred while fetching the status: {:#?}", err);
+                        err_count += 1;
+                        tracing::warn!(
+                            "This is the {} err occurrence. Tolerates {}.",
+                            err_count,
+                            err_tolerance
+                        );
+                        if let Some(graph) = &graph {
+                            status::debug_status(
+                                client,
+                                &graph.token,
+                                &cli.graph_url,
+                                cli.get_lookahead(),
+                            )
+                            .await
+                            .unwrap_or(());
+                        }
+                        if err_count > err_tolerance {
+                            tracing::error!("Err number {} has occurred! This means the tolerance of {} has been surpased. Exiting!", err_count, err_tolerance);
+                            return Err(err);
+                        }
+                        aggregator.render_override()
+                    }
+                },
             }
+        } else {
+            Some(aggregator.render())
         };
 
-        match status::set_status(client, &status, &cli.pico_ip).await {
-            Ok(res) => res,
-            Err(err) => {
-                tracing::warn!("An error occurred while fetching the status: {:#?}", err);
-                err_count += 1;
-                tracing::warn!(
-                    "This is the {} err occurrence. Tolerates {}.",
-                    err_count,
-                    err_tolerance
-                );
-                if err_count > err_tolerance {
-                    tracing::error!("Err number {} has occurred! This means the tolerance of {} has been surpased. Exiting!", err_count, err_tolerance);
-                    return Err(err);
-                } else {
-                    continue;
-                }
+        if let Some(status) = &status {
+            show_status(
+                client,
+                status,
+                &templates,
+                &targets,
+                &mut delivered,
+                &mut push_failures,
+                &discovery_options,
+            )
+            .await;
+        }
+
+        let mut wake_at = next_poll;
+        let now = Utc::now();
+        let change = status.as_ref().and_then(|status| status.next_change(now));
+        let override_ends = overrides.current().map(|ovr| ovr.until);
+        for at in change.into_iter().chain(override_ends) {
+            wake_at = wake_at.min(Instant::now() + (at - now).to_std().unwrap_or_default());
+        }
+        if templates.shows_clock() {
+            let to_next_minute = 60 - u64::from(Local::now().second());
+            wake_at = wake_at.min(Instant::now() + Duration::from_secs(to_next_minute));
+        }
+        let notified = async {
+            match &notifications {
+                Some(notifications) => notifications.wake.notified().await,
+                None => std::future::pending().await,
             }
         };
-
-        tokio::time::sleep(Duration::from_secs(cli.poll_after)).await;
+        tokio::select! {
+            _ = tokio::time::sleep_until(wake_at) => {}
+            _ = overrides.changed() => {
+                tracing::info!("Showing the changed manual override");
+            }
+            _ = notified => {
+                tracing::info!("Recomputing the status after a change notification");
+                // A notification doesn't lift the throttling
+                next_poll = throttled_until.max(Instant::now());
+            }
+        }
     }
 
     #[allow(unreachable_code)]
     Ok(())
 }
+
+/// Pushes `status` to every Pico whose screen changed, and rediscovers the
+/// ones that keep failing
+async fn show_status(
+    client: &DurableClient,
+    status: &Status,
+    templates: &Templates,
+    targets: &SharedPicoTargets,
+    delivered: &mut DeliveredScreens,
+    push_failures: &mut HashMap<String, usize>,
+    discovery_options: &DiscoveryOptions,
+) {
+    let snapshot = targets.snapshot();
+    let results = status::set_status(client, status, templates, &snapshot, delivered).await;
+    // Graph is fine, so keep polling it until the Pico is back
+    for (target, res) in snapshot.iter().zip(results) {
+        // An unchanged screen wasn't pushed, so there is nothing to count
+        let res = match res {
+            Some(res) => res,
+            None => continue,
+        };
+        let failures = push_failures.entry(target.name.clone()).or_default();
+        *failures = if res.is_ok() { 0 } else { *failures + 1 };
+        if target.discovered && *failures >= REDISCOVER_AFTER_FAILURES {
+            rediscover(target, targets, discovery_options).await;
+            *failures = 0;
+        }
+    }
+}
+
+/// The Graph sign in, kept fresh by `SharedAccessToken::autorefresh`
+#[derive(Debug)]
+struct GraphSession {
+    config: OAuthConfiguration,
+    cache: Option<TokenCache>,
+    token: SharedAccessToken,
+}
+
+impl GraphSession {
+    /// Restores the cached token or signs in, then starts refreshing it
+    async fn start(
+        cli: &Cli,
+        client_id: &str,
+        client: &DurableClient,
+        targets: &SharedPicoTargets,
+    ) -> Result<Self> {
+        let config = OAuthConfiguration::new(client_id, &cli.tenant_id, &cli.scope)
+            .with_client_credential(cli.get_client_credential()?);
+        let cache = cli.get_token_cache();
+        let restored = match &cache {
+            Some(cache) => oauth::restore(&config, client, cache).await,
+            None => None,
+        };
+        let token = match restored {
+            Some(token) => token,
+            None => sign_in(cli, &config, client, cache.as_ref(), targets).await?,
+        };
+        SharedAccessToken::autorefresh(
+            token.clone(),
+            client.clone(),
+            config.clone(),
+            cli.refresh_expiry_padding,
+            cache.clone(),
+        );
+        Ok(GraphSession {
+            config,
+            cache,
+            token,
+        })
+    }
+}
+
+/// Looks up a discovered Pico again after its pushes kept failing, e.g.
+/// because its DHCP lease changed
+#[tracing::instrument]
+async fn rediscover(target: &PicoTarget, targets: &SharedPicoTargets, options: &DiscoveryOptions) {
+    tracing::warn!("Pico {} keeps failing. Rediscovering it.", target.name);
+    let claimed = targets.claimed_addrs(&target.name);
+    match discovery::discover_one(options, &claimed).await {
+        Ok(addr) => {
+            tracing::info!("Rediscovered Pico {} at {}", target.name, addr);
+            targets.set_addr(&target.name, &addr.to_string());
+        }
+        Err(err) => tracing::warn!("Could not rediscover Pico {}: {}", target.name, err),
+    }
+}
+
+/// Runs the interactive sign in for the configured `AuthMode` and caches the
+/// resulting token
+#[tracing::instrument]
+async fn sign_in(
+    cli: &Cli,
+    config: &OAuthConfiguration,
+    client: &DurableClient,
+    cache: Option<&TokenCache>,
+    targets: &SharedPicoTargets,
+) -> Result<SharedAccessToken> {
+    let token = match cli.auth_mode {
+        AuthMode::Browser => oauth::flow(config.clone(), client, cli.auth_wait_for).await?,
+        AuthMode::DeviceCode => device_code_flow(config, client, targets).await?,
+    };
+    if let Some(cache) = cache {
+        if let Err(err) = cache.store(&token.snapshot()) {
+            tracing::warn!("Could not write the token cache: {:?}", err);
+        }
+    }
+    Ok(token)
+}
+
+#[tracing::instrument]
+async fn device_code_flow(
+    config: &OAuthConfiguration,
+    client: &DurableClient,
+    targets: &SharedPicoTargets,
+) -> Result<SharedAccessToken> {
+    let device_code = oauth::request_device_code(config, client).await?;
+    println!("{}", device_code.message);
+    if let Err(err) = status::set_device_code(client, &targets.snapshot(), &device_code).await {
+        tracing::warn!("Could not show the device code on the Pico: {:?}", err);
+    }
+    oauth::poll_device_code(config, client, &device_code).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_discover_needs_no_positionals() {
+        let cli = Cli::try_parse_from(["pico-client", "discover", "--scan"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Discover(args)) if args.scan));
+        assert!(Cli::try_parse_from(["pico-client", "auto"]).is_err());
+
+        let cli = Cli::try_parse_from(["pico-client", "auto", "01e89a7d"]).unwrap();
+        assert!(cli.get_pico_targets()[0].discovered);
+    }
+
+    #[test]
+    fn test_sign_in_backoff() {
+        let start = Instant::now();
+        let mut backoff = SignInBackoff::new(start);
+        assert!(backoff.is_due(start));
+
+        let mut now = start;
+        let mut waits = vec![];
+        for _ in 0..8 {
+            backoff.failed(now);
+            assert!(!backoff.is_due(now));
+            waits.push((backoff.next_attempt - now).as_secs());
+            now = backoff.next_attempt;
+            assert!(backoff.is_due(now));
+        }
+        assert_eq!(waits, vec![60, 120, 240, 480, 960, 1920, 3600, 3600]);
+
+        backoff.succeeded(now);
+        assert!(backoff.is_due(now));
+        backoff.failed(now);
+        assert_eq!(backoff.next_attempt - now, SIGN_IN_BACKOFF_MIN);
+    }
+
+    #[test]
+    fn test_caldav_credentials_go_together() {
+        let parse = |credentials: &[&str]| {
+            let mut args = vec!["pico-client", "10.0.0.2", "--caldav", "https://dav.example"];
+            args.extend(credentials);
+            Cli::try_parse_from(args)
+        };
+        let cli = parse(&["--caldav-username", "me", "--caldav-password", "secret"]).unwrap();
+        assert!(cli.get_caldav_credentials().is_some());
+        assert!(parse(&["--caldav-username", "me"]).is_err());
+        assert!(parse(&["--caldav-password", "secret"]).is_err());
+    }
+
+    #[test]
+    fn test_pico_names_are_unique() {
+        let parse = |picos: &[&str]| {
+            let mut args = vec!["pico-client", "10.0.0.2", "01e89a7d"];
+            for pico in picos {
+                args.extend(["--pico", pico]);
+            }
+            Cli::try_parse_from(args).unwrap().validate()
+        };
+        assert!(parse(&["door=10.0.0.3", "desk=10.0.0.4,summary"]).is_ok());
+        assert!(parse(&["door=10.0.0.3", "door=10.0.0.4"]).is_err());
+        let err = parse(&["default=10.0.0.3"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ArgumentConflict);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_override_needs_an_expiry() {
+        let cli = Cli::try_parse_from([
+            "pico-client",
+            "override",
+            "red",
+            "--text",
+            "Focus",
+            "--for",
+            "45m",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Override(args))
+                if args.color == OverrideColor::Red
+                    && args.duration == Some(chrono::Duration::minutes(45))
+        ));
+        assert!(
+            Cli::try_parse_from(["pico-client", "override", "green", "--until-meeting-ends"])
+                .is_ok()
+        );
+        assert!(Cli::try_parse_from(["pico-client", "override", "red"]).is_err());
+    }
+}
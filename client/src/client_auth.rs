@@ -0,0 +1,139 @@
+use std::{fmt, fs, path::Path, str::FromStr};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use sha1::{Digest, Sha1};
+
+use crate::error::{Error, Result};
+
+const CLIENT_ASSERTION_TYPE: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+const ASSERTION_LIFETIME_SECS: i64 = 600;
+
+/// A client secret from the app registration. The value never appears in
+/// `Debug` output, so it is safe to log structs that hold one.
+#[derive(Clone)]
+pub struct ClientSecret(String);
+
+impl fmt::Debug for ClientSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+impl FromStr for ClientSecret {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(ClientSecret(s.into()))
+    }
+}
+
+/// A certificate registered on the app, used to sign a fresh JWT client
+/// assertion for every token request.
+#[derive(Clone)]
+pub struct ClientCertificate {
+    thumbprint: String,
+    key: EncodingKey,
+}
+
+impl fmt::Debug for ClientCertificate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientCertificate")
+            .field("thumbprint", &self.thumbprint)
+            .field("key", &"[REDACTED]")
+            .finish()
+    }
+}
+
+impl ClientCertificate {
+    /// Reads a PEM file holding both the certificate and its RSA private key
+    pub fn from_pem_file(path: &Path) -> Result<Self> {
+        let contents = fs::read(path)?;
+        let certificate = pem::parse_many(&contents)
+            .map_err(|err| Error::Config(format!("{:?} is not a PEM file: {}", path, err)))?
+            .into_iter()
+            .find(|block| block.tag == "CERTIFICATE")
+            .ok_or_else(|| Error::Config(format!("No CERTIFICATE block found in {:?}", path)))?;
+        let key = EncodingKey::from_rsa_pem(&contents)
+            .map_err(|err| Error::Config(format!("No RSA private key in {:?}: {}", path, err)))?;
+        Ok(ClientCertificate {
+            thumbprint: URL_SAFE_NO_PAD.encode(Sha1::digest(&certificate.contents)),
+            key,
+        })
+    }
+
+    /// Signs a short lived client assertion for the given token endpoint
+    fn to_assertion(&self, client_id: &str, token_url: &str) -> Result<String> {
+        let now = Utc::now().timestamp();
+        let mut header = Header::new(Algorithm::RS256);
+        header.x5t = Some(self.thumbprint.clone());
+        let claims = AssertionClaims {
+            aud: token_url.into(),
+            iss: client_id.into(),
+            sub: client_id.into(),
+            jti: URL_SAFE_NO_PAD.encode(rand::random::<[u8; 16]>()),
+            nbf: now,
+            exp: now + ASSERTION_LIFETIME_SECS,
+        };
+        jsonwebtoken::encode(&header, &claims, &self.key)
+            .map_err(|err| Error::Auth(format!("Could not sign the client assertion: {}", err)))
+    }
+}
+
+/// How a confidential client proves its identity to the token endpoint
+#[derive(Clone, Debug)]
+pub enum ClientCredential {
+    Secret(ClientSecret),
+    Certificate(ClientCertificate),
+}
+
+impl ClientCredential {
+    pub fn to_params(&self, client_id: &str, token_url: &str) -> Result<ClientAuthParams> {
+        let params = match self {
+            ClientCredential::Secret(secret) => ClientAuthParams {
+                client_secret: Some(secret.0.clone()),
+                ..Default::default()
+            },
+            ClientCredential::Certificate(certificate) => ClientAuthParams {
+                client_assertion_type: Some(CLIENT_ASSERTION_TYPE.into()),
+                client_assertion: Some(certificate.to_assertion(client_id, token_url)?),
+                ..Default::default()
+            },
+        };
+        Ok(params)
+    }
+}
+
+/// The form fields that authenticate a confidential client. All of them are
+/// omitted for public clients.
+#[derive(Default, Deserialize, Serialize)]
+pub struct ClientAuthParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_secret: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_assertion_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_assertion: Option<String>,
+}
+
+impl fmt::Debug for ClientAuthParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let redact = |value: &Option<String>| value.as_ref().map(|_| "[REDACTED]");
+        f.debug_struct("ClientAuthParams")
+            .field("client_secret", &redact(&self.client_secret))
+            .field("client_assertion_type", &self.client_assertion_type)
+            .field("client_assertion", &redact(&self.client_assertion))
+            .finish()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct AssertionClaims {
+    aud: String,
+    iss: String,
+    sub: String,
+    jti: String,
+    nbf: i64,
+    exp: i64,
+}
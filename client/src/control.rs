@@ -0,0 +1,479 @@
+use std::{
+    fs, io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+#[cfg(unix)]
+use std::path::Path;
+use tokio::sync::Notify;
+#[cfg(unix)]
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+use crate::error::{Error, Result};
+use crate::source::SourceSnapshot;
+use crate::status::{Activity, Availability};
+
+const SOCKET_FILE: &str = "pico-client.sock";
+const OVERRIDE_FILE: &str = "override.json";
+
+/// The color a manual override paints the Pico with
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverrideColor {
+    Green,
+    Yellow,
+    Red,
+}
+
+impl OverrideColor {
+    fn availability(self) -> (Availability, Activity) {
+        match self {
+            OverrideColor::Green => (Availability::Available, Activity::Available),
+            OverrideColor::Yellow => (Availability::BeRightBack, Activity::BeRightBack),
+            OverrideColor::Red => (Availability::DoNotDisturb, Activity::DoNotDisturb),
+        }
+    }
+}
+
+/// When an override requested over the socket ends
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Until {
+    At(DateTime<Utc>),
+    /// The end of the event that is going on when the daemon receives it
+    MeetingEnds,
+}
+
+/// What `pico-client override` and `pico-client clear` send to the daemon,
+/// one JSON object per line
+#[cfg(unix)]
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    Override {
+        color: OverrideColor,
+        text: Option<String>,
+        until: Until,
+    },
+    Clear,
+}
+
+/// A manual status that beats every source until it expires
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Override {
+    pub color: OverrideColor,
+    pub text: Option<String>,
+    pub until: DateTime<Utc>,
+}
+
+#[derive(Debug, Default)]
+struct OverrideState {
+    current: Option<Override>,
+    /// The end of the event going on at the last poll, for `Until::MeetingEnds`
+    meeting_end: Option<DateTime<Utc>>,
+}
+
+/// The active override, shared between the control socket and the
+/// `Aggregator`, and persisted so it survives a restart
+#[derive(Clone, Debug)]
+pub struct OverrideStore {
+    path: Option<PathBuf>,
+    data: Arc<Mutex<OverrideState>>,
+    /// Fires when an override is set or cleared, so the screen follows at once
+    changed: Arc<Notify>,
+}
+
+impl OverrideStore {
+    /// Restores the override persisted at `path`, if there is one. Without a
+    /// path, overrides only live as long as the process.
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let current = path.as_deref().and_then(|path| match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|err| tracing::warn!("Ignoring the persisted override: {}", err))
+                .ok(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => {
+                tracing::warn!("Could not read the persisted override: {}", err);
+                None
+            }
+        });
+        OverrideStore {
+            path,
+            data: Arc::new(Mutex::new(OverrideState {
+                current,
+                meeting_end: None,
+            })),
+            changed: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Next to the token cache, e.g. `~/.cache/pico-client/override.json`
+    pub fn default_path() -> Option<PathBuf> {
+        crate::token_cache::TokenCache::default_dir().map(|dir| dir.join(OVERRIDE_FILE))
+    }
+
+    pub fn current(&self) -> Option<Override> {
+        let data = self.data.lock().unwrap();
+        data.current.clone()
+    }
+
+    /// Resolves `until` and replaces the current override
+    pub fn set(
+        &self,
+        color: OverrideColor,
+        text: Option<String>,
+        until: Until,
+    ) -> Result<Override> {
+        let mut data = self.data.lock().unwrap();
+        let until = match until {
+            Until::At(at) => at,
+            Until::MeetingEnds => data
+                .meeting_end
+                .ok_or_else(|| Error::Control("No meeting is in progress".into()))?,
+        };
+        if until <= Utc::now() {
+            return Err(Error::Control("The override would already be over".into()));
+        }
+        let ovr = Override { color, text, until };
+        // Persisted first, so a failure leaves the screen as it was
+        self.persist(Some(&ovr))?;
+        data.current = Some(ovr.clone());
+        self.changed.notify_one();
+        Ok(ovr)
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        let mut data = self.data.lock().unwrap();
+        self.persist(None)?;
+        data.current = None;
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    /// Waits until an override is set or cleared
+    pub async fn changed(&self) {
+        self.changed.notified().await
+    }
+
+    /// Paints the override over the merged `snapshot`, dropping it once it
+    /// has expired. Also remembers the ongoing meeting for `Until::MeetingEnds`.
+    pub fn apply(&self, snapshot: &mut SourceSnapshot, now: DateTime<Utc>) {
+        let mut data = self.data.lock().unwrap();
+        data.meeting_end = snapshot
+            .events
+            .iter()
+            .find(|evt| evt.start <= now && evt.end > now)
+            .map(|evt| evt.end);
+
+        let expired = data.current.as_ref().is_some_and(|ovr| ovr.until <= now);
+        if expired {
+            tracing::info!("The manual override expired");
+            data.current = None;
+            if let Err(err) = self.persist(None) {
+                tracing::warn!("Could not remove the persisted override: {}", err);
+            }
+        }
+
+        if let Some(ovr) = &data.current {
+            let (availability, activity) = ovr.color.availability();
+            snapshot.availability = Some(availability);
+            snapshot.activity = Some(activity);
+            snapshot.status_message = ovr.text.clone();
+            snapshot.overriding = true;
+        }
+    }
+
+    fn persist(&self, current: Option<&Override>) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        match current {
+            Some(ovr) => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let json =
+                    serde_json::to_vec(ovr).map_err(|err| Error::Control(err.to_string()))?;
+                fs::write(path, json)?;
+            }
+            None => match fs::remove_file(path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            },
+        }
+        Ok(())
+    }
+}
+
+/// In the runtime directory, e.g. `/run/user/1000/pico-client.sock`
+#[cfg(unix)]
+pub fn default_socket_path() -> PathBuf {
+    dirs::runtime_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join(SOCKET_FILE)
+}
+
+/// Accepts `ControlRequest`s on the Unix socket at `path` until the process
+/// exits. Each connection gets a one line answer.
+#[cfg(unix)]
+#[tracing::instrument]
+pub async fn serve(path: &Path, store: OverrideStore) -> Result<()> {
+    // A socket left behind by a previous run would make the bind fail
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    let listener = bind_private(path)?;
+    tracing::info!("Listening for overrides on {:?}", path);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let store = store.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle(stream, &store).await {
+                tracing::warn!("Control connection failed: {}", err);
+            }
+        });
+    }
+}
+
+/// Binds the socket inside a fresh owner-only directory and only moves it to
+/// `path` once it is 0600, so other local users never get to connect
+#[cfg(unix)]
+fn bind_private(path: &Path) -> Result<UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let staging = path.with_file_name(format!(".pico-client-{}", std::process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join(SOCKET_FILE);
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&staged);
+    fs::remove_dir(&staging)?;
+    Ok(bound?)
+}
+
+#[cfg(unix)]
+async fn handle(stream: UnixStream, store: &OverrideStore) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line).await?;
+
+    let reply = match serde_json::from_str::<ControlRequest>(&line) {
+        Ok(ControlRequest::Override { color, text, until }) => {
+            store.set(color, text, until).map(|ovr| {
+                format!(
+                    "Overriding until {}",
+                    ovr.until.with_timezone(&chrono::Local)
+                )
+            })
+        }
+        Ok(ControlRequest::Clear) => store.clear().map(|_| "Override cleared".into()),
+        Err(err) => Err(Error::Control(format!("Invalid request: {}", err))),
+    };
+    let reply = match reply {
+        Ok(message) => format!("ok: {}\n", message),
+        Err(err) => format!("error: {}\n", err),
+    };
+    writer.write_all(reply.as_bytes()).await?;
+    Ok(())
+}
+
+/// Sends `request` to the daemon listening at `path` and returns its answer
+#[cfg(unix)]
+#[tracing::instrument]
+pub async fn send(path: &Path, request: &ControlRequest) -> Result<String> {
+    let stream = UnixStream::connect(path).await.map_err(|err| {
+        Error::Control(format!(
+            "Could not reach pico-client at {:?}. Is it running? {}",
+            path, err
+        ))
+    })?;
+    let (reader, mut writer) = stream.into_split();
+    let mut json = serde_json::to_string(request).map_err(|err| Error::Control(err.to_string()))?;
+    json.push('\n');
+    writer.write_all(json.as_bytes()).await?;
+
+    let mut reply = String::new();
+    BufReader::new(reader).read_line(&mut reply).await?;
+    match reply.trim_end().split_once(": ") {
+        Some(("ok", message)) => Ok(message.into()),
+        Some(("error", message)) => Err(Error::Control(message.into())),
+        _ => Err(Error::Control(format!("Unexpected reply: {:?}", reply))),
+    }
+}
+
+/// Parses durations like `45m`, `1h30m` or `90s`
+pub fn parse_duration(value: &str) -> std::result::Result<chrono::Duration, String> {
+    let mut total = chrono::Duration::zero();
+    let mut digits = String::new();
+    for c in value.trim().chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let amount: i64 = digits
+            .parse()
+            .map_err(|_| format!("Expected a number before '{}' in {:?}", c, value))?;
+        total += match c {
+            'h' => chrono::Duration::hours(amount),
+            'm' => chrono::Duration::minutes(amount),
+            's' => chrono::Duration::seconds(amount),
+            _ => {
+                return Err(format!(
+                    "Unknown unit '{}' in {:?}, use h, m or s",
+                    c, value
+                ))
+            }
+        };
+        digits.clear();
+    }
+    if !digits.is_empty() || total <= chrono::Duration::zero() {
+        return Err(format!(
+            "Expected a duration like 45m or 1h30m, got {:?}",
+            value
+        ));
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::SourceEvent;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("pico-client-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("45m"), Ok(chrono::Duration::minutes(45)));
+        assert_eq!(parse_duration("1h30m"), Ok(chrono::Duration::minutes(90)));
+        assert_eq!(parse_duration("90s"), Ok(chrono::Duration::seconds(90)));
+        assert!(parse_duration("45").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("3d").is_err());
+    }
+
+    #[test]
+    fn test_override_applies_persists_and_expires() {
+        let path = temp_path("override.json");
+        let store = OverrideStore::load(Some(path.clone()));
+        let until = Utc::now() + chrono::Duration::minutes(45);
+        store
+            .set(OverrideColor::Red, Some("Focus".into()), Until::At(until))
+            .unwrap();
+
+        // A restarted daemon picks the override up again
+        let restored = OverrideStore::load(Some(path.clone()));
+        let mut snapshot = SourceSnapshot {
+            availability: Some(Availability::Available),
+            ..Default::default()
+        };
+        restored.apply(&mut snapshot, Utc::now());
+        assert_eq!(snapshot.availability, Some(Availability::DoNotDisturb));
+        assert_eq!(snapshot.status_message.as_deref(), Some("Focus"));
+        assert!(snapshot.overriding);
+
+        let mut snapshot = SourceSnapshot::default();
+        restored.apply(&mut snapshot, until);
+        assert_eq!(snapshot.availability, None);
+        assert!(restored.current().is_none());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_failed_persist_leaves_the_override_alone() {
+        // A file where the override's directory should be
+        let blocker = temp_path("override-blocker");
+        fs::write(&blocker, b"").unwrap();
+        let store = OverrideStore::load(Some(blocker.join("override.json")));
+        let until = Until::At(Utc::now() + chrono::Duration::minutes(5));
+        assert!(store.set(OverrideColor::Red, None, until).is_err());
+        assert!(store.current().is_none());
+
+        let path = temp_path("override-kept.json");
+        let store = OverrideStore::load(Some(path.clone()));
+        store.set(OverrideColor::Red, None, until).unwrap();
+        // A directory in its place can't be removed like the file
+        fs::remove_file(&path).unwrap();
+        fs::create_dir(&path).unwrap();
+        assert!(store.clear().is_err());
+        assert_eq!(store.current().unwrap().color, OverrideColor::Red);
+
+        fs::remove_dir(path).unwrap();
+        fs::remove_file(blocker).unwrap();
+    }
+
+    #[test]
+    fn test_until_meeting_ends() {
+        let store = OverrideStore::load(None);
+        assert!(store
+            .set(OverrideColor::Yellow, None, Until::MeetingEnds)
+            .is_err());
+
+        let now = Utc::now();
+        let end = now + chrono::Duration::minutes(20);
+        let mut snapshot = SourceSnapshot {
+            events: vec![SourceEvent {
+                subject: "Standup".into(),
+                start: now - chrono::Duration::minutes(10),
+                end,
+                attendee_count: 4,
+            }],
+            ..Default::default()
+        };
+        store.apply(&mut snapshot, now);
+        let ovr = store
+            .set(OverrideColor::Yellow, None, Until::MeetingEnds)
+            .unwrap();
+        assert_eq!(ovr.until, end);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_socket_round_trip() {
+        let path = temp_path("control.sock");
+        let store = OverrideStore::load(None);
+        let server = tokio::spawn({
+            let path = path.clone();
+            let store = store.clone();
+            async move { serve(&path, store).await }
+        });
+        while !path.exists() {
+            tokio::task::yield_now().await;
+        }
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let request = ControlRequest::Override {
+            color: OverrideColor::Green,
+            text: None,
+            until: Until::At(Utc::now() + chrono::Duration::minutes(5)),
+        };
+        send(&path, &request).await.unwrap();
+        assert_eq!(store.current().unwrap().color, OverrideColor::Green);
+        let changed = tokio::time::timeout(std::time::Duration::from_secs(5), store.changed());
+        assert!(changed.await.is_ok());
+
+        send(&path, &ControlRequest::Clear).await.unwrap();
+        assert!(store.current().is_none());
+
+        server.abort();
+        fs::remove_file(path).unwrap();
+    }
+}
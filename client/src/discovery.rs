@@ -0,0 +1,296 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use futures::stream::{self, StreamExt};
+use reqwest::header::SERVER;
+use tokio::{net::UdpSocket, time::Duration};
+
+use crate::error::{Error, Result};
+
+/// The `Server` header the firmware sends with every response
+const PICO_SERVER_HEADER: &str = "pi-in-the-sky";
+const MDNS_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353);
+const MDNS_TIMEOUT: Duration = Duration::from_secs(2);
+const PROBE_TIMEOUT: Duration = Duration::from_millis(750);
+const PROBE_CONCURRENCY: usize = 64;
+const DNS_TYPE_A: u16 = 1;
+const DNS_CLASS_IN: u16 = 1;
+
+/// How to look for Picos on the local network
+#[derive(Clone, Debug)]
+pub struct DiscoveryOptions {
+    /// The mDNS hostname the firmware announces, without `.local`
+    pub mdns_hostname: String,
+    /// Probe every host of this subnet when mDNS finds nothing. `None`
+    /// disables the scan.
+    pub scan_subnet: Option<Subnet>,
+}
+
+/// Finds Picos, first over mDNS and then, if enabled, by scanning the subnet
+/// for hosts that answer with the firmware's `Server` header. The `claimed`
+/// addresses are already in use by another target and are skipped.
+#[tracing::instrument]
+pub async fn discover(options: &DiscoveryOptions, claimed: &[Ipv4Addr]) -> Result<Vec<Ipv4Addr>> {
+    match resolve_mdns(&options.mdns_hostname).await {
+        Ok(Some(addr)) if claimed.contains(&addr) => {
+            tracing::info!("{} answered mDNS but is already in use", addr);
+        }
+        Ok(Some(addr)) if probe(addr).await => {
+            tracing::info!(
+                "Found {}.local at {} over mDNS",
+                options.mdns_hostname,
+                addr
+            );
+            return Ok(vec![addr]);
+        }
+        Ok(Some(addr)) => {
+            tracing::warn!("{} answered mDNS but is not a Pico", addr);
+        }
+        Ok(None) => {
+            tracing::info!("No mDNS answer for {}.local", options.mdns_hostname);
+        }
+        Err(err) => tracing::warn!("mDNS lookup failed: {}", err),
+    }
+
+    let subnet = match &options.scan_subnet {
+        Some(subnet) => subnet,
+        None => return Ok(vec![]),
+    };
+    tracing::info!("Scanning {} for Picos...", subnet);
+    let mut found: Vec<Ipv4Addr> = stream::iter(subnet.hosts())
+        .filter(|addr| std::future::ready(!claimed.contains(addr)))
+        .map(|addr| async move { probe(addr).await.then_some(addr) })
+        .buffer_unordered(PROBE_CONCURRENCY)
+        .filter_map(|addr| async move { addr })
+        .collect()
+        .await;
+    found.sort();
+    tracing::info!("Found Picos: {:?}", found);
+    Ok(found)
+}
+
+/// Picks the first Pico `discover` finds that isn't `claimed`, so several
+/// discovered targets end up on different Picos
+pub async fn discover_one(options: &DiscoveryOptions, claimed: &[Ipv4Addr]) -> Result<Ipv4Addr> {
+    let found = discover(options, claimed).await?;
+    if found.len() > 1 {
+        tracing::warn!("Found several Picos {:?}. Using {}", found, found[0]);
+    }
+    found
+        .first()
+        .copied()
+        .ok_or_else(|| Error::Config("No unclaimed Pico could be discovered on the network".into()))
+}
+
+/// Checks whether the host serves the Pico firmware on port 80. `GET /` is not
+/// a supported route, so the probe never repaints the screen.
+async fn probe(addr: Ipv4Addr) -> bool {
+    let client = match reqwest::Client::builder().timeout(PROBE_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(_) => return false,
+    };
+    match client.get(format!("http://{}/", addr)).send().await {
+        Ok(res) => res
+            .headers()
+            .get(SERVER)
+            .and_then(|val| val.to_str().ok())
+            .map(|val| val.trim() == PICO_SERVER_HEADER)
+            .unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+/// Sends a one-shot mDNS query for `<hostname>.local` and returns the first A
+/// record in the answers
+async fn resolve_mdns(hostname: &str) -> Result<Option<Ipv4Addr>> {
+    let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))).await?;
+    let fqdn = format!("{}.local", hostname);
+    socket.send_to(&build_mdns_query(&fqdn), MDNS_ADDR).await?;
+
+    let mut buf = [0u8; 1500];
+    let deadline = tokio::time::Instant::now() + MDNS_TIMEOUT;
+    loop {
+        let received = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await;
+        let (len, _) = match received {
+            Ok(res) => res?,
+            Err(_) => return Ok(None),
+        };
+        if let Some(addr) = parse_mdns_answer(&buf[..len], &fqdn) {
+            return Ok(Some(addr));
+        }
+    }
+}
+
+fn build_mdns_query(fqdn: &str) -> Vec<u8> {
+    // ID 0, no flags, a single question
+    let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in fqdn.split('.') {
+        packet.push(label.len() as u8);
+        packet.extend(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend(DNS_TYPE_A.to_be_bytes());
+    packet.extend(DNS_CLASS_IN.to_be_bytes());
+    packet
+}
+
+fn parse_mdns_answer(packet: &[u8], fqdn: &str) -> Option<Ipv4Addr> {
+    let read_u16 = |pos: usize| -> Option<u16> {
+        Some(u16::from_be_bytes([
+            *packet.get(pos)?,
+            *packet.get(pos + 1)?,
+        ]))
+    };
+    let questions = read_u16(4)?;
+    let records = read_u16(6)? + read_u16(8)? + read_u16(10)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        let (_, next) = read_name(packet, pos)?;
+        pos = next + 4;
+    }
+    for _ in 0..records {
+        let (name, next) = read_name(packet, pos)?;
+        let rtype = read_u16(next)?;
+        // The top bit of the class is the mDNS cache flush flag
+        let class = read_u16(next + 2)? & 0x7fff;
+        let len = read_u16(next + 8)? as usize;
+        let data = packet.get(next + 10..next + 10 + len)?;
+        if rtype == DNS_TYPE_A
+            && class == DNS_CLASS_IN
+            && len == 4
+            && name.eq_ignore_ascii_case(fqdn)
+        {
+            return Some(Ipv4Addr::new(data[0], data[1], data[2], data[3]));
+        }
+        pos = next + 10 + len;
+    }
+    None
+}
+
+/// Reads a possibly compressed DNS name. Returns the name and the position
+/// right after it in the packet.
+fn read_name(packet: &[u8], start: usize) -> Option<(String, usize)> {
+    let mut labels = vec![];
+    let mut pos = start;
+    let mut end = None;
+    // Bound the number of compression jumps so a malicious packet can't loop
+    for _ in 0..32 {
+        let len = *packet.get(pos)? as usize;
+        if len == 0 {
+            return Some((labels.join("."), end.unwrap_or(pos + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            let offset = ((len & 0x3f) << 8) | *packet.get(pos + 1)? as usize;
+            end.get_or_insert(pos + 2);
+            pos = offset;
+            continue;
+        }
+        let label = packet.get(pos + 1..pos + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len;
+    }
+    None
+}
+
+/// An IPv4 network in CIDR notation, e.g. `192.168.1.0/24`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Subnet {
+    network: Ipv4Addr,
+    prefix: u8,
+}
+
+impl Subnet {
+    /// The /24 of the interface that routes to the internet. No packets are
+    /// sent; connecting a UDP socket only selects the local address.
+    pub fn local() -> Result<Self> {
+        let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.connect((Ipv4Addr::new(8, 8, 8, 8), 80))?;
+        match socket.local_addr()? {
+            SocketAddr::V4(addr) => Ok(Subnet::new(*addr.ip(), 24)),
+            SocketAddr::V6(_) => Err(Error::Config(
+                "Could not determine the local IPv4 subnet. Pass --scan-subnet".into(),
+            )),
+        }
+    }
+
+    fn new(addr: Ipv4Addr, prefix: u8) -> Self {
+        let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+        Subnet {
+            network: Ipv4Addr::from(u32::from(addr) & mask),
+            prefix,
+        }
+    }
+
+    /// Every usable host address, skipping the network and broadcast addresses
+    pub fn hosts(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let first = u32::from(self.network);
+        let size = 1u64 << (32 - self.prefix as u32);
+        let (start, end) = if size > 2 {
+            (first as u64 + 1, first as u64 + size - 1)
+        } else {
+            (first as u64, first as u64 + size)
+        };
+        (start..end).map(|addr| Ipv4Addr::from(addr as u32))
+    }
+}
+
+impl std::str::FromStr for Subnet {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').unwrap_or((s, "24"));
+        let addr = addr
+            .parse::<Ipv4Addr>()
+            .map_err(|err| format!("Invalid subnet '{}': {}", s, err))?;
+        let prefix = prefix
+            .parse::<u8>()
+            .ok()
+            // Refuse anything bigger than a /16 so a typo can't scan for hours
+            .filter(|prefix| (16..=32).contains(prefix))
+            .ok_or_else(|| format!("Invalid prefix in '{}'. Expected 16 to 32", s))?;
+        Ok(Subnet::new(addr, prefix))
+    }
+}
+
+impl std::fmt::Display for Subnet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subnet_hosts() {
+        let subnet: Subnet = "192.168.1.77/24".parse().unwrap();
+        assert_eq!(subnet.to_string(), "192.168.1.0/24");
+        let hosts: Vec<_> = subnet.hosts().collect();
+        assert_eq!(hosts.len(), 254);
+        assert_eq!(hosts[0], Ipv4Addr::new(192, 168, 1, 1));
+        assert_eq!(hosts[253], Ipv4Addr::new(192, 168, 1, 254));
+        assert!("10.0.0.0/8".parse::<Subnet>().is_err());
+    }
+
+    #[test]
+    fn test_parse_mdns_answer() {
+        // A response echoing the question, then an A record that points back
+        // at the question's name with a compression pointer
+        let mut packet = build_mdns_query("pico-status.local");
+        packet[2] = 0x84;
+        packet[7] = 1;
+        packet.extend([0xc0, 12]);
+        packet.extend(DNS_TYPE_A.to_be_bytes());
+        packet.extend((0x8000 | DNS_CLASS_IN).to_be_bytes());
+        packet.extend(120u32.to_be_bytes());
+        packet.extend(4u16.to_be_bytes());
+        packet.extend([192, 168, 1, 42]);
+
+        assert_eq!(
+            parse_mdns_answer(&packet, "pico-status.local"),
+            Some(Ipv4Addr::new(192, 168, 1, 42))
+        );
+        assert_eq!(parse_mdns_answer(&packet, "other.local"), None);
+    }
+}
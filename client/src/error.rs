@@ -0,0 +1,146 @@
+use reqwest::StatusCode;
+use tokio::time::Duration;
+
+use crate::oauth::TokenError;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Every way the pico-client can fail. The run loop matches on the variant to
+/// decide whether to re-authenticate, back off, or keep going.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The sign in could not be completed, e.g. it timed out or was declined
+    #[error("Authentication failed: {0}")]
+    Auth(String),
+
+    /// The identity platform answered a token request with an OAuth error
+    #[error("The token request was rejected: {0}")]
+    Token(#[from] TokenError),
+
+    /// The identity platform could not be reached
+    #[error("Could not reach the identity platform: {0}")]
+    AuthTransport(#[source] reqwest_middleware::Error),
+
+    /// Graph answered with a non-success status code
+    #[error("Graph responded with {status}: {body}")]
+    GraphStatus {
+        status: StatusCode,
+        retry_after: Option<Duration>,
+        body: String,
+    },
+
+    /// Graph answered, but not with the JSON shape we expect
+    #[error("Could not deserialize the Graph response: {0}")]
+    GraphDeserialize(#[source] serde_json::Error),
+
+    /// Graph could not be reached
+    #[error("Could not reach Graph: {0}")]
+    GraphTransport(#[source] reqwest_middleware::Error),
+
+    /// The Pico could not be reached or did not accept the screen
+    #[error("Could not update the Pico: {0}")]
+    PicoTransport(#[source] reqwest_middleware::Error),
+
+    /// A calendar source could not be fetched or parsed
+    #[error("Could not read the calendar: {0}")]
+    Calendar(String),
+
+    /// The Slack Web API could not be reached or refused the request
+    #[error("Slack error: {0}")]
+    Slack(String),
+
+    /// The desktop session's idle or lock state could not be read
+    #[error("Could not read the session state: {0}")]
+    Session(String),
+
+    /// An override could not be set, or the daemon could not be reached
+    #[error("Control error: {0}")]
+    Control(String),
+
+    /// The command line or a file it points to is invalid
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+
+    /// The token cache could not be read or written
+    #[error("Token cache error: {0}")]
+    TokenCache(String),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl Error {
+    /// Graph rejected the access token, so a refresh or new sign in is needed
+    pub fn is_unauthorized(&self) -> bool {
+        matches!(self, Error::GraphStatus { status, .. } if *status == StatusCode::UNAUTHORIZED)
+    }
+
+    /// Graph is throttling us. Returns how long it asked us to wait, if it did.
+    pub fn throttled_for(&self) -> Option<Option<Duration>> {
+        match self {
+            Error::GraphStatus {
+                status,
+                retry_after,
+                ..
+            } if *status == StatusCode::TOO_MANY_REQUESTS => Some(*retry_after),
+            _ => None,
+        }
+    }
+
+    /// The identity platform no longer accepts the refresh token
+    pub fn is_invalid_grant(&self) -> bool {
+        matches!(self, Error::Token(err) if err.error == "invalid_grant")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph_status(status: StatusCode, retry_after: Option<Duration>) -> Error {
+        Error::GraphStatus {
+            status,
+            retry_after,
+            body: String::new(),
+        }
+    }
+
+    fn token_error(error: &str) -> Error {
+        Error::Token(TokenError {
+            error: error.into(),
+            error_description: String::new(),
+        })
+    }
+
+    #[test]
+    fn test_is_unauthorized() {
+        assert!(graph_status(StatusCode::UNAUTHORIZED, None).is_unauthorized());
+        assert!(!graph_status(StatusCode::FORBIDDEN, None).is_unauthorized());
+        assert!(!token_error("invalid_grant").is_unauthorized());
+    }
+
+    #[test]
+    fn test_throttled_for() {
+        let retry_after = Some(Duration::from_secs(30));
+        assert_eq!(
+            graph_status(StatusCode::TOO_MANY_REQUESTS, retry_after).throttled_for(),
+            Some(retry_after)
+        );
+        assert_eq!(
+            graph_status(StatusCode::TOO_MANY_REQUESTS, None).throttled_for(),
+            Some(None)
+        );
+        assert_eq!(
+            graph_status(StatusCode::SERVICE_UNAVAILABLE, retry_after).throttled_for(),
+            None
+        );
+        assert_eq!(Error::Calendar("down".into()).throttled_for(), None);
+    }
+
+    #[test]
+    fn test_is_invalid_grant() {
+        assert!(token_error("invalid_grant").is_invalid_grant());
+        assert!(!token_error("invalid_client").is_invalid_grant());
+        assert!(!graph_status(StatusCode::UNAUTHORIZED, None).is_invalid_grant());
+    }
+}
@@ -10,3 +10,11 @@ pub fn build_durable_client() -> DurableClient {
         .with(RetryTransientMiddleware::new_with_policy(retry_policy))
         .build()
 }
+
+/// A client for Graph, which leaves out the transient retries. Graph says in
+/// `Retry-After` how long to back off from a 429 or 5xx, and the retry
+/// middleware doesn't listen. So the `Error::GraphStatus` goes to the callers
+/// that do, i.e. `Batch::send` and the run loop.
+pub fn build_graph_client() -> DurableClient {
+    ClientBuilder::new(Client::new()).build()
+}
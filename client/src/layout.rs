@@ -0,0 +1,285 @@
+//! Fits text to the Waveshare 1.14" LCD of the Pico. The panel is 240x135
+//! pixels and the firmware draws each line with the 8x8 pixel framebuf font,
+//! 2 pixels from the left edge and 20 pixels below the previous line. That
+//! leaves room for 29 glyphs, but `paint.trim` cuts anything over 28 with
+//! "...", so 28 columns is what we lay out for.
+
+const PANEL_WIDTH_PX: usize = 240;
+const PANEL_HEIGHT_PX: usize = 135;
+const GLYPH_PX: usize = 8;
+const MARGIN_PX: usize = 2;
+const LINE_PITCH_PX: usize = 20;
+/// What `paint.trim` in the firmware lets through
+const FIRMWARE_COLUMNS: usize = 28;
+
+/// Characters per line
+pub const COLUMNS: usize = min((PANEL_WIDTH_PX - MARGIN_PX) / GLYPH_PX, FIRMWARE_COLUMNS);
+/// Lines per screen
+pub const ROWS: usize = (PANEL_HEIGHT_PX - GLYPH_PX) / LINE_PITCH_PX + 1;
+
+const ELLIPSIS: &str = "...";
+
+const fn min(a: usize, b: usize) -> usize {
+    if a < b {
+        a
+    } else {
+        b
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Align {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+/// A run of text on a line. Elastic pieces, e.g. an event subject, may be
+/// shortened to make the rest of the line fit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Piece {
+    pub text: String,
+    pub elastic: bool,
+}
+
+/// One line of the screen, before it is fitted
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Line {
+    pub pieces: Vec<Piece>,
+    pub align: Align,
+}
+
+impl Line {
+    pub fn push(&mut self, text: &str, elastic: bool) {
+        if text.is_empty() {
+            return;
+        }
+        match self.pieces.last_mut() {
+            Some(last) if !last.elastic && !elastic => last.text.push_str(text),
+            _ => self.pieces.push(Piece {
+                text: text.into(),
+                elastic,
+            }),
+        }
+    }
+
+    pub fn text(&self) -> String {
+        self.pieces
+            .iter()
+            .map(|piece| piece.text.as_str())
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pieces.iter().all(|piece| piece.text.is_empty())
+    }
+
+    /// The columns the line needs with every elastic piece shortened to
+    /// nothing but an ellipsis
+    pub fn min_width(&self) -> usize {
+        self.pieces
+            .iter()
+            .map(|piece| match piece.elastic {
+                true => width(&piece.text).min(ELLIPSIS.len()),
+                false => width(&piece.text),
+            })
+            .sum()
+    }
+}
+
+/// Fits every line to `COLUMNS`. A line that is too long wraps into the line
+/// below it when that one is empty, and otherwise has its elastic pieces
+/// shortened, longest first. Returns one string per line, empty for a blank
+/// one.
+pub fn fit(lines: &[Line]) -> Vec<String> {
+    let mut fitted: Vec<Option<String>> = vec![None; lines.len()];
+    for idx in 0..lines.len() {
+        if fitted[idx].is_some() {
+            continue;
+        }
+        let line = &lines[idx];
+        let text = line.text();
+        if width(&text) <= COLUMNS {
+            fitted[idx] = Some(align(&text, line.align));
+            continue;
+        }
+        let spare = lines.get(idx + 1).is_some_and(|next| next.is_empty());
+        if let Some((first, second)) = wrap(&text).filter(|_| spare) {
+            fitted[idx] = Some(align(&first, line.align));
+            fitted[idx + 1] = Some(align(&second, line.align));
+            continue;
+        }
+        fitted[idx] = Some(align(&shorten(line), line.align));
+    }
+    fitted.into_iter().map(Option::unwrap_or_default).collect()
+}
+
+/// Checks that `line` can be made to fit by shortening its elastic pieces
+pub fn validate(line: &Line) -> Result<(), String> {
+    let needed = line.min_width();
+    if needed > COLUMNS {
+        return Err(format!(
+            "{:?} needs {} columns even with its values shortened, but the screen has {}",
+            line.text(),
+            needed,
+            COLUMNS
+        ));
+    }
+    Ok(())
+}
+
+fn width(text: &str) -> usize {
+    text.chars().count()
+}
+
+fn align(text: &str, align: Align) -> String {
+    match align {
+        // Trailing spaces would only end up in the URI
+        Align::Left => text.trim_end().into(),
+        Align::Center => format!("{:^width$}", text.trim(), width = COLUMNS)
+            .trim_end()
+            .into(),
+        Align::Right => format!("{:>width$}", text.trim(), width = COLUMNS),
+    }
+}
+
+/// Breaks `text` at a space into two lines that both fit. The second line
+/// keeps the indentation of the first.
+fn wrap(text: &str) -> Option<(String, String)> {
+    let indent: String = text.chars().take_while(|c| *c == ' ').collect();
+    text.char_indices()
+        .filter(|(idx, c)| *c == ' ' && *idx > indent.len())
+        .map(|(idx, _)| (text[..idx].trim_end(), text[idx..].trim_start()))
+        .rfind(|(first, second)| {
+            !second.is_empty()
+                && width(first) <= COLUMNS
+                && width(&indent) + width(second) <= COLUMNS
+        })
+        .map(|(first, second)| (first.into(), format!("{}{}", indent, second)))
+}
+
+/// Shortens the elastic pieces, longest first, until the line fits. When that
+/// isn't enough the end is cut off, like the firmware would.
+fn shorten(line: &Line) -> String {
+    let mut pieces = line.pieces.clone();
+    let total = |pieces: &[Piece]| pieces.iter().map(|piece| width(&piece.text)).sum::<usize>();
+    while total(&pieces) > COLUMNS {
+        let excess = total(&pieces) - COLUMNS;
+        let longest = pieces
+            .iter_mut()
+            .filter(|piece| piece.elastic && width(&piece.text) > ELLIPSIS.len())
+            .max_by_key(|piece| width(&piece.text));
+        let piece = match longest {
+            Some(piece) => piece,
+            None => break,
+        };
+        let target = width(&piece.text)
+            .saturating_sub(excess)
+            .max(ELLIPSIS.len());
+        piece.text = ellipsize(&piece.text, target);
+    }
+    let text: String = pieces.iter().map(|piece| piece.text.as_str()).collect();
+    if width(&text) > COLUMNS {
+        tracing::warn!("{:?} does not fit on the screen", text);
+        return ellipsize(&text, COLUMNS);
+    }
+    text
+}
+
+/// Cuts `text` to `max` columns ending in "...", at a word boundary if that
+/// doesn't waste more than half of the room
+fn ellipsize(text: &str, max: usize) -> String {
+    if width(text) <= max {
+        return text.into();
+    }
+    let room = max.saturating_sub(ELLIPSIS.len());
+    let cut: String = text.chars().take(room).collect();
+    let cut = match cut.rfind(' ') {
+        Some(space) if width(&cut[..space]) * 2 >= room => cut[..space].trim_end(),
+        _ => cut.trim_end(),
+    };
+    format!("{}{}", cut, ELLIPSIS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(pieces: &[(&str, bool)], align: Align) -> Line {
+        let mut line = Line {
+            align,
+            ..Default::default()
+        };
+        for (text, elastic) in pieces {
+            line.push(text, *elastic);
+        }
+        line
+    }
+
+    #[test]
+    fn test_panel_grid() {
+        assert_eq!(COLUMNS, 28);
+        assert_eq!(ROWS, 7);
+    }
+
+    #[test]
+    fn test_shortens_the_subject_and_keeps_the_time() {
+        let lines = [line(
+            &[
+                ("  03:30 pm (", false),
+                ("Quarterly planning with the whole team", true),
+                (")", false),
+            ],
+            Align::Left,
+        )];
+        let fitted = fit(&lines);
+        assert_eq!(fitted[0], "  03:30 pm (Quarterly...)");
+        assert!(width(&fitted[0]) <= COLUMNS);
+    }
+
+    #[test]
+    fn test_wraps_into_a_spare_line() {
+        let lines = [
+            line(
+                &[
+                    (" \"", false),
+                    ("Heads down on the release, ping me later", true),
+                    ("\"", false),
+                ],
+                Align::Left,
+            ),
+            Line::default(),
+            line(&[(" Busy", false)], Align::Left),
+        ];
+        assert_eq!(
+            fit(&lines),
+            vec![" \"Heads down on the release,", " ping me later\"", " Busy",]
+        );
+    }
+
+    #[test]
+    fn test_alignment() {
+        let lines = [
+            line(&[("03:30 pm", false)], Align::Right),
+            line(&[("Busy", false)], Align::Center),
+        ];
+        let fitted = fit(&lines);
+        assert_eq!(fitted[0], format!("{:>28}", "03:30 pm"));
+        assert_eq!(fitted[1], "            Busy");
+    }
+
+    #[test]
+    fn test_validate() {
+        let fits = line(
+            &[
+                ("Until 03:30 pm: ", false),
+                ("A very long subject indeed", true),
+            ],
+            Align::Left,
+        );
+        assert!(validate(&fits).is_ok());
+        let too_wide = line(&[("Wednesday, September 14th 2022", false)], Align::Left);
+        assert!(validate(&too_wide).is_err());
+    }
+}
@@ -1,9 +1,24 @@
+mod batch;
 mod cli;
+mod client_auth;
+mod control;
+mod discovery;
+mod error;
 mod http;
+mod layout;
+mod notifications;
 mod oauth;
+mod pico;
+mod rules;
+mod source;
 mod status;
+mod template;
+#[cfg(test)]
+mod test_support;
+mod token_cache;
 
-use cli::{Cli, Parser};
+use cli::{Cli, Command, Parser};
+use pico::SharedPicoTargets;
 use tokio::signal;
 
 #[macro_use]
@@ -12,12 +27,33 @@ extern crate serde;
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Cli::parse();
+    if let Err(err) = args.validate() {
+        err.exit();
+    }
 
     cli::init_tracing(&args).expect("Could not initialize tracing infrastructure!");
     tracing::info!("CLI: {:?}", args);
 
+    match &args.command {
+        Some(Command::Discover(discovery)) => {
+            cli::discover(discovery).await?;
+            return Ok(());
+        }
+        #[cfg(unix)]
+        Some(Command::Override(override_args)) => {
+            cli::send_override(override_args).await?;
+            return Ok(());
+        }
+        #[cfg(unix)]
+        Some(Command::Clear(control)) => {
+            cli::send_clear(control).await?;
+            return Ok(());
+        }
+        None => {}
+    }
+
     let client = http::build_durable_client();
-    let pico_ip = args.get_pico_ip();
+    let pico_targets = SharedPicoTargets::new(args.get_pico_targets());
 
     // `tokio::select!` proc macro will concurrently execute/poll the futures.
     // The first to return or error will stop the listeners and execute the
@@ -25,7 +61,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let is_graceful_shutdown = tokio::select! {
         // An error from `cli::run` means we've exceeded the error threshold
         // and have encountered a fatal error
-        err = cli::run(args, &client) => {
+        err = cli::run(args, &client, pico_targets.clone()) => {
             tracing::error!("Fatal error: {:?}", err);
             false
         },
@@ -38,9 +74,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
 
     if is_graceful_shutdown {
-        status::set_graceful_shutdown(&client, &pico_ip).await?;
+        status::set_graceful_shutdown(&client, &pico_targets.snapshot()).await?;
     } else {
-        status::set_fatal_error(&client, &pico_ip).await?;
+        status::set_fatal_error(&client, &pico_targets.snapshot()).await?;
     }
 
     Ok(())
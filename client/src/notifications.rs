@@ -0,0 +1,484 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use axum::{
+    extract::{Extension, Query},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::post,
+    Json, Router,
+};
+use chrono::{DateTime, Duration, Utc};
+use rand::RngCore;
+use reqwest::Url;
+use tokio::sync::Notify;
+use tower::ServiceBuilder;
+
+use crate::error::{Error, Result};
+use crate::http::DurableClient;
+use crate::oauth::SharedAccessToken;
+use crate::status;
+
+/// Presence subscriptions may last at most an hour
+const PRESENCE_LIFETIME_MINUTES: i64 = 60;
+/// Outlook event subscriptions may last just under three days
+const EVENTS_LIFETIME_MINUTES: i64 = 4230;
+/// Subscriptions are renewed this long before they expire
+const RENEW_BEFORE_MINUTES: i64 = 10;
+/// How long to wait before retrying a failed renewal
+const RETRY_AFTER_SECS: u64 = 60;
+
+/// Where Graph delivers change notifications
+#[derive(Clone, Debug)]
+pub struct NotificationOptions {
+    pub graph_url: String,
+    /// The public URL Graph posts to, e.g. a tunnel to `bind`
+    pub notification_url: Url,
+    /// The local address of the endpoint
+    pub bind: SocketAddr,
+}
+
+/// The things we subscribe to. Each one wakes the run loop when it changes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Resource {
+    Presence,
+    Events,
+}
+
+impl Resource {
+    fn path(self, user_id: &str) -> String {
+        match self {
+            Resource::Presence => format!("/communications/presences/{}", user_id),
+            Resource::Events => "/me/events".into(),
+        }
+    }
+
+    fn change_type(self) -> &'static str {
+        match self {
+            Resource::Presence => "updated",
+            Resource::Events => "created,updated,deleted",
+        }
+    }
+
+    fn lifetime(self) -> Duration {
+        match self {
+            Resource::Presence => Duration::minutes(PRESENCE_LIFETIME_MINUTES),
+            Resource::Events => Duration::minutes(EVENTS_LIFETIME_MINUTES),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Subscription {
+    id: String,
+    expiration_date_time: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Me {
+    id: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ChangeNotifications {
+    value: Vec<ChangeNotification>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChangeNotification {
+    subscription_id: String,
+    client_state: Option<String>,
+    #[serde(default)]
+    change_type: String,
+}
+
+/// Shared with the notification endpoint
+#[derive(Clone, Debug)]
+struct Endpoint {
+    client_state: String,
+    wake: Arc<Notify>,
+}
+
+/// The handle on the change notifications
+#[derive(Clone, Debug)]
+pub struct Notifications {
+    /// Fires whenever Graph reports a change
+    pub wake: Arc<Notify>,
+    subscribed: Arc<AtomicBool>,
+}
+
+impl Notifications {
+    /// Whether every subscription is up, so polling can slow down
+    pub fn is_subscribed(&self) -> bool {
+        self.subscribed.load(Ordering::Relaxed)
+    }
+}
+
+/// Serves the notification endpoint, subscribes to presence and calendar
+/// changes and keeps the subscriptions renewed, all in the background. What
+/// fails is retried until every subscription is up.
+pub fn start(
+    client: &DurableClient,
+    token: &SharedAccessToken,
+    options: &NotificationOptions,
+) -> Notifications {
+    let mut secret = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut secret);
+    let endpoint = Endpoint {
+        client_state: hex::encode(secret),
+        wake: Arc::new(Notify::new()),
+    };
+    let notifications = Notifications {
+        wake: endpoint.wake.clone(),
+        subscribed: Arc::new(AtomicBool::new(false)),
+    };
+    let mut subscriber = Subscriber {
+        client: client.clone(),
+        token: token.clone(),
+        options: options.clone(),
+        endpoint,
+        serving: false,
+        user_id: None,
+        pending: vec![Resource::Presence, Resource::Events],
+    };
+    let subscribed = notifications.subscribed.clone();
+    tokio::spawn(async move {
+        while let Err(err) = subscriber.subscribe().await {
+            tracing::warn!(
+                "Could not subscribe to Graph notifications: {}. Retrying in {} seconds.",
+                err,
+                RETRY_AFTER_SECS
+            );
+            tokio::time::sleep(std::time::Duration::from_secs(RETRY_AFTER_SECS)).await;
+        }
+        subscribed.store(true, Ordering::Relaxed);
+    });
+    notifications
+}
+
+/// Remembers how far subscribing got, so a retry picks up where it failed
+struct Subscriber {
+    client: DurableClient,
+    token: SharedAccessToken,
+    options: NotificationOptions,
+    endpoint: Endpoint,
+    serving: bool,
+    user_id: Option<String>,
+    pending: Vec<Resource>,
+}
+
+impl Subscriber {
+    #[tracing::instrument(skip(self), fields(pending = ?self.pending))]
+    async fn subscribe(&mut self) -> Result<()> {
+        // Graph validates the endpoint while the subscription is created, so
+        // it has to be up first
+        if !self.serving {
+            self.serve()?;
+            self.serving = true;
+        }
+        let user_id = match &self.user_id {
+            Some(user_id) => user_id.clone(),
+            None => {
+                let me: Me = status::graph_send(
+                    self.client
+                        .get(format!("{}/me?$select=id", self.options.graph_url)),
+                    &self.token,
+                )
+                .await?;
+                self.user_id = Some(me.id.clone());
+                me.id
+            }
+        };
+        while let Some(&resource) = self.pending.first() {
+            let subscription = create(
+                &self.client,
+                &self.token,
+                &self.options,
+                &user_id,
+                resource,
+                &self.endpoint,
+            )
+            .await?;
+            tokio::spawn(keep_renewed(
+                self.client.clone(),
+                self.token.clone(),
+                self.options.clone(),
+                user_id.clone(),
+                resource,
+                self.endpoint.clone(),
+                subscription,
+            ));
+            self.pending.remove(0);
+        }
+        Ok(())
+    }
+
+    fn serve(&self) -> Result<()> {
+        let options = &self.options;
+        let app = Router::new()
+            .route(options.notification_url.path(), post(handle_notification))
+            .layer(
+                ServiceBuilder::new()
+                    .layer(Extension(self.endpoint.clone()))
+                    .into_inner(),
+            );
+        let server = axum::Server::try_bind(&options.bind).map_err(|err| {
+            Error::Config(format!("Could not listen on {}: {}", options.bind, err))
+        })?;
+        tokio::spawn(async move {
+            if let Err(err) = server.serve(app.into_make_service()).await {
+                tracing::error!("The notification endpoint failed: {}", err);
+            }
+        });
+        tracing::info!(
+            "Receiving Graph notifications on {} for {}",
+            options.bind,
+            options.notification_url
+        );
+        Ok(())
+    }
+}
+
+async fn create(
+    client: &DurableClient,
+    token: &SharedAccessToken,
+    options: &NotificationOptions,
+    user_id: &str,
+    resource: Resource,
+    endpoint: &Endpoint,
+) -> Result<Subscription> {
+    let body = serde_json::json!({
+        "changeType": resource.change_type(),
+        "notificationUrl": options.notification_url.as_str(),
+        "resource": resource.path(user_id),
+        "expirationDateTime": expiration(resource),
+        "clientState": endpoint.client_state,
+    });
+    let subscription: Subscription = status::graph_send(
+        client
+            .post(format!("{}/subscriptions", options.graph_url))
+            .json(&body),
+        token,
+    )
+    .await?;
+    tracing::info!(
+        "Subscribed to {:?} changes until {}",
+        resource,
+        subscription.expiration_date_time
+    );
+    Ok(subscription)
+}
+
+/// Renews `subscription` ahead of its expiry, and creates a new one when Graph
+/// has dropped it
+async fn keep_renewed(
+    client: DurableClient,
+    token: SharedAccessToken,
+    options: NotificationOptions,
+    user_id: String,
+    resource: Resource,
+    endpoint: Endpoint,
+    mut subscription: Subscription,
+) {
+    loop {
+        let renew_at = subscription.expiration_date_time - Duration::minutes(RENEW_BEFORE_MINUTES);
+        let wait = (renew_at - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(wait).await;
+
+        let renewal = status::graph_send::<Subscription>(
+            client
+                .patch(format!(
+                    "{}/subscriptions/{}",
+                    options.graph_url, subscription.id
+                ))
+                .json(&serde_json::json!({ "expirationDateTime": expiration(resource) })),
+            &token,
+        )
+        .await;
+        let renewal = match renewal {
+            Err(Error::GraphStatus { status, .. }) if status == StatusCode::NOT_FOUND => {
+                tracing::warn!(
+                    "The {:?} subscription is gone. Subscribing again.",
+                    resource
+                );
+                create(&client, &token, &options, &user_id, resource, &endpoint).await
+            }
+            renewal => renewal,
+        };
+        match renewal {
+            Ok(renewed) => {
+                tracing::info!(
+                    "Renewed the {:?} subscription until {}",
+                    resource,
+                    renewed.expiration_date_time
+                );
+                subscription = renewed;
+            }
+            Err(err) => {
+                tracing::warn!(
+                    "Could not renew the {:?} subscription: {}. Retrying in {} seconds.",
+                    resource,
+                    err,
+                    RETRY_AFTER_SECS
+                );
+                tokio::time::sleep(std::time::Duration::from_secs(RETRY_AFTER_SECS)).await;
+            }
+        }
+    }
+}
+
+fn expiration(resource: Resource) -> String {
+    (Utc::now() + resource.lifetime()).to_rfc3339()
+}
+
+/// Answers the validation request Graph sends when subscribing, and wakes the
+/// run loop for notifications that carry our client state
+async fn handle_notification(
+    Query(params): Query<HashMap<String, String>>,
+    Extension(endpoint): Extension<Endpoint>,
+    body: Option<Json<ChangeNotifications>>,
+) -> impl IntoResponse {
+    if let Some(validation_token) = params.get("validationToken") {
+        tracing::info!("Graph is validating the notification endpoint");
+        return (StatusCode::OK, validation_token.clone());
+    }
+    let notifications = match body {
+        Some(Json(notifications)) => notifications,
+        None => return (StatusCode::BAD_REQUEST, String::new()),
+    };
+    let mut wake = false;
+    for notification in notifications.value {
+        if notification.client_state.as_deref() == Some(endpoint.client_state.as_str()) {
+            tracing::info!(
+                "Graph notified a {} change for subscription {}",
+                notification.change_type,
+                notification.subscription_id
+            );
+            wake = true;
+        } else {
+            tracing::warn!(
+                "Ignoring a notification for subscription {} with the wrong client state",
+                notification.subscription_id
+            );
+        }
+    }
+    if wake {
+        endpoint.wake.notify_one();
+    }
+    (StatusCode::ACCEPTED, String::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use axum::routing::get;
+
+    use super::*;
+    use crate::http;
+    use crate::oauth::test_token;
+    use crate::test_support::{free_addr, spawn_fake_graph};
+
+    /// Graph's side: validates the endpoint when subscribing, like Graph does,
+    /// and remembers each subscription's client state
+    #[derive(Clone, Debug, Default)]
+    struct FakeGraph {
+        subscriptions: Arc<Mutex<Vec<serde_json::Value>>>,
+    }
+
+    async fn fake_me() -> Json<serde_json::Value> {
+        Json(serde_json::json!({ "id": "user-1" }))
+    }
+
+    async fn fake_subscribe(
+        Extension(graph): Extension<FakeGraph>,
+        Json(body): Json<serde_json::Value>,
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        let url = format!(
+            "{}?validationToken=Validation%3A%20Token",
+            body["notificationUrl"].as_str().unwrap()
+        );
+        let echoed = reqwest::Client::new()
+            .post(url)
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        if echoed != "Validation: Token" {
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({})));
+        }
+        graph.subscriptions.lock().unwrap().push(body.clone());
+        let expires = Utc::now() + Duration::minutes(PRESENCE_LIFETIME_MINUTES);
+        (
+            StatusCode::CREATED,
+            Json(serde_json::json!({ "id": "sub-1", "expirationDateTime": expires })),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_subscribes_and_wakes_on_notifications() {
+        let graph = FakeGraph::default();
+        let graph_url = spawn_fake_graph(
+            Router::new()
+                .route("/me", get(fake_me))
+                .route("/subscriptions", post(fake_subscribe))
+                .layer(Extension(graph.clone())),
+        );
+
+        let bind = free_addr();
+        let options = NotificationOptions {
+            graph_url,
+            notification_url: format!("http://{}/graph/notify", bind).parse().unwrap(),
+            bind,
+        };
+        let client = http::build_graph_client();
+        let notifications = start(&client, &test_token(), &options);
+        let subscribed = async {
+            while !notifications.is_subscribed() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), subscribed)
+            .await
+            .unwrap();
+        let wake = notifications.wake;
+
+        let subscriptions = graph.subscriptions.lock().unwrap().clone();
+        assert_eq!(subscriptions.len(), 2);
+        assert_eq!(
+            subscriptions[0]["resource"],
+            "/communications/presences/user-1"
+        );
+        assert_eq!(subscriptions[1]["resource"], "/me/events");
+
+        let notify = |client_state: &serde_json::Value| {
+            reqwest::Client::new()
+                .post(options.notification_url.clone())
+                .json(&serde_json::json!({ "value": [{
+                    "subscriptionId": "sub-1",
+                    "clientState": client_state,
+                    "changeType": "updated",
+                    "resource": "/communications/presences/user-1",
+                }]}))
+                .send()
+        };
+        let res = notify(&serde_json::json!("forged")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        let woke = tokio::time::timeout(std::time::Duration::from_millis(200), wake.notified());
+        assert!(woke.await.is_err());
+
+        notify(&subscriptions[0]["clientState"]).await.unwrap();
+        let woke = tokio::time::timeout(std::time::Duration::from_secs(5), wake.notified());
+        assert!(woke.await.is_ok());
+    }
+}
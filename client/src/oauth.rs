@@ -1,26 +1,71 @@
 use axum::{
     extract::{Extension, Query},
+    http::StatusCode,
     response::{Html, IntoResponse},
     routing::get,
     Router,
 };
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
 use std::{
     env, fmt,
     net::SocketAddr,
-    process::Command,
     sync::{Arc, Mutex},
 };
-use tokio::time::Duration;
+use tokio::{
+    process::Command,
+    sync::{mpsc, oneshot, Notify},
+    time::Duration,
+};
 use tower::ServiceBuilder;
 
-use crate::http::DurableClient;
+use crate::{
+    client_auth::{ClientAuthParams, ClientCredential},
+    error::{Error, Result},
+    http::DurableClient,
+    token_cache::TokenCache,
+};
+
+/// Sent by the redirect handler once the sign in completed or failed
+type RedirectOutcome = std::result::Result<(), String>;
+
+/// The Microsoft identity platform
+const DEFAULT_AUTHORITY: &str = "https://login.microsoftonline.com";
+const REFRESH_BACKOFF_MIN: Duration = Duration::from_secs(5);
+const REFRESH_BACKOFF_MAX: Duration = Duration::from_secs(300);
+const REFRESH_MAX_NAP: Duration = Duration::from_secs(60);
+
+/// Attempts to resume the session stored in the `TokenCache` by immediately
+/// refreshing the cached token. Returns `None` when there is nothing cached or
+/// the refresh is rejected, in which case the interactive `flow` is required.
+#[tracing::instrument]
+pub async fn restore(
+    config: &OAuthConfiguration,
+    client: &DurableClient,
+    cache: &TokenCache,
+) -> Option<SharedAccessToken> {
+    let token = SharedAccessToken::new(cache.load()?);
+    match SharedAccessToken::do_refresh(client.clone(), &token, config, Some(cache)).await {
+        Ok(()) => {
+            tracing::info!("Restored the OAuth session from the token cache.");
+            Some(token)
+        }
+        Err(err) => {
+            tracing::warn!("Could not refresh the cached token: {:?}", err);
+            None
+        }
+    }
+}
 
 #[tracing::instrument]
 pub async fn flow(
     config: OAuthConfiguration,
     client: &DurableClient,
-    shutdown_after_secs: u64,
-) -> Result<SharedAccessToken, Box<dyn std::error::Error>> {
+    wait_for_secs: u64,
+) -> Result<SharedAccessToken> {
+    config.start_flow();
     let auth_url = &config.get_authorize_url();
     if cfg!(unix) {
         // webbrowser doesn't seem to work on WSL.
@@ -28,65 +73,210 @@ pub async fn flow(
         let browser = env::var("BROWSER").unwrap();
         tracing::info!("BROWSER: {}", browser);
         Command::new(browser)
-            .arg(&auth_url)
+            .arg(auth_url)
             .spawn()
             .expect("Could not open browser");
     } else {
         webbrowser::open(auth_url).expect("Could not open browser");
     }
 
-    run_server(&config, shutdown_after_secs).await?;
+    run_server(&config, wait_for_secs).await?;
     tracing::info!("The OAuth server has terminated. Fetching token.");
 
     let token_url = config.get_token_url();
-    let body = config.to_token_request_body();
-    let token = client
-        .post(token_url)
-        .form(&body)
-        .send()
+    let body = config.to_token_request_body()?;
+    let token = post_token_request(client, &token_url, &body)
         .await?
-        .json::<AccessToken>()
-        .await?;
+        .into_result()?;
 
     tracing::info!("T: {:#?}", token);
     Ok(SharedAccessToken::new(token))
 }
 
+/// Starts the OAuth 2.0 device authorization grant. The returned `DeviceCode`
+/// holds the user code and verification URL that must be shown to the user
+/// before calling `poll_device_code`.
 #[tracing::instrument]
-async fn run_server(
+pub async fn request_device_code(
     config: &OAuthConfiguration,
-    shutdown_after_secs: u64,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let refresh_time = shutdown_after_secs * 1000 + 300;
+    client: &DurableClient,
+) -> Result<DeviceCode> {
+    let device_code_url = config.get_device_code_url();
+    let body = config.to_device_code_request_body();
+    let device_code = client
+        .post(device_code_url)
+        .form(&body)
+        .send()
+        .await
+        .map_err(Error::AuthTransport)?
+        .json::<DeviceCode>()
+        .await
+        .map_err(|err| Error::AuthTransport(err.into()))?;
+    tracing::info!("Device code: {:#?}", device_code);
+    Ok(device_code)
+}
+
+/// Polls the token endpoint at the interval requested by the identity platform
+/// until the user completes the device code sign in, declines it, or the code
+/// expires.
+#[tracing::instrument]
+pub async fn poll_device_code(
+    config: &OAuthConfiguration,
+    client: &DurableClient,
+    device_code: &DeviceCode,
+) -> Result<SharedAccessToken> {
+    let token_url = config.get_token_url();
+    let body = config.to_device_token_request_body(&device_code.device_code)?;
+    let mut interval = device_code.interval;
+    let expires_at = tokio::time::Instant::now() + Duration::from_secs(device_code.expires_in);
+
+    loop {
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+        if tokio::time::Instant::now() > expires_at {
+            return Err(Error::Auth(
+                "The device code expired before sign in completed".into(),
+            ));
+        }
+
+        match post_token_request(client, &token_url, &body).await? {
+            TokenResponse::Token(token) => {
+                tracing::info!("T: {:#?}", token);
+                return Ok(SharedAccessToken::new(token));
+            }
+            TokenResponse::Error(err) => match err.error.as_str() {
+                "authorization_pending" => {
+                    tracing::debug!("Waiting for the user to complete the device code sign in");
+                }
+                "slow_down" => {
+                    interval += 5;
+                    tracing::info!("Asked to slow down. Polling every {} seconds", interval);
+                }
+                _ => return Err(err.into()),
+            },
+        }
+    }
+}
+
+/// Posts a form to the token endpoint. OAuth error payloads are returned as
+/// `TokenResponse::Error` so callers can react to codes like
+/// `authorization_pending`.
+async fn post_token_request<T: serde::Serialize + fmt::Debug>(
+    client: &DurableClient,
+    token_url: &str,
+    body: &T,
+) -> Result<TokenResponse> {
+    client
+        .post(token_url)
+        .form(body)
+        .send()
+        .await
+        .map_err(Error::AuthTransport)?
+        .json::<TokenResponse>()
+        .await
+        .map_err(|err| Error::AuthTransport(err.into()))
+}
 
+/// Serves the OAuth redirect until the identity platform sends the user back
+/// with either an access code or an error, or until `wait_for_secs` elapses.
+#[tracing::instrument]
+async fn run_server(config: &OAuthConfiguration, wait_for_secs: u64) -> Result<()> {
+    let (redirect_tx, mut redirect_rx) = mpsc::channel::<RedirectOutcome>(1);
     let app = Router::new()
         .route("/redirect", get(handle_oauth_redirect))
         .layer(
             ServiceBuilder::new()
                 .layer(Extension(config.clone()))
-                .layer(Extension(refresh_time))
+                .layer(Extension(redirect_tx))
                 .into_inner(),
         );
 
     let port = config.get_port();
     let addr = SocketAddr::from(([127, 0, 0, 1], port));
 
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .with_graceful_shutdown(shutdown_timer(shutdown_after_secs))
-        .await?;
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let server = tokio::spawn(
+        axum::Server::bind(&addr)
+            .serve(app.into_make_service())
+            .with_graceful_shutdown(async {
+                shutdown_rx.await.ok();
+                tracing::info!("Shutdown signal fired!");
+            }),
+    );
+
+    tracing::debug!(
+        "Waiting up to {} seconds for the redirect...",
+        wait_for_secs
+    );
+    let outcome =
+        tokio::time::timeout(Duration::from_secs(wait_for_secs), redirect_rx.recv()).await;
+    shutdown_tx.send(()).ok();
+    server
+        .await
+        .map_err(|err| Error::Auth(format!("The OAuth redirect server panicked: {}", err)))?
+        .map_err(|err| Error::Auth(format!("The OAuth redirect server failed: {}", err)))?;
 
-    Ok(())
+    match outcome {
+        Ok(Some(Ok(()))) => Ok(()),
+        Ok(Some(Err(err))) => Err(Error::Auth(format!("Sign in failed: {}", err))),
+        Ok(None) => Err(Error::Auth(
+            "The OAuth redirect server stopped unexpectedly".into(),
+        )),
+        Err(_) => Err(Error::Auth(format!(
+            "Timed out after {} seconds waiting for sign in. Try a larger --auth-wait-for",
+            wait_for_secs
+        ))),
+    }
 }
 
 #[tracing::instrument]
 async fn handle_oauth_redirect(
     config: Extension<OAuthConfiguration>,
-    Extension(refresh_after): Extension<u64>,
-    Query(access): Query<AccessCode>,
+    Extension(redirect_tx): Extension<mpsc::Sender<RedirectOutcome>>,
+    Query(redirect): Query<RedirectParams>,
 ) -> impl IntoResponse {
-    tracing::debug!("Access code received at server with value {:#?}", access);
-    config.set_access_code(&access.code);
+    tracing::debug!("Redirect received at server with value {:#?}", redirect);
+    if !config.is_expected_state(&redirect.state) {
+        tracing::warn!("Rejecting a redirect with an unexpected state parameter");
+        return (
+            StatusCode::BAD_REQUEST,
+            render_redirect_page(
+                "Access Code Rejected",
+                "The sign in response did not match the request sent by pico-client. Please try again.",
+            ),
+        );
+    }
+
+    match (redirect.code, redirect.error) {
+        (Some(code), _) => {
+            config.set_access_code(&code);
+            redirect_tx.send(Ok(())).await.ok();
+            (
+                StatusCode::OK,
+                render_redirect_page(
+                    "Access Code",
+                    "Access code recieved! You can close this window.",
+                ),
+            )
+        }
+        (None, Some(error)) => {
+            let message = format!("{}: {}", error, redirect.error_description);
+            redirect_tx.send(Err(message.clone())).await.ok();
+            (
+                StatusCode::BAD_REQUEST,
+                render_redirect_page("Sign In Failed", &message),
+            )
+        }
+        (None, None) => (
+            StatusCode::BAD_REQUEST,
+            render_redirect_page(
+                "Access Code Missing",
+                "The redirect did not include an access code.",
+            ),
+        ),
+    }
+}
+
+fn render_redirect_page(heading: &str, message: &str) -> Html<String> {
     Html(format!(
         r#"
         <!DOCTYPE html>
@@ -98,24 +288,23 @@ async fn handle_oauth_redirect(
             <title>Warp OAuth</title>
           </head>
           <body>
-            <h1>Access Code</h1>
-            <p>Access code recieved!</p>
-            <code>{}</code>
-            <script>
-              setTimeout(() => window.location.reload(), {});
-            </script>
+            <h1>{}</h1>
+            <p>{}</p>
           </body>
         </html>
         "#,
-        access.code, refresh_after
+        escape_html(heading),
+        escape_html(message)
     ))
 }
 
-#[tracing::instrument]
-async fn shutdown_timer(wait_for_secs: u64) {
-    tracing::debug!("Waiting for {} seconds to send shutdown...", wait_for_secs);
-    tokio::time::sleep(Duration::from_secs(wait_for_secs)).await;
-    tracing::info!("Shutdown signal fired!");
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
 }
 
 #[derive(Clone, Debug)]
@@ -130,6 +319,37 @@ impl OAuthConfiguration {
         }
     }
 
+    /// Authenticates every token request as a confidential client
+    pub fn with_client_credential(self, credential: Option<ClientCredential>) -> Self {
+        {
+            let mut config = self.data.lock().unwrap();
+            config.client_credential = credential;
+        }
+        self
+    }
+
+    /// Sends the sign in to another identity platform, e.g. a fake one in tests
+    #[cfg(test)]
+    fn with_authority(self, authority: &str) -> Self {
+        {
+            let mut config = self.data.lock().unwrap();
+            config.authority = authority.into();
+        }
+        self
+    }
+
+    /// Generates a fresh PKCE verifier and `state` for a new authorization code
+    /// flow. Any previously received access code is discarded.
+    pub fn start_flow(&self) {
+        let mut config = self.data.lock().unwrap();
+        config.start_flow();
+    }
+
+    fn is_expected_state(&self, state: &str) -> bool {
+        let config = self.data.lock().unwrap();
+        config.state == state
+    }
+
     pub fn set_access_code(&self, ac: &str) {
         let mut config = self.data.lock().unwrap();
         config.set_access_code(ac);
@@ -150,60 +370,132 @@ impl OAuthConfiguration {
         config.get_port()
     }
 
-    fn to_token_request_body(&self) -> AccessTokenRequestBody {
+    fn to_token_request_body(&self) -> Result<AccessTokenRequestBody> {
         let config = self.data.lock().unwrap();
         config.to_token_request_body()
     }
 
-    fn to_token_refresh_body(&self, refresh_token: &str) -> RefreshTokenRequestBody {
+    fn get_device_code_url(&self) -> String {
+        let config = self.data.lock().unwrap();
+        config.get_device_code_url()
+    }
+
+    fn to_device_code_request_body(&self) -> DeviceCodeRequestBody {
+        let config = self.data.lock().unwrap();
+        DeviceCodeRequestBody {
+            client_id: config.get_client_id(),
+            scope: config.get_scope(),
+        }
+    }
+
+    fn to_device_token_request_body(&self, device_code: &str) -> Result<DeviceTokenRequestBody> {
+        let config = self.data.lock().unwrap();
+        Ok(DeviceTokenRequestBody {
+            client_id: config.get_client_id(),
+            grant_type: "urn:ietf:params:oauth:grant-type:device_code".into(),
+            device_code: device_code.into(),
+            client_auth: config.to_client_auth_params()?,
+        })
+    }
+
+    fn to_token_refresh_body(&self, refresh_token: &str) -> Result<RefreshTokenRequestBody> {
         let config = self.data.lock().unwrap();
-        RefreshTokenRequestBody {
+        Ok(RefreshTokenRequestBody {
             client_id: config.get_client_id(),
             grant_type: "refresh_token".into(),
             scope: config.get_scope(),
             refresh_token: refresh_token.into(),
-        }
+            client_auth: config.to_client_auth_params()?,
+        })
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 struct Config {
+    pub authority: String,
     pub client_id: String,
     pub tenant_id: String,
     pub port: u16,
     pub scope: String,
     pub access_code: Option<String>,
+    pub state: String,
+    pub code_verifier: String,
+    pub client_credential: Option<ClientCredential>,
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("authority", &self.authority)
+            .field("client_id", &self.client_id)
+            .field("tenant_id", &self.tenant_id)
+            .field("port", &self.port)
+            .field("scope", &self.scope)
+            .field(
+                "access_code",
+                &self.access_code.as_ref().map(|_| "[REDACTED]"),
+            )
+            .field("state", &self.state)
+            .field("code_verifier", &"[REDACTED]")
+            .field("client_credential", &self.client_credential)
+            .finish()
+    }
 }
 
 impl Config {
     pub fn new(client_id: &str, tenant_id: &str, scope: &str) -> Self {
         Config {
+            authority: DEFAULT_AUTHORITY.into(),
             client_id: client_id.into(),
             tenant_id: tenant_id.into(),
             port: 42069,
             access_code: None,
             scope: scope.into(),
+            state: random_url_safe(16),
+            code_verifier: random_url_safe(32),
+            client_credential: None,
         }
     }
 
-    /// Panics! Panics when invoked while the `access_code` property is the
-    /// `None` variant
-    fn to_token_request_body(&self) -> AccessTokenRequestBody {
+    fn start_flow(&mut self) {
+        self.access_code = None;
+        self.state = random_url_safe(16);
+        self.code_verifier = random_url_safe(32);
+    }
+
+    /// The S256 PKCE challenge for the current `code_verifier`
+    fn get_code_challenge(&self) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(self.code_verifier.as_bytes()))
+    }
+
+    /// Errors when invoked while the `access_code` property is the `None`
+    /// variant
+    fn to_token_request_body(&self) -> Result<AccessTokenRequestBody> {
         let code = match &self.access_code {
             Some(ac) => ac,
-            None => panic!("No Access Code available."),
+            None => return Err(Error::Auth("No Access Code available.".into())),
         };
-        AccessTokenRequestBody {
+        Ok(AccessTokenRequestBody {
             code: code.into(),
             client_id: self.client_id.clone(),
             redirect_uri: self.get_redirect_uri(),
             grant_type: String::from("authorization_code"),
+            code_verifier: self.code_verifier.clone(),
+            client_auth: self.to_client_auth_params()?,
+        })
+    }
+
+    fn to_client_auth_params(&self) -> Result<ClientAuthParams> {
+        match &self.client_credential {
+            Some(credential) => credential.to_params(&self.client_id, &self.get_token_url()),
+            None => Ok(ClientAuthParams::default()),
         }
     }
 
     fn get_authorize_url(&self) -> String {
         format!(
-            "https://login.microsoftonline.com/{}/oauth2/v2.0/authorize?{}",
+            "{}/{}/oauth2/v2.0/authorize?{}",
+            self.authority,
             self.tenant_id,
             self.get_authorize_query()
         )
@@ -211,17 +503,23 @@ impl Config {
 
     fn get_authorize_query(&self) -> String {
         format!(
-            "response_type=code&client_id={}&redirect_uri={}&scope={}&sso_reload=true",
+            "response_type=code&client_id={}&redirect_uri={}&scope={}&state={}&code_challenge={}&code_challenge_method=S256&sso_reload=true",
             self.client_id,
             self.get_redirect_uri(),
-            self.scope
+            self.scope,
+            self.state,
+            self.get_code_challenge()
         )
     }
 
     fn get_token_url(&self) -> String {
+        format!("{}/{}/oauth2/v2.0/token", self.authority, self.tenant_id)
+    }
+
+    fn get_device_code_url(&self) -> String {
         format!(
-            "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
-            self.tenant_id
+            "{}/{}/oauth2/v2.0/devicecode",
+            self.authority, self.tenant_id
         )
     }
 
@@ -269,40 +567,156 @@ impl fmt::Debug for AccessToken {
     }
 }
 
+/// Whether the shared token can currently be used to call Graph
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenHealth {
+    /// The token is fresh, or at least has not expired yet
+    Valid,
+    /// The token expired while refreshes kept failing. They are still retried.
+    Expired,
+    /// The refresh token was rejected. Only a new interactive sign in helps.
+    SignInRequired,
+}
+
+#[derive(Debug)]
+struct TokenState {
+    token: AccessToken,
+    expires_at: DateTime<Utc>,
+    health: TokenHealth,
+    /// Refresh without waiting for the expiry, e.g. because Graph rejected
+    /// the token
+    refresh_requested: bool,
+}
+
+impl TokenState {
+    fn new(token: AccessToken) -> Self {
+        TokenState {
+            expires_at: Utc::now() + chrono::Duration::seconds(token.expires_in as i64),
+            token,
+            health: TokenHealth::Valid,
+            refresh_requested: false,
+        }
+    }
+}
+
+/// What the `autorefresh` task does next
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RefreshStep {
+    /// The refresh token was rejected, so only `replace` helps
+    AwaitSignIn,
+    /// Sleep for `nap`, then look again. The naps are short, so a suspended
+    /// host still wakes up in time for the refresh `due`.
+    Sleep {
+        due: DateTime<Utc>,
+        nap: Duration,
+    },
+    Refresh,
+}
+
+impl TokenState {
+    /// Schedules the refresh `pad` before the absolute expiry, so the time
+    /// spent asleep doesn't push it back
+    fn next_step(&self, pad: chrono::Duration, now: DateTime<Utc>) -> RefreshStep {
+        if self.health == TokenHealth::SignInRequired {
+            return RefreshStep::AwaitSignIn;
+        }
+        let due = self.expires_at - pad;
+        match (due - now).to_std() {
+            Ok(wait) if !self.refresh_requested && !wait.is_zero() => RefreshStep::Sleep {
+                due,
+                nap: wait.min(REFRESH_MAX_NAP),
+            },
+            _ => RefreshStep::Refresh,
+        }
+    }
+
+    /// A rejected refresh token needs a new sign in. Any other failure is
+    /// retried, and only counts once the token really expired.
+    fn refresh_failed(&mut self, err: &Error, now: DateTime<Utc>) {
+        if err.is_invalid_grant() {
+            self.health = TokenHealth::SignInRequired;
+        } else if now >= self.expires_at {
+            self.health = TokenHealth::Expired;
+        }
+    }
+}
+
+/// Doubles the wait after another failed attempt, up to `max`
+pub fn next_backoff(backoff: Duration, max: Duration) -> Duration {
+    (backoff * 2).min(max)
+}
+
 #[derive(Clone, Debug)]
 pub struct SharedAccessToken {
-    data: Arc<Mutex<AccessToken>>,
+    data: Arc<Mutex<TokenState>>,
+    wake: Arc<Notify>,
 }
 
 impl SharedAccessToken {
-    fn new(token: AccessToken) -> Self {
+    pub fn new(token: AccessToken) -> Self {
         SharedAccessToken {
-            data: Arc::new(Mutex::new(token)),
+            data: Arc::new(Mutex::new(TokenState::new(token))),
+            wake: Arc::new(Notify::new()),
         }
     }
 
+    /// Keeps the token fresh in the background. Refreshes are scheduled
+    /// `pad_secs` before the absolute expiry and retried with backoff when they
+    /// fail. A rejected refresh token flips the health to
+    /// `TokenHealth::SignInRequired` and pauses until `replace` is called.
     #[tracing::instrument]
     pub fn autorefresh(
         token: SharedAccessToken,
         client: DurableClient,
         config: OAuthConfiguration,
         pad_secs: u64,
+        cache: Option<TokenCache>,
     ) {
+        let pad = chrono::Duration::seconds(pad_secs as i64);
         tokio::spawn(async move {
+            let mut backoff = REFRESH_BACKOFF_MIN;
             loop {
-                let wait_time = token.get_expires_in() - pad_secs;
-                tracing::info!(
-                    "{} - {} = {}. Fresh sleeping {} seconds...",
-                    token.get_expires_in(),
-                    pad_secs,
-                    wait_time,
-                    wait_time
-                );
-                tokio::time::sleep(Duration::from_secs(wait_time)).await;
-                tracing::info!("oauth::auto_refresh awake. Refreshing token!");
-                Self::do_refresh(client.clone(), &token, &config)
-                    .await
-                    .expect("Could not refresh token!");
+                let step = token.data.lock().unwrap().next_step(pad, Utc::now());
+                match step {
+                    RefreshStep::AwaitSignIn => {
+                        tracing::info!("oauth::auto_refresh paused until the next sign in.");
+                        token.wake.notified().await;
+                        backoff = REFRESH_BACKOFF_MIN;
+                    }
+                    RefreshStep::Sleep { due, nap } => {
+                        tracing::info!(
+                            "Token refresh due at {}. Fresh sleeping {} seconds...",
+                            due,
+                            nap.as_secs()
+                        );
+                        tokio::select! {
+                            _ = tokio::time::sleep(nap) => {},
+                            _ = token.wake.notified() => {},
+                        }
+                    }
+                    RefreshStep::Refresh => {
+                        tracing::info!("oauth::auto_refresh awake. Refreshing token!");
+                        let refresh =
+                            Self::do_refresh(client.clone(), &token, &config, cache.as_ref());
+                        match refresh.await {
+                            Ok(()) => backoff = REFRESH_BACKOFF_MIN,
+                            Err(err) if err.is_invalid_grant() => {
+                                tracing::error!("The refresh token was rejected: {}", err);
+                                token.data.lock().unwrap().refresh_failed(&err, Utc::now());
+                            }
+                            Err(err) => {
+                                token.data.lock().unwrap().refresh_failed(&err, Utc::now());
+                                tracing::warn!(
+                                    "Could not refresh token: {}. Retrying in {} seconds...",
+                                    err,
+                                    backoff.as_secs()
+                                );
+                                tokio::time::sleep(backoff).await;
+                                backoff = next_backoff(backoff, REFRESH_BACKOFF_MAX);
+                            }
+                        }
+                    }
+                }
             }
         });
     }
@@ -312,55 +726,82 @@ impl SharedAccessToken {
         client: DurableClient,
         token: &SharedAccessToken,
         config: &OAuthConfiguration,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+        cache: Option<&TokenCache>,
+    ) -> Result<()> {
         let refresh_url = config.get_token_url();
-        let body = config.to_token_refresh_body(&token.get_refresh_token());
+        let body = config.to_token_refresh_body(&token.get_refresh_token())?;
         tracing::debug!("Refresh token request body: {:#?}", body);
-        let res = client
-            .post(refresh_url)
-            .form(&body)
-            .send()
+        let res = post_token_request(&client, &refresh_url, &body)
             .await?
-            .json::<AccessToken>()
-            .await?;
+            .into_result()?;
         tracing::info!("Refresh response: {:#?}", res);
         token.apply_refresh(res);
+        // The refresh succeeded either way, and the rotated refresh token is
+        // still held in memory
+        if let Some(Err(err)) = cache.map(|cache| cache.store(&token.snapshot())) {
+            tracing::warn!("Could not cache the refreshed token: {}", err);
+        }
         Ok(())
     }
 
     #[tracing::instrument]
     fn apply_refresh(&self, payload: AccessToken) {
         tracing::debug!("Applying token refresh payload {:#?}", payload);
-        let mut token = self.data.lock().unwrap();
-        token.access_token = payload.access_token;
-        token.expires_in = payload.expires_in;
-        token.ext_expires_in = payload.ext_expires_in;
-        token.refresh_token = payload.refresh_token;
-        token.scope = payload.scope;
-        tracing::debug!("New token: {:#?}", token);
+        let mut state = self.data.lock().unwrap();
+        *state = TokenState::new(payload);
+        tracing::debug!("New token: {:#?}", state.token);
     }
 
-    fn get_expires_in(&self) -> u64 {
-        let token = self.data.lock().unwrap();
-        token.expires_in
+    /// Swaps in the token from a fresh interactive sign in and resumes the
+    /// `autorefresh` task if it was waiting for one.
+    #[tracing::instrument]
+    pub fn replace(&self, other: &SharedAccessToken) {
+        self.apply_refresh(other.snapshot());
+        self.wake.notify_one();
+    }
+
+    /// Wakes the `autorefresh` task so it refreshes right away, e.g. after
+    /// Graph answered `401 Unauthorized`. The token keeps its expiry, so a
+    /// failed refresh doesn't count it as expired early.
+    #[tracing::instrument]
+    pub fn force_refresh(&self) {
+        {
+            let mut state = self.data.lock().unwrap();
+            state.refresh_requested = true;
+        }
+        self.wake.notify_one();
+    }
+
+    pub fn snapshot(&self) -> AccessToken {
+        let state = self.data.lock().unwrap();
+        state.token.clone()
+    }
+
+    pub fn health(&self) -> TokenHealth {
+        let state = self.data.lock().unwrap();
+        state.health
     }
 
     fn get_refresh_token(&self) -> String {
-        let token = self.data.lock().unwrap();
-        token.refresh_token.clone()
+        let state = self.data.lock().unwrap();
+        state.token.refresh_token.clone()
     }
 
     pub fn get_access_token(&self) -> String {
-        let token = self.data.lock().unwrap();
-        token.access_token.clone()
+        let state = self.data.lock().unwrap();
+        state.token.access_token.clone()
     }
 }
+
 #[derive(Debug, Deserialize, Serialize)]
 struct AccessTokenRequestBody {
     client_id: String,
     redirect_uri: String,
     code: String,
     grant_type: String,
+    code_verifier: String,
+    #[serde(flatten)]
+    client_auth: ClientAuthParams,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -369,9 +810,461 @@ struct RefreshTokenRequestBody {
     grant_type: String,
     scope: String,
     refresh_token: String,
+    #[serde(flatten)]
+    client_auth: ClientAuthParams,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
-struct AccessCode {
-    pub code: String,
+struct DeviceCodeRequestBody {
+    client_id: String,
+    scope: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct DeviceTokenRequestBody {
+    client_id: String,
+    grant_type: String,
+    device_code: String,
+    #[serde(flatten)]
+    client_auth: ClientAuthParams,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct DeviceCode {
+    device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    expires_in: u64,
+    interval: u64,
+    pub message: String,
+}
+
+impl fmt::Debug for DeviceCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceCode")
+            .field("device_code", &"[REDACTED]")
+            .field("user_code", &self.user_code)
+            .field("verification_uri", &self.verification_uri)
+            .field("expires_in", &self.expires_in)
+            .field("interval", &self.interval)
+            .finish()
+    }
+}
+
+/// The token endpoint answers with either a token or an OAuth error payload
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum TokenResponse {
+    Token(AccessToken),
+    Error(TokenError),
+}
+
+impl TokenResponse {
+    fn into_result(self) -> Result<AccessToken> {
+        match self {
+            TokenResponse::Token(token) => Ok(token),
+            TokenResponse::Error(err) => Err(err.into()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenError {
+    pub error: String,
+    #[serde(default)]
+    pub error_description: String,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.error, self.error_description)
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+/// The query parameters the identity platform sends to the redirect URI. On
+/// success `code` is set, otherwise `error` and `error_description` are.
+#[derive(Deserialize, Serialize)]
+struct RedirectParams {
+    pub code: Option<String>,
+    #[serde(default)]
+    pub state: String,
+    pub error: Option<String>,
+    #[serde(default)]
+    pub error_description: String,
+}
+
+impl fmt::Debug for RedirectParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedirectParams")
+            .field("code", &self.code.as_ref().map(|_| "[REDACTED]"))
+            .field("state", &self.state)
+            .field("error", &self.error)
+            .field("error_description", &self.error_description)
+            .finish()
+    }
+}
+
+/// Encodes `len` random bytes as unpadded base64url, which is valid for both
+/// the PKCE verifier (43 to 128 characters from 32 bytes) and `state`
+fn random_url_safe(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// A token that stays valid for an hour, for tests against fake APIs
+#[cfg(test)]
+pub fn test_token() -> SharedAccessToken {
+    let token = serde_json::from_value(serde_json::json!({
+        "token_type": "Bearer",
+        "scope": "Presence.Read Calendars.Read",
+        "expires_in": 3600,
+        "ext_expires_in": 3600,
+        "access_token": "access",
+        "refresh_token": "refresh",
+    }))
+    .unwrap();
+    SharedAccessToken::new(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::{extract::Form, routing::post, Json};
+
+    use super::*;
+    use crate::test_support::{free_addr, spawn_server};
+
+    #[test]
+    fn test_code_challenge_is_s256_of_verifier() {
+        let mut config = Config::new("client", "common", "Presence.Read");
+        config.code_verifier = String::from("dBjftJeZ4CVP-mJ92MwGmo2ZQ6cZjCm8X9pC6gUyFag");
+        assert_eq!(
+            config.get_code_challenge(),
+            "bMokKhKKc0zM0AgdXVT7tTPi-hAZKUyGMphCBlm1B0g"
+        );
+    }
+
+    #[test]
+    fn test_client_secret_is_sent_and_redacted() {
+        let config = OAuthConfiguration::new("client", "common", "Presence.Read")
+            .with_client_credential(Some(ClientCredential::Secret("hunter2".parse().unwrap())));
+        let body = config.to_token_refresh_body("refresh").unwrap();
+
+        let form = serde_urlencoded::to_string(&body).unwrap();
+        assert!(form.contains("client_secret=hunter2"));
+        assert!(!form.contains("client_assertion"));
+        assert!(!format!("{:?}", config).contains("hunter2"));
+        assert!(!format!("{:?}", body).contains("hunter2"));
+    }
+
+    #[test]
+    fn test_force_refresh_keeps_the_expiry() {
+        let token = test_token();
+        let expires_at = token.data.lock().unwrap().expires_at;
+        let pad = chrono::Duration::seconds(120);
+
+        token.force_refresh();
+        let state = token.data.lock().unwrap();
+        assert_eq!(state.next_step(pad, Utc::now()), RefreshStep::Refresh);
+        assert_eq!(state.expires_at, expires_at);
+        assert_eq!(state.health, TokenHealth::Valid);
+        drop(state);
+
+        // A successful refresh is a fresh token, with nothing left to force
+        token.apply_refresh(token.snapshot());
+        let step = token.data.lock().unwrap().next_step(pad, Utc::now());
+        assert!(matches!(step, RefreshStep::Sleep { .. }));
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_max() {
+        let backoffs: Vec<u64> = std::iter::successors(Some(REFRESH_BACKOFF_MIN), |backoff| {
+            Some(next_backoff(*backoff, REFRESH_BACKOFF_MAX))
+        })
+        .take(9)
+        .map(|backoff| backoff.as_secs())
+        .collect();
+        assert_eq!(backoffs, vec![5, 10, 20, 40, 80, 160, 300, 300, 300]);
+    }
+
+    #[test]
+    fn test_refresh_is_scheduled_from_the_expiry() {
+        let token = test_token();
+        let mut state = token.data.lock().unwrap();
+        let pad = chrono::Duration::seconds(120);
+        let due = state.expires_at - pad;
+
+        let now = Utc::now();
+        assert_eq!(
+            state.next_step(pad, now),
+            RefreshStep::Sleep {
+                due,
+                nap: REFRESH_MAX_NAP
+            }
+        );
+        // However long the naps took, the refresh stays due at the same time
+        assert_eq!(
+            state.next_step(pad, due - chrono::Duration::seconds(30)),
+            RefreshStep::Sleep {
+                due,
+                nap: Duration::from_secs(30)
+            }
+        );
+        assert_eq!(state.next_step(pad, due), RefreshStep::Refresh);
+        assert_eq!(
+            state.next_step(pad, state.expires_at + chrono::Duration::hours(8)),
+            RefreshStep::Refresh
+        );
+
+        state.refresh_requested = true;
+        assert_eq!(state.next_step(pad, now), RefreshStep::Refresh);
+    }
+
+    #[test]
+    fn test_failed_refreshes_update_the_health() {
+        let token = test_token();
+        let mut state = token.data.lock().unwrap();
+        let transient = Error::Auth("timed out".into());
+
+        state.refresh_failed(&transient, Utc::now());
+        assert_eq!(state.health, TokenHealth::Valid);
+        let expires_at = state.expires_at;
+        state.refresh_failed(&transient, expires_at);
+        assert_eq!(state.health, TokenHealth::Expired);
+
+        let rejected = Error::Token(TokenError {
+            error: "invalid_grant".into(),
+            error_description: "AADSTS70008: The refresh token has expired".into(),
+        });
+        state.refresh_failed(&rejected, Utc::now());
+        assert_eq!(state.health, TokenHealth::SignInRequired);
+        assert_eq!(
+            state.next_step(chrono::Duration::zero(), Utc::now()),
+            RefreshStep::AwaitSignIn
+        );
+    }
+
+    async fn fake_refresh() -> Json<serde_json::Value> {
+        Json(serde_json::json!({
+            "token_type": "Bearer",
+            "scope": "Presence.Read",
+            "expires_in": 3600,
+            "ext_expires_in": 3600,
+            "access_token": "refreshed-access",
+            "refresh_token": "rotated-refresh",
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_refresh_survives_an_unwritable_cache() {
+        let app = Router::new().route("/common/oauth2/v2.0/token", post(fake_refresh));
+        let authority = format!("http://{}", spawn_server(app));
+        let config =
+            OAuthConfiguration::new("client", "common", "Presence.Read").with_authority(&authority);
+        // A file where the cache directory should be
+        let blocker =
+            std::env::temp_dir().join(format!("pico-client-blocker-{}", std::process::id()));
+        std::fs::write(&blocker, b"").unwrap();
+        let cache = TokenCache::new(&blocker.join("cache"), "client", "common");
+
+        let token = test_token();
+        SharedAccessToken::do_refresh(
+            crate::http::build_durable_client(),
+            &token,
+            &config,
+            Some(&cache),
+        )
+        .await
+        .unwrap();
+        assert_eq!(token.get_access_token(), "refreshed-access");
+        assert_eq!(token.get_refresh_token(), "rotated-refresh");
+
+        std::fs::remove_file(blocker).unwrap();
+    }
+
+    /// The identity platform's side of the device code flow. The client ID
+    /// becomes the device code, which picks the answers to the polls.
+    async fn fake_device_code(
+        Form(body): Form<HashMap<String, String>>,
+    ) -> Json<serde_json::Value> {
+        Json(serde_json::json!({
+            "device_code": body["client_id"],
+            "user_code": "ABCD-EFGH",
+            "verification_uri": "https://microsoft.com/devicelogin",
+            "expires_in": 900,
+            "interval": 1,
+            "message": "To sign in, enter ABCD-EFGH",
+        }))
+    }
+
+    /// The device code of each poll
+    type Polls = Arc<Mutex<Vec<String>>>;
+
+    async fn fake_device_token(
+        Extension(polls): Extension<Polls>,
+        Form(body): Form<HashMap<String, String>>,
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        let mut polls = polls.lock().unwrap();
+        let scenario = &body["device_code"];
+        let answers: &[&str] = match scenario.as_str() {
+            "approved" => &["authorization_pending", "slow_down", "token"],
+            "expired" => &["authorization_pending", "expired_token"],
+            _ => &["access_denied"],
+        };
+        let count = polls.iter().filter(|code| *code == scenario).count();
+        polls.push(scenario.clone());
+        match answers[count.min(answers.len() - 1)] {
+            "token" => (
+                StatusCode::OK,
+                Json(serde_json::json!({
+                    "token_type": "Bearer",
+                    "scope": "Presence.Read",
+                    "expires_in": 3600,
+                    "ext_expires_in": 3600,
+                    "access_token": "device-access",
+                    "refresh_token": "device-refresh",
+                })),
+            ),
+            error => (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({ "error": error, "error_description": error })),
+            ),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_device_code_flow() {
+        let polls = Polls::default();
+        let app = Router::new()
+            .route("/common/oauth2/v2.0/devicecode", post(fake_device_code))
+            .route("/common/oauth2/v2.0/token", post(fake_device_token))
+            .layer(Extension(polls.clone()));
+        let authority = format!("http://{}", spawn_server(app));
+        let client = crate::http::build_durable_client();
+        let sign_in = |scenario: &str| {
+            let config = OAuthConfiguration::new(scenario, "common", "Presence.Read")
+                .with_authority(&authority);
+            let client = client.clone();
+            async move {
+                let device_code = request_device_code(&config, &client).await?;
+                assert_eq!(device_code.user_code, "ABCD-EFGH");
+                let started = tokio::time::Instant::now();
+                let token = poll_device_code(&config, &client, &device_code).await;
+                token.map(|token| (token, started.elapsed()))
+            }
+        };
+
+        // Pending, then asked to slow down from 1 to 6 seconds between polls.
+        // The paused clock also skips ahead while the requests are in flight,
+        // so that's only a lower bound.
+        let (token, took) = sign_in("approved").await.unwrap();
+        assert_eq!(token.get_access_token(), "device-access");
+        assert!(took >= Duration::from_secs(1 + 1 + 6));
+
+        let err = sign_in("expired").await.unwrap_err();
+        assert!(matches!(err, Error::Token(ref err) if err.error == "expired_token"));
+        let err = sign_in("denied").await.unwrap_err();
+        assert!(matches!(err, Error::Token(ref err) if err.error == "access_denied"));
+
+        let count = |scenario: &str| {
+            let polls = polls.lock().unwrap();
+            polls.iter().filter(|code| *code == scenario).count()
+        };
+        assert_eq!(count("approved"), 3);
+        assert_eq!(count("expired"), 2);
+        assert_eq!(count("denied"), 1);
+    }
+
+    /// A config whose redirect server listens on a free port
+    fn redirect_config() -> OAuthConfiguration {
+        let config = OAuthConfiguration::new("client", "common", "Presence.Read");
+        config.data.lock().unwrap().port = free_addr().port();
+        config
+    }
+
+    /// Follows a redirect to the `run_server` at `url`
+    async fn redirect(url: &str, query: &[(&str, &str)]) -> reqwest::Response {
+        loop {
+            // The server may not be listening yet
+            match reqwest::Client::new().get(url).query(query).send().await {
+                Ok(res) => return res,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_redirect_error_is_escaped_and_fails_the_sign_in() {
+        let config = redirect_config();
+        let server = tokio::spawn({
+            let config = config.clone();
+            async move { run_server(&config, 30).await }
+        });
+
+        let (state, url) = {
+            let config = config.data.lock().unwrap();
+            (config.state.clone(), config.get_redirect_uri())
+        };
+        let query = [
+            ("state", state.as_str()),
+            ("error", "access_denied"),
+            (
+                "error_description",
+                "<script>alert('pwned')</script> & more",
+            ),
+        ];
+        let res = redirect(&url, &query).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let page = res.text().await.unwrap();
+        assert!(page.contains(
+            "access_denied: &lt;script&gt;alert(&#39;pwned&#39;)&lt;/script&gt; &amp; more"
+        ));
+        assert!(!page.contains("<script>"));
+
+        let err = server.await.unwrap().unwrap_err();
+        assert!(matches!(err, Error::Auth(ref msg) if msg.contains("access_denied")));
+        assert!(config.to_token_request_body().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_redirect_with_a_foreign_state_is_rejected() {
+        let config = redirect_config();
+        let server = tokio::spawn({
+            let config = config.clone();
+            async move { run_server(&config, 1).await }
+        });
+
+        let url = config.data.lock().unwrap().get_redirect_uri();
+        let query = [("state", "forged"), ("code", "attacker-code")];
+        let res = redirect(&url, &query).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // The code never reached the flow, which is still waiting
+        let err = server.await.unwrap().unwrap_err();
+        assert!(matches!(err, Error::Auth(ref msg) if msg.starts_with("Timed out")));
+        assert!(config.to_token_request_body().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_redirect_times_out() {
+        let err = run_server(&redirect_config(), 0).await.unwrap_err();
+        assert!(matches!(err, Error::Auth(ref msg) if msg.starts_with("Timed out")));
+    }
+
+    #[test]
+    fn test_start_flow_rotates_state_and_verifier() {
+        let config = OAuthConfiguration::new("client", "common", "Presence.Read");
+        config.set_access_code("stale");
+        let state = config.data.lock().unwrap().state.clone();
+
+        config.start_flow();
+        let data = config.data.lock().unwrap();
+        assert_ne!(data.state, state);
+        assert!(data.access_code.is_none());
+        assert!(data.code_verifier.len() >= 43);
+    }
 }
@@ -0,0 +1,490 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::Ipv4Addr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use futures::future::join_all;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+use crate::error::{Error, Result};
+use crate::http::DurableClient;
+use crate::layout::ROWS;
+
+/// Everything but the unreserved characters. The firmware splits the query
+/// on `&`, `=` and `?`, the request line on whitespace, and only decodes
+/// `%XX`, so a `+` can't stand in for a space either.
+const LINE_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// What a Pico shows for a `Status`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ScreenLayout {
+    /// Every line: time, availability, activity and the next event
+    Full,
+    /// Only the color and a one line summary, e.g. for a door display
+    Summary,
+}
+
+/// The four screens the Pico firmware serves
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Route {
+    Green,
+    #[default]
+    Yellow,
+    Red,
+    Late,
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let route = match self {
+            Route::Green => "green",
+            Route::Yellow => "yellow",
+            Route::Red => "red",
+            Route::Late => "late",
+        };
+        f.write_str(route)
+    }
+}
+
+/// A request for the Pico firmware: the route to paint and up to seven lines
+/// of text. Lines that are `None` are left blank.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PicoScreen {
+    pub color: Route,
+    pub lines: [Option<String>; ROWS],
+}
+
+impl PicoScreen {
+    pub fn new(color: Route) -> Self {
+        PicoScreen {
+            color,
+            ..Default::default()
+        }
+    }
+
+    /// Sets line `number`, counting from 1 like the firmware does
+    pub fn with_line(mut self, number: usize, text: &str) -> Self {
+        self.lines[number - 1] = Some(text.into());
+        self
+    }
+
+    /// The path and query the firmware expects, e.g.
+    /// `green?line2=%20Available&line3=%20%28Available%29`
+    pub fn path_and_query(&self) -> String {
+        let query: Vec<String> = self
+            .lines
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, line)| {
+                line.as_ref().map(|line| {
+                    format!(
+                        "line{}={}",
+                        idx + 1,
+                        utf8_percent_encode(line, LINE_ENCODE_SET)
+                    )
+                })
+            })
+            .collect();
+        if query.is_empty() {
+            return self.color.to_string();
+        }
+        format!("{}?{}", self.color, query.join("&"))
+    }
+}
+
+/// The address that asks for the Pico to be found with `discovery::discover`
+pub const AUTO_ADDR: &str = "auto";
+
+/// A named Pico display and the layout it should be painted with
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PicoTarget {
+    pub name: String,
+    pub addr: String,
+    pub layout: ScreenLayout,
+    /// The address is found, and re-found, by discovery
+    pub discovered: bool,
+}
+
+impl PicoTarget {
+    pub fn new(name: &str, addr: &str, layout: ScreenLayout) -> Self {
+        PicoTarget {
+            name: name.into(),
+            addr: addr.into(),
+            layout,
+            discovered: addr.eq_ignore_ascii_case(AUTO_ADDR),
+        }
+    }
+
+    /// Discovered targets have no address until discovery has run
+    pub fn is_resolved(&self) -> bool {
+        !self.addr.eq_ignore_ascii_case(AUTO_ADDR)
+    }
+}
+
+/// The Pico targets, shared between the run loop (which may rediscover
+/// addresses) and the shutdown handling in `main`
+#[derive(Clone, Debug)]
+pub struct SharedPicoTargets {
+    data: Arc<Mutex<Vec<PicoTarget>>>,
+}
+
+impl SharedPicoTargets {
+    pub fn new(targets: Vec<PicoTarget>) -> Self {
+        SharedPicoTargets {
+            data: Arc::new(Mutex::new(targets)),
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<PicoTarget> {
+        let targets = self.data.lock().unwrap();
+        targets.clone()
+    }
+
+    /// The IP addresses of every target but `name`, which discovery must not
+    /// hand out to it
+    pub fn claimed_addrs(&self, name: &str) -> Vec<Ipv4Addr> {
+        let targets = self.data.lock().unwrap();
+        targets
+            .iter()
+            .filter(|target| target.name != name)
+            .filter_map(|target| target.addr.parse().ok())
+            .collect()
+    }
+
+    pub fn set_addr(&self, name: &str, addr: &str) {
+        let mut targets = self.data.lock().unwrap();
+        for target in targets.iter_mut().filter(|target| target.name == name) {
+            target.addr = addr.into();
+        }
+    }
+}
+
+impl FromStr for PicoTarget {
+    type Err = String;
+
+    /// Parses `NAME=ADDR` or `NAME=ADDR,LAYOUT`, e.g. `door=192.168.1.51,summary`
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (name, rest) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected NAME=ADDR[,LAYOUT] but got '{}'", s))?;
+        let (addr, layout) = match rest.split_once(',') {
+            Some((addr, layout)) => (addr, ScreenLayout::from_str(layout, true)?),
+            None => (rest, ScreenLayout::Full),
+        };
+        if name.is_empty() || addr.is_empty() {
+            return Err(format!("Expected NAME=ADDR[,LAYOUT] but got '{}'", s));
+        }
+        Ok(PicoTarget::new(name, addr, layout))
+    }
+}
+
+/// Pushes a screen to every target concurrently. `render` builds the screen
+/// for each target. A failing target is logged
+/// and does not hold up the others. The results are in the order of `targets`.
+#[tracing::instrument(skip(render))]
+pub async fn broadcast<F>(
+    client: &DurableClient,
+    targets: &[PicoTarget],
+    render: F,
+) -> Vec<Result<String>>
+where
+    F: Fn(&PicoTarget) -> PicoScreen,
+{
+    let pushes = targets.iter().map(|target| {
+        let url = format!("http://{}/{}", target.addr, render(target).path_and_query());
+        async move {
+            if !target.is_resolved() {
+                return Err(Error::Config(format!(
+                    "Pico {} has not been discovered yet",
+                    target.name
+                )));
+            }
+            tracing::info!("Pi URL for {}: {:#?}", target.name, url);
+            let res = get(client, &url).await;
+            match &res {
+                Ok(pires) => tracing::info!("Pi Response from {}: {:#?}", target.name, pires),
+                Err(err) => tracing::warn!("Could not update Pico {}: {}", target.name, err),
+            }
+            res
+        }
+    });
+    join_all(pushes).await
+}
+
+/// Why a screen is pushed to a target
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PushReason {
+    /// Nothing was delivered to the target yet, or it was painted with
+    /// something else since
+    Unknown,
+    Changed,
+    /// The target was rediscovered at another address
+    Moved,
+    /// The same screen, again, in case the Pico rebooted
+    Refresh,
+}
+
+impl fmt::Display for PushReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            PushReason::Unknown => "nothing is known to be on the screen",
+            PushReason::Changed => "the screen changed",
+            PushReason::Moved => "the Pico moved to another address",
+            PushReason::Refresh => "the periodic refresh is due",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// The last screen each target accepted, so an unchanged screen isn't pushed
+/// again. Every push makes the firmware repaint, which flickers, and its
+/// server only takes one connection at a time.
+#[derive(Debug)]
+pub struct DeliveredScreens {
+    /// Pushes an unchanged screen anyway after this long
+    refresh_after: Duration,
+    delivered: HashMap<String, Delivery>,
+}
+
+#[derive(Debug)]
+struct Delivery {
+    addr: String,
+    screen: PicoScreen,
+    at: Instant,
+}
+
+impl DeliveredScreens {
+    pub fn new(refresh_after: Duration) -> Self {
+        DeliveredScreens {
+            refresh_after,
+            delivered: HashMap::new(),
+        }
+    }
+
+    /// Why `screen` should be pushed to `target`, or `None` to skip it
+    pub fn push_reason(
+        &self,
+        target: &PicoTarget,
+        screen: &PicoScreen,
+        now: Instant,
+    ) -> Option<PushReason> {
+        let delivery = match self.delivered.get(&target.name) {
+            Some(delivery) => delivery,
+            None => return Some(PushReason::Unknown),
+        };
+        if delivery.addr != target.addr {
+            Some(PushReason::Moved)
+        } else if delivery.screen != *screen {
+            Some(PushReason::Changed)
+        } else if now.duration_since(delivery.at) >= self.refresh_after {
+            Some(PushReason::Refresh)
+        } else {
+            None
+        }
+    }
+
+    pub fn record(&mut self, target: &PicoTarget, screen: PicoScreen, now: Instant) {
+        self.delivered.insert(
+            target.name.clone(),
+            Delivery {
+                addr: target.addr.clone(),
+                screen,
+                at: now,
+            },
+        );
+    }
+
+    /// Forgets every delivery, e.g. after the sign in screen was painted
+    /// behind our back
+    pub fn clear(&mut self) {
+        self.delivered.clear();
+    }
+}
+
+/// Collapses the results of `broadcast` into the first failure, if any
+pub fn all_delivered(results: Vec<Result<String>>) -> Result<()> {
+    results
+        .into_iter()
+        .find(|res| res.is_err())
+        .unwrap_or(Ok(String::new()))
+        .map(|_| ())
+}
+
+async fn get(client: &DurableClient, url: &str) -> Result<String> {
+    client
+        .get(url)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status().map_err(Into::into))
+        .map_err(Error::PicoTransport)?
+        .text()
+        .await
+        .map_err(|err| Error::PicoTransport(err.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mirrors `parse_request`, `parse_text` and `unquote` of the firmware in
+    /// `server_micropython/server.py`
+    fn firmware_parse(request_line: &str) -> (String, [String; ROWS]) {
+        let url = request_line.split_whitespace().nth(1).unwrap_or_default();
+        let url_parts: Vec<&str> = url.split('?').collect();
+        let mut lines: [String; ROWS] = Default::default();
+        if url_parts.len() > 1 {
+            for el in url_parts[1].replace('?', "").split('&') {
+                let (param, val) = match el.split('=').collect::<Vec<_>>()[..] {
+                    [param, val] => (param, val),
+                    _ => continue,
+                };
+                for (idx, line) in lines.iter_mut().enumerate() {
+                    if param == format!("line{}", idx + 1) {
+                        *line = firmware_unquote(val);
+                    }
+                }
+            }
+        }
+        (url_parts[0].into(), lines)
+    }
+
+    fn firmware_unquote(value: &str) -> String {
+        let mut bits = value.split('%');
+        let mut res = bits.next().unwrap_or_default().as_bytes().to_vec();
+        for item in bits {
+            match item
+                .get(..2)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                Some(byte) => {
+                    res.push(byte);
+                    res.extend(&item.as_bytes()[2..]);
+                }
+                None => {
+                    res.push(b'%');
+                    res.extend(item.as_bytes());
+                }
+            }
+        }
+        String::from_utf8(res).unwrap()
+    }
+
+    fn round_trip(screen: &PicoScreen) -> (String, [String; ROWS]) {
+        firmware_parse(&format!("GET /{} HTTP/1.1", screen.path_and_query()))
+    }
+
+    #[test]
+    fn test_screen_round_trips_through_the_firmware() {
+        let nasty = "Q&A: 1+1=2? #fun 100% (café)";
+        let screen = PicoScreen::new(Route::Late)
+            .with_line(1, "                    08:00 am")
+            .with_line(4, nasty)
+            .with_line(6, "  08:30 am (R&D = fun)");
+        let (path, lines) = round_trip(&screen);
+        assert_eq!(path, "/late");
+        assert_eq!(lines[0], "                    08:00 am");
+        assert_eq!(lines[1], "");
+        assert_eq!(lines[3], nasty);
+        assert_eq!(lines[5], "  08:30 am (R&D = fun)");
+    }
+
+    #[test]
+    fn test_skips_unchanged_screens() {
+        let mut delivered = DeliveredScreens::new(Duration::from_secs(300));
+        let mut target = PicoTarget::new("desk", "192.168.1.50", ScreenLayout::Full);
+        let busy = PicoScreen::new(Route::Red).with_line(2, " Busy");
+        let start = Instant::now();
+
+        assert_eq!(
+            delivered.push_reason(&target, &busy, start),
+            Some(PushReason::Unknown)
+        );
+        delivered.record(&target, busy.clone(), start);
+        let soon = start + Duration::from_secs(60);
+        assert_eq!(delivered.push_reason(&target, &busy, soon), None);
+        assert_eq!(
+            delivered.push_reason(&target, &PicoScreen::new(Route::Green), soon),
+            Some(PushReason::Changed)
+        );
+        assert_eq!(
+            delivered.push_reason(&target, &busy, start + Duration::from_secs(300)),
+            Some(PushReason::Refresh)
+        );
+
+        target.addr = "192.168.1.77".into();
+        assert_eq!(
+            delivered.push_reason(&target, &busy, soon),
+            Some(PushReason::Moved)
+        );
+        delivered.clear();
+        assert_eq!(
+            delivered.push_reason(&target, &busy, soon),
+            Some(PushReason::Unknown)
+        );
+    }
+
+    #[test]
+    fn test_path_and_query() {
+        assert_eq!(PicoScreen::new(Route::Green).path_and_query(), "green");
+        assert_eq!(
+            PicoScreen::new(Route::Yellow)
+                .with_line(3, "  Good bye")
+                .path_and_query(),
+            "yellow?line3=%20%20Good%20bye"
+        );
+        let (path, lines) = round_trip(&PicoScreen::new(Route::Red).with_line(7, "a-b_c.d~e"));
+        assert_eq!(path, "/red");
+        assert_eq!(lines[6], "a-b_c.d~e");
+    }
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(
+            "door=192.168.1.51,summary".parse::<PicoTarget>().unwrap(),
+            PicoTarget::new("door", "192.168.1.51", ScreenLayout::Summary)
+        );
+        assert_eq!(
+            "desk=192.168.1.50".parse::<PicoTarget>().unwrap(),
+            PicoTarget::new("desk", "192.168.1.50", ScreenLayout::Full)
+        );
+        assert!("door=auto".parse::<PicoTarget>().unwrap().discovered);
+        assert!("192.168.1.50".parse::<PicoTarget>().is_err());
+        assert!("door=192.168.1.51,fancy".parse::<PicoTarget>().is_err());
+    }
+
+    #[test]
+    fn test_claimed_addrs() {
+        let targets = SharedPicoTargets::new(vec![
+            PicoTarget::new("desk", "auto", ScreenLayout::Full),
+            PicoTarget::new("door", "auto", ScreenLayout::Summary),
+            PicoTarget::new("hall", "192.168.1.60", ScreenLayout::Full),
+        ]);
+        assert_eq!(
+            targets.claimed_addrs("desk"),
+            vec![Ipv4Addr::new(192, 168, 1, 60)]
+        );
+
+        // Once desk is found, door has to be another Pico
+        targets.set_addr("desk", "192.168.1.50");
+        assert_eq!(
+            targets.claimed_addrs("door"),
+            vec![
+                Ipv4Addr::new(192, 168, 1, 50),
+                Ipv4Addr::new(192, 168, 1, 60)
+            ]
+        );
+        assert!(!targets
+            .claimed_addrs("desk")
+            .contains(&Ipv4Addr::new(192, 168, 1, 50)));
+    }
+}
@@ -0,0 +1,227 @@
+use std::{fs, path::Path};
+
+use chrono::NaiveTime;
+use serde::{de, Deserialize, Deserializer};
+
+use crate::error::{Error, Result};
+use crate::pico::Route;
+use crate::status::{Activity, Availability};
+
+const DEFAULT_RULES: &str = include_str!("default_rules.toml");
+
+/// What the rules know about you when they're evaluated
+#[derive(Clone, Debug)]
+pub struct Facts<'a> {
+    pub availability: Availability,
+    pub activity: Activity,
+    pub busy: bool,
+    pub late: bool,
+    /// The subject of the current or next event
+    pub subject: &'a str,
+    /// The local time of day
+    pub time: NaiveTime,
+}
+
+/// How a status is painted, as picked by the `Rules`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Rendering {
+    pub route: Route,
+    pub availability_text: String,
+    pub activity_text: String,
+}
+
+/// An ordered list of rules mapping `Facts` to a `Rendering`. A rules file is
+/// consulted before the built in rules, which keep the original mapping.
+#[derive(Clone, Debug)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rule: Vec<Rule>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    availability: Option<Vec<Availability>>,
+    activity: Option<Vec<Activity>>,
+    busy: Option<bool>,
+    late: Option<bool>,
+    subject: Option<String>,
+    #[serde(default, deserialize_with = "deser_time_of_day")]
+    after: Option<NaiveTime>,
+    #[serde(default, deserialize_with = "deser_time_of_day")]
+    before: Option<NaiveTime>,
+
+    route: Option<Route>,
+    availability_text: Option<String>,
+    activity_text: Option<String>,
+}
+
+impl Rules {
+    /// Reads a TOML rules file, falling back to the built in rules for
+    /// whatever it doesn't cover
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let toml = fs::read_to_string(path)?;
+        let mut rules =
+            parse(&toml).map_err(|err| Error::Config(format!("{:?}: {}", path, err)))?;
+        rules.extend(Rules::default().rules);
+        Ok(Rules { rules })
+    }
+
+    pub fn evaluate(&self, facts: &Facts) -> Rendering {
+        let matching = || self.rules.iter().filter(|rule| rule.matches(facts));
+        Rendering {
+            // The built in rules cover every availability, so this only
+            // falls back for an empty rule set
+            route: matching().find_map(|rule| rule.route).unwrap_or_default(),
+            availability_text: matching()
+                .find_map(|rule| rule.availability_text.clone())
+                .unwrap_or_default(),
+            activity_text: matching()
+                .find_map(|rule| rule.activity_text.clone())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Rules {
+            rules: parse(DEFAULT_RULES).expect("The built in rules are invalid"),
+        }
+    }
+}
+
+impl Rule {
+    fn matches(&self, facts: &Facts) -> bool {
+        self.availability
+            .as_ref()
+            .is_none_or(|avail| avail.contains(&facts.availability))
+            && self
+                .activity
+                .as_ref()
+                .is_none_or(|activity| activity.contains(&facts.activity))
+            && self.busy.is_none_or(|busy| busy == facts.busy)
+            && self.late.is_none_or(|late| late == facts.late)
+            && self.subject.as_ref().is_none_or(|subject| {
+                facts
+                    .subject
+                    .to_lowercase()
+                    .contains(&subject.to_lowercase())
+            })
+            && self.matches_time(facts.time)
+    }
+
+    /// `after` is inclusive and `before` exclusive. A window like 22:00 to
+    /// 06:00 wraps midnight.
+    fn matches_time(&self, time: NaiveTime) -> bool {
+        match (self.after, self.before) {
+            (Some(after), Some(before)) if after > before => time >= after || time < before,
+            (after, before) => {
+                after.is_none_or(|after| time >= after) && before.is_none_or(|before| time < before)
+            }
+        }
+    }
+}
+
+fn parse(toml: &str) -> std::result::Result<Vec<Rule>, toml::de::Error> {
+    toml::from_str::<RulesFile>(toml).map(|file| file.rule)
+}
+
+/// Accepts "17:00" as well as "17:00:30"
+fn deser_time_of_day<'de, D>(deserializer: D) -> std::result::Result<Option<NaiveTime>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value: String = Deserialize::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&value, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(&value, "%H:%M:%S"))
+        .map(Some)
+        .map_err(|_| de::Error::custom(format!("Expected a time like 17:00, got {:?}", value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn facts(availability: Availability, activity: Activity) -> Facts<'static> {
+        Facts {
+            availability,
+            activity,
+            busy: false,
+            late: false,
+            subject: "Standup",
+            time: NaiveTime::from_hms_opt(12, 0, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_default_rules_keep_the_original_mapping() {
+        let rules = Rules::default();
+        let rendering = rules.evaluate(&facts(Availability::Offline, Activity::OffWork));
+        assert_eq!(rendering.route, Route::Yellow);
+        assert_eq!(rendering.availability_text, "Offline");
+        assert_eq!(rendering.activity_text, "(Off Work!)");
+
+        let rendering = rules.evaluate(&facts(Availability::PresenceUnknown, Activity::Busy));
+        assert_eq!(rendering.availability_text, "Dono");
+
+        let mut late = facts(Availability::Away, Activity::Away);
+        late.busy = true;
+        late.late = true;
+        assert_eq!(rules.evaluate(&late).route, Route::Late);
+    }
+
+    #[test]
+    fn test_custom_rules_come_first() {
+        let custom = parse(
+            r#"
+            [[rule]]
+            availability = ["Offline"]
+            route = "red"
+            availability_text = "Gone fishing"
+
+            [[rule]]
+            subject = "standup"
+            after = "09:00"
+            before = "10:00"
+            activity_text = "(Standup!)"
+
+            [[rule]]
+            after = "22:00"
+            before = "06:00"
+            route = "green"
+            "#,
+        )
+        .unwrap();
+        let mut rules = Rules { rules: custom };
+        rules.rules.extend(Rules::default().rules);
+
+        let offline = rules.evaluate(&facts(Availability::Offline, Activity::Offline));
+        assert_eq!(offline.route, Route::Red);
+        assert_eq!(offline.availability_text, "Gone fishing");
+        assert_eq!(offline.activity_text, "(Offline)");
+
+        let mut standup = facts(Availability::Busy, Activity::InAMeeting);
+        assert_eq!(rules.evaluate(&standup).activity_text, "(In a Meeting)");
+        standup.time = NaiveTime::from_hms_opt(9, 15, 0).unwrap();
+        assert_eq!(rules.evaluate(&standup).activity_text, "(Standup!)");
+        assert_eq!(rules.evaluate(&standup).route, Route::Red);
+
+        let mut night = facts(Availability::Busy, Activity::Busy);
+        night.time = NaiveTime::from_hms_opt(23, 30, 0).unwrap();
+        assert_eq!(rules.evaluate(&night).route, Route::Green);
+    }
+
+    #[test]
+    fn test_rejects_unknown_conditions() {
+        assert!(parse("[[rule]]\nmood = \"happy\"\nroute = \"red\"").is_err());
+        assert!(parse("[[rule]]\nroute = \"blue\"").is_err());
+        assert!(parse("[[rule]]\nafter = \"noon\"").is_err());
+    }
+}
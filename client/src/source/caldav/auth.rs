@@ -0,0 +1,265 @@
+//! HTTP Basic and Digest (RFC 7616) authentication for CalDAV servers. The
+//! scheme is picked from the server's `WWW-Authenticate` challenge.
+
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use md5::Md5;
+use reqwest::{header::HeaderMap, header::WWW_AUTHENTICATE, Method, Url};
+use sha2::{Digest, Sha256};
+
+/// A CalDAV password. The value never appears in `Debug` output.
+#[derive(Clone)]
+pub struct Password(String);
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+impl FromStr for Password {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(Password(s.into()))
+    }
+}
+
+/// Answers the server's challenges, remembering the last one so later
+/// requests are authenticated up front
+#[derive(Debug)]
+pub struct Authenticator {
+    username: String,
+    password: Password,
+    challenge: Mutex<Option<Challenge>>,
+    nonce_count: AtomicU32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Challenge {
+    Basic,
+    Digest(DigestChallenge),
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct DigestChallenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    algorithm: Option<String>,
+    /// Whether the server supports `qop=auth`
+    qop_auth: bool,
+}
+
+impl Authenticator {
+    pub fn new(username: &str, password: Password) -> Self {
+        Authenticator {
+            username: username.into(),
+            password,
+            challenge: Mutex::new(None),
+            nonce_count: AtomicU32::new(0),
+        }
+    }
+
+    /// The `Authorization` header for a request, once a challenge is known
+    pub fn authorization(&self, method: &Method, url: &Url) -> Option<String> {
+        let challenge = self.challenge.lock().unwrap().clone()?;
+        Some(match challenge {
+            Challenge::Basic => format!(
+                "Basic {}",
+                STANDARD.encode(format!("{}:{}", self.username, self.password.0))
+            ),
+            Challenge::Digest(digest) => {
+                let uri = match url.query() {
+                    Some(query) => format!("{}?{}", url.path(), query),
+                    None => url.path().to_string(),
+                };
+                let nonce_count = self.nonce_count.fetch_add(1, Ordering::SeqCst) + 1;
+                let cnonce = hex::encode(rand::random::<[u8; 8]>());
+                self.digest_header(&digest, method.as_str(), &uri, nonce_count, &cnonce)
+            }
+        })
+    }
+
+    /// Learns the challenge of a 401 response. Returns whether retrying with
+    /// it could succeed, i.e. it's new or the old nonce went stale.
+    pub fn update(&self, headers: &HeaderMap) -> bool {
+        let challenges: Vec<Challenge> = headers
+            .get_all(WWW_AUTHENTICATE)
+            .iter()
+            .filter_map(|val| val.to_str().ok())
+            .filter_map(parse_challenge)
+            .collect();
+        // Digest doesn't send the password, so it's preferred
+        let challenge = challenges
+            .iter()
+            .find(|challenge| matches!(challenge, Challenge::Digest(_)))
+            .or_else(|| challenges.first())
+            .cloned();
+        let challenge = match challenge {
+            Some(challenge) => challenge,
+            None => return false,
+        };
+        let mut current = self.challenge.lock().unwrap();
+        if current.as_ref() == Some(&challenge) {
+            return false;
+        }
+        *current = Some(challenge);
+        self.nonce_count.store(0, Ordering::SeqCst);
+        true
+    }
+
+    fn digest_header(
+        &self,
+        challenge: &DigestChallenge,
+        method: &str,
+        uri: &str,
+        nonce_count: u32,
+        cnonce: &str,
+    ) -> String {
+        let algorithm = challenge.algorithm.as_deref().unwrap_or("MD5");
+        let hash = |data: String| match algorithm.to_ascii_uppercase().starts_with("SHA-256") {
+            true => hex::encode(Sha256::digest(data.as_bytes())),
+            false => hex::encode(Md5::digest(data.as_bytes())),
+        };
+        let nc = format!("{:08x}", nonce_count);
+
+        let mut ha1 = hash(format!(
+            "{}:{}:{}",
+            self.username, challenge.realm, self.password.0
+        ));
+        if algorithm.to_ascii_uppercase().ends_with("-SESS") {
+            ha1 = hash(format!("{}:{}:{}", ha1, challenge.nonce, cnonce));
+        }
+        let ha2 = hash(format!("{}:{}", method, uri));
+        let response = match challenge.qop_auth {
+            true => hash(format!(
+                "{}:{}:{}:{}:auth:{}",
+                ha1, challenge.nonce, nc, cnonce, ha2
+            )),
+            false => hash(format!("{}:{}:{}", ha1, challenge.nonce, ha2)),
+        };
+
+        let mut header = format!(
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", response=\"{}\"",
+            self.username, challenge.realm, challenge.nonce, uri, response
+        );
+        if challenge.qop_auth {
+            header.push_str(&format!(", qop=auth, nc={}, cnonce=\"{}\"", nc, cnonce));
+        }
+        if let Some(opaque) = &challenge.opaque {
+            header.push_str(&format!(", opaque=\"{}\"", opaque));
+        }
+        if let Some(algorithm) = &challenge.algorithm {
+            header.push_str(&format!(", algorithm={}", algorithm));
+        }
+        header
+    }
+}
+
+fn parse_challenge(header: &str) -> Option<Challenge> {
+    let (scheme, params) = header.trim().split_once(' ').unwrap_or((header.trim(), ""));
+    if scheme.eq_ignore_ascii_case("Basic") {
+        return Some(Challenge::Basic);
+    }
+    if !scheme.eq_ignore_ascii_case("Digest") {
+        return None;
+    }
+    let mut digest = DigestChallenge::default();
+    for (key, value) in parse_params(params) {
+        match key.to_ascii_lowercase().as_str() {
+            "realm" => digest.realm = value,
+            "nonce" => digest.nonce = value,
+            "opaque" => digest.opaque = Some(value),
+            "algorithm" => digest.algorithm = Some(value),
+            "qop" => digest.qop_auth = value.split(',').any(|qop| qop.trim() == "auth"),
+            _ => {}
+        }
+    }
+    Some(Challenge::Digest(digest))
+}
+
+/// Splits `key=value, key="quoted, value"` auth params
+fn parse_params(params: &str) -> Vec<(String, String)> {
+    let mut parsed = vec![];
+    let mut rest = params.trim();
+    while let Some((key, after)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_string();
+        let after = after.trim_start();
+        let (value, next) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                (
+                    quoted[..end].to_string(),
+                    quoted.get(end + 1..).unwrap_or(""),
+                )
+            }
+            None => {
+                let end = after.find(',').unwrap_or(after.len());
+                (after[..end].trim().to_string(), &after[end..])
+            }
+        };
+        parsed.push((key, value));
+        rest = next.trim_start().trim_start_matches(',');
+    }
+    parsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest_rfc2617_example() {
+        let auth = Authenticator::new("Mufasa", "Circle Of Life".parse().unwrap());
+        let mut headers = HeaderMap::new();
+        headers.append(
+            WWW_AUTHENTICATE,
+            "Basic realm=\"testrealm@host.com\"".parse().unwrap(),
+        );
+        headers.append(
+            WWW_AUTHENTICATE,
+            "Digest realm=\"testrealm@host.com\", qop=\"auth,auth-int\", nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", opaque=\"5ccc069c403ebaf9f0171e9517f40e41\""
+                .parse()
+                .unwrap(),
+        );
+        assert!(auth.update(&headers));
+        assert!(!auth.update(&headers));
+
+        let challenge = match auth.challenge.lock().unwrap().clone() {
+            Some(Challenge::Digest(digest)) => digest,
+            other => panic!("Expected a digest challenge, got {:?}", other),
+        };
+        let header = auth.digest_header(&challenge, "GET", "/dir/index.html", 1, "0a4f113b");
+        assert!(header.contains("response=\"6629fae49393a05397450978507c4ef1\""));
+        assert!(header.contains("nc=00000001"));
+        assert!(header.contains("opaque=\"5ccc069c403ebaf9f0171e9517f40e41\""));
+    }
+
+    #[test]
+    fn test_basic() {
+        let auth = Authenticator::new("user", "pass".parse().unwrap());
+        let url = Url::parse("http://localhost/").unwrap();
+        assert_eq!(auth.authorization(&Method::GET, &url), None);
+
+        let mut headers = HeaderMap::new();
+        headers.append(
+            WWW_AUTHENTICATE,
+            "Basic realm=\"Radicale\"".parse().unwrap(),
+        );
+        assert!(auth.update(&headers));
+        assert_eq!(
+            auth.authorization(&Method::GET, &url).unwrap(),
+            "Basic dXNlcjpwYXNz"
+        );
+        assert_eq!(format!("{:?}", auth.password), "[REDACTED]");
+    }
+}
@@ -0,0 +1,433 @@
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use reqwest::{
+    header::{CONTENT_TYPE, WWW_AUTHENTICATE},
+    Method, StatusCode, Url,
+};
+
+use crate::error::{Error, Result};
+use crate::http::DurableClient;
+use crate::source::ics::{self, Calendar, CalendarEvent};
+use crate::source::{SourceSnapshot, StatusSource};
+
+mod auth;
+
+use auth::Authenticator;
+pub use auth::Password;
+
+const DAV_NS: &str = "DAV:";
+const CALDAV_NS: &str = "urn:ietf:params:xml:ns:caldav";
+
+const PROPFIND_PRINCIPAL: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:propfind xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+  <D:prop><D:resourcetype/><D:current-user-principal/><C:calendar-home-set/></D:prop>
+</D:propfind>"#;
+
+const PROPFIND_CALENDARS: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:propfind xmlns:D="DAV:">
+  <D:prop><D:resourcetype/></D:prop>
+</D:propfind>"#;
+
+/// Events from every calendar of a CalDAV account (e.g. Nextcloud or
+/// Radicale). The calendars are discovered once with PROPFIND and then
+/// queried for the lookahead with a REPORT on every poll.
+#[derive(Debug)]
+pub struct CalDavSource {
+    client: DurableClient,
+    url: Url,
+    auth: Option<Authenticator>,
+    lookahead: Duration,
+    calendars: Mutex<Option<Vec<Url>>>,
+}
+
+impl CalDavSource {
+    /// `url` may point at the server root, a principal, the calendar home or
+    /// a single calendar
+    pub fn new(
+        client: DurableClient,
+        url: Url,
+        credentials: Option<(String, Password)>,
+        lookahead: Duration,
+    ) -> Self {
+        CalDavSource {
+            client,
+            url,
+            auth: credentials.map(|(username, password)| Authenticator::new(&username, password)),
+            lookahead,
+            calendars: Mutex::new(None),
+        }
+    }
+
+    /// Sends a WebDAV request, answering an auth challenge if the server
+    /// sends one, and returns the body of the 207 Multi-Status response
+    async fn dav_request(
+        &self,
+        method: &str,
+        url: &Url,
+        depth: &str,
+        body: &str,
+    ) -> Result<String> {
+        let method = Method::from_bytes(method.as_bytes())
+            .map_err(|err| Error::Calendar(format!("Invalid method {}: {}", method, err)))?;
+        for attempt in 0..2 {
+            let mut req = self
+                .client
+                .request(method.clone(), url.clone())
+                .header("Depth", depth)
+                .header(CONTENT_TYPE, "application/xml; charset=utf-8")
+                .body(body.to_string());
+            if let Some(authorization) = self
+                .auth
+                .as_ref()
+                .and_then(|auth| auth.authorization(&method, url))
+            {
+                req = req.header("Authorization", authorization);
+            }
+            let res = req
+                .send()
+                .await
+                .map_err(|err| Error::Calendar(format!("Could not reach {}: {}", url, err)))?;
+
+            let status = res.status();
+            if status == StatusCode::UNAUTHORIZED && attempt == 0 {
+                if let Some(auth) = &self.auth {
+                    if auth.update(res.headers()) {
+                        continue;
+                    }
+                }
+            }
+            if status != StatusCode::MULTI_STATUS {
+                let challenge = res.headers().get(WWW_AUTHENTICATE).cloned();
+                return Err(Error::Calendar(format!(
+                    "{} {} responded with {} {:?}",
+                    method, url, status, challenge
+                )));
+            }
+            return res
+                .text()
+                .await
+                .map_err(|err| Error::Calendar(format!("Could not read {}: {}", url, err)));
+        }
+        Err(Error::Calendar(format!("{} rejected the credentials", url)))
+    }
+
+    /// Follows current-user-principal and calendar-home-set down to the
+    /// calendar collections
+    #[tracing::instrument]
+    async fn discover_calendars(&self) -> Result<Vec<Url>> {
+        let mut url = self.url.clone();
+        // At most: root -> principal -> home -> calendars
+        for _ in 0..3 {
+            let body = self
+                .dav_request("PROPFIND", &url, "0", PROPFIND_PRINCIPAL)
+                .await?;
+            let props = parse_multistatus(&body)?
+                .into_iter()
+                .next()
+                .ok_or_else(|| Error::Calendar(format!("{} returned no properties", url)))?;
+            if props.is_calendar {
+                return Ok(vec![url]);
+            }
+            if let Some(home) = props.calendar_home {
+                return self.list_calendars(&join(&url, &home)?).await;
+            }
+            match props.principal {
+                Some(principal) if join(&url, &principal)? != url => {
+                    url = join(&url, &principal)?;
+                }
+                _ => break,
+            }
+        }
+        // Not a principal either, so treat the URL as the calendar home
+        self.list_calendars(&url).await
+    }
+
+    async fn list_calendars(&self, home: &Url) -> Result<Vec<Url>> {
+        let body = self
+            .dav_request("PROPFIND", home, "1", PROPFIND_CALENDARS)
+            .await?;
+        let calendars = parse_multistatus(&body)?
+            .into_iter()
+            .filter(|props| props.is_calendar)
+            .map(|props| join(home, &props.href))
+            .collect::<Result<Vec<_>>>()?;
+        tracing::info!("Found CalDAV calendars: {:?}", calendars);
+        Ok(calendars)
+    }
+
+    async fn query(
+        &self,
+        calendar: &Url,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<CalendarEvent>> {
+        let body = self
+            .dav_request("REPORT", calendar, "1", &calendar_query(start, end))
+            .await?;
+        let mut events = vec![];
+        for props in parse_multistatus(&body)? {
+            let data = match props.calendar_data {
+                Some(data) => data,
+                None => continue,
+            };
+            match Calendar::parse(&data) {
+                Ok(cal) => events.extend(cal.events_between(start, end)),
+                Err(err) => tracing::warn!("Skipping unreadable {}: {}", props.href, err),
+            }
+        }
+        Ok(events)
+    }
+}
+
+#[async_trait]
+impl StatusSource for CalDavSource {
+    fn name(&self) -> &str {
+        "caldav"
+    }
+
+    #[tracing::instrument]
+    async fn snapshot(&self) -> Result<SourceSnapshot> {
+        let known = self.calendars.lock().unwrap().clone();
+        let calendars = match known {
+            Some(calendars) => calendars,
+            None => {
+                let calendars = self.discover_calendars().await?;
+                *self.calendars.lock().unwrap() = Some(calendars.clone());
+                calendars
+            }
+        };
+
+        let now = Utc::now();
+        let mut events = vec![];
+        for calendar in &calendars {
+            match self.query(calendar, now, now + self.lookahead).await {
+                Ok(found) => events.extend(found),
+                Err(err) => {
+                    // The calendar may have been deleted, so look again next time
+                    *self.calendars.lock().unwrap() = None;
+                    return Err(err);
+                }
+            }
+        }
+        events.sort_by_key(|evt| evt.event.start);
+        Ok(ics::to_snapshot(events, now))
+    }
+}
+
+fn join(base: &Url, href: &str) -> Result<Url> {
+    base.join(href)
+        .map_err(|err| Error::Calendar(format!("Invalid href {}: {}", href, err)))
+}
+
+fn calendar_query(start: DateTime<Utc>, end: DateTime<Utc>) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<C:calendar-query xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+  <D:prop><C:calendar-data/></D:prop>
+  <C:filter>
+    <C:comp-filter name="VCALENDAR">
+      <C:comp-filter name="VEVENT">
+        <C:time-range start="{}" end="{}"/>
+      </C:comp-filter>
+    </C:comp-filter>
+  </C:filter>
+</C:calendar-query>"#,
+        start.format("%Y%m%dT%H%M%SZ"),
+        end.format("%Y%m%dT%H%M%SZ")
+    )
+}
+
+/// The properties we ask for, from one `response` of a multistatus
+#[derive(Debug, Default, PartialEq, Eq)]
+struct DavProps {
+    href: String,
+    is_calendar: bool,
+    principal: Option<String>,
+    calendar_home: Option<String>,
+    calendar_data: Option<String>,
+}
+
+fn parse_multistatus(body: &str) -> Result<Vec<DavProps>> {
+    let doc = roxmltree::Document::parse(body)
+        .map_err(|err| Error::Calendar(format!("Invalid multistatus XML: {}", err)))?;
+    let is = |node: &roxmltree::Node, ns: &str, name: &str| {
+        node.is_element()
+            && node.tag_name().name() == name
+            && node.tag_name().namespace() == Some(ns)
+    };
+    // The href inside a property, e.g. current-user-principal/href
+    let href_in = |node: roxmltree::Node| {
+        node.descendants()
+            .find(|child| is(child, DAV_NS, "href"))
+            .and_then(|href| href.text())
+            .map(|href| href.trim().to_string())
+    };
+
+    let responses = doc
+        .descendants()
+        .filter(|node| is(node, DAV_NS, "response"))
+        .map(|response| {
+            let mut props = DavProps {
+                href: response
+                    .children()
+                    .find(|child| is(child, DAV_NS, "href"))
+                    .and_then(|href| href.text())
+                    .unwrap_or_default()
+                    .trim()
+                    .to_string(),
+                ..Default::default()
+            };
+            // Only properties the server found, not the 404 propstats
+            let found = response
+                .children()
+                .filter(|child| is(child, DAV_NS, "propstat"))
+                .filter(|propstat| {
+                    propstat
+                        .children()
+                        .find(|child| is(child, DAV_NS, "status"))
+                        .and_then(|status| status.text())
+                        .is_none_or(|status| status.contains(" 200 "))
+                });
+            for node in found.flat_map(|propstat| propstat.descendants()) {
+                if is(&node, DAV_NS, "resourcetype") {
+                    props.is_calendar |= node
+                        .children()
+                        .any(|child| is(&child, CALDAV_NS, "calendar"));
+                } else if is(&node, DAV_NS, "current-user-principal") {
+                    props.principal = href_in(node);
+                } else if is(&node, CALDAV_NS, "calendar-home-set") {
+                    props.calendar_home = href_in(node);
+                } else if is(&node, CALDAV_NS, "calendar-data") {
+                    props.calendar_data = node.text().map(Into::into);
+                }
+            }
+            props
+        })
+        .collect();
+    Ok(responses)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        http::{HeaderMap, Method as HttpMethod, StatusCode as HttpStatus, Uri},
+        response::IntoResponse,
+        routing::any,
+        Router,
+    };
+
+    use super::*;
+    use crate::http::build_durable_client;
+    use crate::test_support::spawn_server;
+
+    const EVENT: &str = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VEVENT\r\nUID:1\r\nSUMMARY:Door meeting\r\nDTSTART:{start}\r\nDTEND:{end}\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n";
+
+    fn multistatus(responses: &str) -> String {
+        format!(
+            r#"<?xml version="1.0"?><d:multistatus xmlns:d="DAV:" xmlns:cal="urn:ietf:params:xml:ns:caldav">{}</d:multistatus>"#,
+            responses
+        )
+    }
+
+    /// A Radicale-like server: the root points at the principal, which
+    /// points at the home holding one calendar. Requests need basic auth.
+    async fn fake_server(
+        method: HttpMethod,
+        uri: Uri,
+        headers: HeaderMap,
+        body: String,
+    ) -> impl IntoResponse {
+        if headers
+            .get("authorization")
+            .and_then(|val| val.to_str().ok())
+            != Some("Basic am86c2VjcmV0")
+        {
+            let mut res = HeaderMap::new();
+            res.insert(
+                "www-authenticate",
+                "Basic realm=\"Radicale\"".parse().unwrap(),
+            );
+            return (HttpStatus::UNAUTHORIZED, res, String::new());
+        }
+        let ok = r#"<d:status>HTTP/1.1 200 OK</d:status>"#;
+        let body = match (method.as_str(), uri.path()) {
+            ("PROPFIND", "/") => multistatus(&format!(
+                r#"<d:response><d:href>/</d:href><d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype><d:current-user-principal><d:href>/jo/</d:href></d:current-user-principal></d:prop>{ok}</d:propstat></d:response>"#
+            )),
+            ("PROPFIND", "/jo/") if headers.get("depth").unwrap() == "0" => multistatus(&format!(
+                r#"<d:response><d:href>/jo/</d:href><d:propstat><d:prop><cal:calendar-home-set><d:href>/jo/</d:href></cal:calendar-home-set></d:prop>{ok}</d:propstat></d:response>"#
+            )),
+            ("PROPFIND", "/jo/") => multistatus(&format!(
+                r#"<d:response><d:href>/jo/</d:href><d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop>{ok}</d:propstat></d:response><d:response><d:href>/jo/work/</d:href><d:propstat><d:prop><d:resourcetype><d:collection/><cal:calendar/></d:resourcetype></d:prop>{ok}</d:propstat></d:response>"#
+            )),
+            ("REPORT", "/jo/work/") => {
+                assert!(body.contains("time-range"));
+                let now = Utc::now();
+                let event = EVENT
+                    .replace(
+                        "{start}",
+                        &(now - Duration::minutes(5))
+                            .format("%Y%m%dT%H%M%SZ")
+                            .to_string(),
+                    )
+                    .replace(
+                        "{end}",
+                        &(now + Duration::minutes(25))
+                            .format("%Y%m%dT%H%M%SZ")
+                            .to_string(),
+                    );
+                multistatus(&format!(
+                    r#"<d:response><d:href>/jo/work/1.ics</d:href><d:propstat><d:prop><cal:calendar-data>{}</cal:calendar-data></d:prop>{ok}</d:propstat></d:response>"#,
+                    event
+                ))
+            }
+            _ => return (HttpStatus::NOT_FOUND, HeaderMap::new(), String::new()),
+        };
+        (HttpStatus::MULTI_STATUS, HeaderMap::new(), body)
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_against_fake_server() {
+        let app = Router::new().fallback(any(fake_server));
+        let url = Url::parse(&format!("http://{}/", spawn_server(app))).unwrap();
+
+        let source = CalDavSource::new(
+            build_durable_client(),
+            url.clone(),
+            Some(("jo".into(), "secret".parse().unwrap())),
+            Duration::days(7),
+        );
+        let snapshot = source.snapshot().await.unwrap();
+
+        assert_eq!(
+            snapshot.availability,
+            Some(crate::status::Availability::Busy)
+        );
+        assert_eq!(snapshot.events.len(), 1);
+        assert_eq!(snapshot.events[0].subject, "Door meeting");
+        assert_eq!(
+            source.calendars.lock().unwrap().clone(),
+            Some(vec![url.join("/jo/work/").unwrap()])
+        );
+    }
+
+    #[test]
+    fn test_parse_multistatus_skips_missing_props() {
+        let body = multistatus(
+            r#"<d:response><d:href>/cal/</d:href>
+            <d:propstat><d:prop><d:resourcetype><d:collection/><cal:calendar/></d:resourcetype></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat>
+            <d:propstat><d:prop><d:current-user-principal><d:href>/nope/</d:href></d:current-user-principal></d:prop><d:status>HTTP/1.1 404 Not Found</d:status></d:propstat>
+            </d:response>"#,
+        );
+        assert_eq!(
+            parse_multistatus(&body).unwrap(),
+            vec![DavProps {
+                href: "/cal/".into(),
+                is_calendar: true,
+                ..Default::default()
+            }]
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+
+use crate::error::{Error, Result};
+use crate::source::{SourceSnapshot, StatusSource};
+use crate::status::{Activity, Availability};
+
+/// Whether this Linux machine is capturing audio or video, which catches
+/// calls that Graph reports late or never (Zoom, Meet in a browser, ...).
+/// It only ever reports being in a call and leaves the rest to other sources.
+#[derive(Clone, Debug)]
+pub struct DeviceSource {
+    proc_root: PathBuf,
+    dev_root: PathBuf,
+}
+
+impl DeviceSource {
+    /// `proc_root` and `dev_root` are normally `/proc` and `/dev`
+    pub fn new(proc_root: &Path, dev_root: &Path) -> Self {
+        DeviceSource {
+            proc_root: proc_root.into(),
+            dev_root: dev_root.into(),
+        }
+    }
+
+    /// An ALSA capture substream is running, i.e.
+    /// `asound/card*/pcm*c/sub*/status` says `state: RUNNING`
+    fn is_microphone_active(&self) -> bool {
+        let cards = read_dir_matching(&self.proc_root.join("asound"), |name| {
+            name.starts_with("card")
+        });
+        cards
+            .flat_map(|card| {
+                read_dir_matching(&card, |name| name.starts_with("pcm") && name.ends_with('c'))
+            })
+            .flat_map(|pcm| read_dir_matching(&pcm, |name| name.starts_with("sub")))
+            .filter_map(|sub| fs::read_to_string(sub.join("status")).ok())
+            .any(|status| {
+                status.lines().any(|line| {
+                    line.split_once(':')
+                        .map(|(key, value)| (key.trim(), value.trim()))
+                        == Some(("state", "RUNNING"))
+                })
+            })
+    }
+
+    /// Some process holds a `video*` device open. Processes of other users
+    /// can't be inspected and are skipped.
+    fn is_camera_active(&self) -> bool {
+        let video = self.dev_root.join("video");
+        let video = video.to_string_lossy();
+        read_dir_matching(&self.proc_root, |name| {
+            name.chars().all(|c| c.is_ascii_digit())
+        })
+        .flat_map(|pid| read_dir_matching(&pid.join("fd"), |_| true))
+        .filter_map(|fd| fs::read_link(fd).ok())
+        .any(|target| target.to_string_lossy().starts_with(video.as_ref()))
+    }
+}
+
+#[async_trait]
+impl StatusSource for DeviceSource {
+    fn name(&self) -> &str {
+        "device"
+    }
+
+    fn is_primary(&self) -> bool {
+        false
+    }
+
+    #[tracing::instrument]
+    async fn snapshot(&self) -> Result<SourceSnapshot> {
+        let source = self.clone();
+        let (microphone, camera) = tokio::task::spawn_blocking(move || {
+            (source.is_microphone_active(), source.is_camera_active())
+        })
+        .await
+        .map_err(|err| Error::Io(std::io::Error::other(err)))?;
+        tracing::debug!(
+            "Microphone active: {}, camera active: {}",
+            microphone,
+            camera
+        );
+
+        if !(microphone || camera) {
+            return Ok(SourceSnapshot::default());
+        }
+        Ok(SourceSnapshot {
+            availability: Some(Availability::Busy),
+            activity: Some(Activity::InACall),
+            ..Default::default()
+        })
+    }
+}
+
+/// The entries of `dir` whose file name matches. Unreadable directories are
+/// treated as empty.
+fn read_dir_matching(dir: &Path, matches: impl Fn(&str) -> bool) -> impl Iterator<Item = PathBuf> {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(move |entry| entry.file_name().to_str().is_some_and(&matches))
+        .map(|entry| entry.path())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    struct Fixture {
+        root: PathBuf,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let root =
+                std::env::temp_dir().join(format!("pico-client-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(root.join("proc/asound/card0/pcm0c/sub0")).unwrap();
+            fs::create_dir_all(root.join("proc/asound/card0/pcm0p/sub0")).unwrap();
+            fs::create_dir_all(root.join("proc/1234/fd")).unwrap();
+            fs::write(root.join("proc/asound/card0/pcm0c/sub0/status"), "closed\n").unwrap();
+            fs::write(
+                root.join("proc/asound/card0/pcm0p/sub0/status"),
+                "state: RUNNING\n",
+            )
+            .unwrap();
+            symlink("/dev/null", root.join("proc/1234/fd/0")).unwrap();
+            Fixture { root }
+        }
+
+        fn source(&self) -> DeviceSource {
+            DeviceSource::new(&self.root.join("proc"), &self.root.join("dev"))
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idle_devices() {
+        let fixture = Fixture::new("idle");
+        // Playback running doesn't count, only capture
+        let snapshot = fixture.source().snapshot().await.unwrap();
+        assert_eq!(snapshot.availability, None);
+        assert_eq!(snapshot.activity, None);
+    }
+
+    #[tokio::test]
+    async fn test_microphone_in_use() {
+        let fixture = Fixture::new("mic");
+        fs::write(
+            fixture.root.join("proc/asound/card0/pcm0c/sub0/status"),
+            "state: RUNNING\nowner_pid   : 1234\n",
+        )
+        .unwrap();
+        let snapshot = fixture.source().snapshot().await.unwrap();
+        assert_eq!(snapshot.activity, Some(Activity::InACall));
+    }
+
+    #[tokio::test]
+    async fn test_camera_in_use() {
+        let fixture = Fixture::new("camera");
+        symlink(
+            fixture.root.join("dev/video0"),
+            fixture.root.join("proc/1234/fd/7"),
+        )
+        .unwrap();
+        let snapshot = fixture.source().snapshot().await.unwrap();
+        assert_eq!(snapshot.availability, Some(Availability::Busy));
+        assert_eq!(snapshot.activity, Some(Activity::InACall));
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Duration, Utc};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::http::DurableClient;
+use crate::oauth::SharedAccessToken;
+use crate::status::{self, CalendarDelta, CalendarView, DeltaPage, Event, EventChange};
+
+/// The calendarView kept in sync with Graph's `calendarView/delta`, so a poll
+/// only downloads what changed. With a path, the events and the `deltaLink`
+/// survive a restart.
+#[derive(Clone, Debug)]
+pub struct EventStore {
+    path: Option<PathBuf>,
+    lookahead: Duration,
+    data: Arc<Mutex<StoreState>>,
+}
+
+/// The first request of a sync
+#[derive(Clone, Debug)]
+pub struct SyncStart {
+    /// Relative to the Graph URL
+    pub path: String,
+    /// Set for a full sync, which starts a new window
+    window_end: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct StoreState {
+    events: HashMap<String, Event>,
+    delta_link: Option<String>,
+    /// The end of the window the `delta_link` was started with
+    window_end: Option<DateTime<Utc>>,
+}
+
+impl EventStore {
+    /// Restores the store persisted at `path`, if there is one
+    pub fn load(path: Option<PathBuf>, lookahead: Duration) -> Self {
+        let state = path.as_deref().and_then(|path| match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|err| tracing::warn!("Ignoring the persisted calendar: {}", err))
+                .ok(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => {
+                tracing::warn!("Could not read the persisted calendar: {}", err);
+                None
+            }
+        });
+        EventStore {
+            path,
+            lookahead,
+            data: Arc::new(Mutex::new(state.unwrap_or_default())),
+        }
+    }
+
+    /// Where the next sync starts: the `deltaLink`, or a full sync when there
+    /// is none, or when the look ahead no longer fits the window it was
+    /// started with
+    pub fn next_sync(&self, graph_url: &str, now: DateTime<Utc>) -> SyncStart {
+        let state = self.data.lock().unwrap();
+        let covered = state
+            .window_end
+            .is_some_and(|end| end >= now + self.lookahead);
+        // Batched requests are relative to the Graph URL, so a deltaLink
+        // elsewhere is no use
+        let delta_path = state
+            .delta_link
+            .as_deref()
+            .filter(|_| covered)
+            .and_then(|link| link.strip_prefix(graph_url));
+        match delta_path {
+            Some(path) => SyncStart {
+                path: path.into(),
+                window_end: None,
+            },
+            None => self.full_sync(now),
+        }
+    }
+
+    /// Applies the changes from the `first_page` fetched for `start`, and the
+    /// pages after it, and returns the calendarView. Starts over with a full
+    /// sync when Graph forgot the `deltaLink`.
+    #[tracing::instrument(skip(self, first_page))]
+    pub async fn finish_sync(
+        &self,
+        client: &DurableClient,
+        token: &SharedAccessToken,
+        graph_url: &str,
+        now: DateTime<Utc>,
+        start: SyncStart,
+        first_page: Result<DeltaPage>,
+    ) -> Result<CalendarView> {
+        let (start, first_page) = match first_page {
+            Err(Error::GraphStatus { status, .. })
+                if status == StatusCode::GONE && start.window_end.is_none() =>
+            {
+                tracing::warn!("Graph no longer knows the calendar deltaLink. Resyncing.");
+                let start = self.full_sync(now);
+                let url = format!("{}{}", graph_url, start.path);
+                let page = status::get_calendar_delta_page(client, token, &url).await;
+                (start, page)
+            }
+            page => (start, page),
+        };
+        let delta = status::finish_calendar_delta(client, token, first_page?).await?;
+        self.apply(delta, start.window_end);
+        Ok(self.calendar(now))
+    }
+
+    fn full_sync(&self, now: DateTime<Utc>) -> SyncStart {
+        // A day of slack, so the window is only restarted once a day
+        let window_end = now + self.lookahead + Duration::days(1);
+        SyncStart {
+            path: status::calendar_delta_path(now, window_end),
+            window_end: Some(window_end),
+        }
+    }
+
+    /// The events that haven't ended and start within the look ahead, in the
+    /// order they start
+    pub fn calendar(&self, now: DateTime<Utc>) -> CalendarView {
+        let state = self.data.lock().unwrap();
+        let mut value: Vec<Event> = state
+            .events
+            .values()
+            .filter(|evt| evt.end > now && evt.start < now + self.lookahead)
+            .cloned()
+            .collect();
+        value.sort_by_key(|evt| evt.start);
+        CalendarView { value }
+    }
+
+    /// Applies `delta` to the store. A `window_end` means the delta is a full
+    /// sync that replaces every event.
+    fn apply(&self, delta: CalendarDelta, window_end: Option<DateTime<Utc>>) {
+        let mut state = self.data.lock().unwrap();
+        if window_end.is_some() {
+            state.events.clear();
+            state.window_end = window_end;
+        }
+        tracing::info!(
+            "Applying {} calendar changes to {} events",
+            delta.changes.len(),
+            state.events.len()
+        );
+        for change in delta.changes {
+            match change {
+                EventChange::Upserted { id, event } => {
+                    state.events.insert(id, event);
+                }
+                EventChange::Removed { id } => {
+                    state.events.remove(&id);
+                }
+            }
+        }
+        state.delta_link = Some(delta.delta_link);
+        if let Err(err) = self.persist(&state) {
+            tracing::warn!("Could not persist the calendar: {}", err);
+        }
+    }
+
+    fn persist(&self, state: &StoreState) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let json = serde_json::to_vec(state).map_err(|err| Error::Calendar(err.to_string()))?;
+        fs::write(path, json)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::{
+        extract::{Extension, Query},
+        http::StatusCode as HttpStatus,
+        routing::get,
+        Json, Router,
+    };
+
+    use super::*;
+    use crate::http::build_graph_client;
+    use crate::oauth::test_token;
+    use crate::test_support::{graph_event, spawn_fake_graph, GraphUrl};
+
+    /// Pages the initial sync, then reports an update and a deletion, then
+    /// forgets the deltaLink
+    async fn fake_delta(
+        Query(params): Query<HashMap<String, String>>,
+        Extension(GraphUrl(base)): Extension<GraphUrl>,
+    ) -> (HttpStatus, Json<serde_json::Value>) {
+        let soon = Utc::now() + Duration::hours(1);
+        let link = |param: &str| format!("{}/me/calendarView/delta?{}", base, param);
+        let page = match (params.get("$skiptoken"), params.get("$deltatoken")) {
+            (None, None) => serde_json::json!({
+                "value": [graph_event("a", "Standup", soon)],
+                "@odata.nextLink": link("$skiptoken=page-2"),
+            }),
+            (Some(_), None) => serde_json::json!({
+                "value": [graph_event("b", "Retro", soon + Duration::hours(1))],
+                "@odata.deltaLink": link("$deltatoken=first"),
+            }),
+            (None, Some(token)) if token == "first" => serde_json::json!({
+                "value": [
+                    graph_event("a", "Standup (moved)", soon + Duration::hours(2)),
+                    { "id": "b", "@removed": { "reason": "deleted" } },
+                ],
+                "@odata.deltaLink": link("$deltatoken=second"),
+            }),
+            _ => {
+                return (
+                    HttpStatus::GONE,
+                    Json(serde_json::json!({ "error": { "code": "syncStateNotFound" } })),
+                )
+            }
+        };
+        (HttpStatus::OK, Json(page))
+    }
+
+    async fn sync(
+        store: &EventStore,
+        client: &DurableClient,
+        token: &SharedAccessToken,
+        graph_url: &str,
+    ) -> CalendarView {
+        let now = Utc::now();
+        let start = store.next_sync(graph_url, now);
+        let url = format!("{}{}", graph_url, start.path);
+        let page = status::get_calendar_delta_page(client, token, &url).await;
+        store
+            .finish_sync(client, token, graph_url, now, start, page)
+            .await
+            .unwrap()
+    }
+
+    fn subjects(calendar: &CalendarView) -> Vec<&str> {
+        calendar
+            .value
+            .iter()
+            .map(|evt| evt.subject.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_applies_deltas_and_resyncs_when_gone() {
+        let base = spawn_fake_graph(Router::new().route("/me/calendarView/delta", get(fake_delta)));
+        let token = test_token();
+        let client = build_graph_client();
+        let path =
+            std::env::temp_dir().join(format!("pico-client-calendar-{}.json", std::process::id()));
+        let store = EventStore::load(Some(path.clone()), Duration::days(7));
+
+        let calendar = sync(&store, &client, &token, &base).await;
+        assert_eq!(subjects(&calendar), vec!["Standup", "Retro"]);
+
+        let calendar = sync(&store, &client, &token, &base).await;
+        assert_eq!(subjects(&calendar), vec!["Standup (moved)"]);
+
+        // A restart picks up where the last sync left off
+        let restored = EventStore::load(Some(path.clone()), Duration::days(7));
+        assert_eq!(
+            subjects(&restored.calendar(Utc::now())),
+            vec!["Standup (moved)"]
+        );
+        let calendar = sync(&restored, &client, &token, &base).await;
+        assert_eq!(subjects(&calendar), vec!["Standup", "Retro"]);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+
+use crate::batch::Batch;
+use crate::error::Result;
+use crate::http::DurableClient;
+use crate::oauth::SharedAccessToken;
+use crate::source::{event_store::EventStore, SourceEvent, SourceSnapshot, StatusSource};
+use crate::status::{self, CalendarViewPage, Presence};
+
+/// Presence and the calendarView from Microsoft Graph. With an `EventStore`
+/// the calendarView is kept up to date with deltas instead of being fetched
+/// whole.
+#[derive(Clone, Debug)]
+pub struct GraphSource {
+    client: DurableClient,
+    graph_url: String,
+    token: SharedAccessToken,
+    lookahead: Duration,
+    events: Option<EventStore>,
+}
+
+impl GraphSource {
+    pub fn new(
+        client: DurableClient,
+        graph_url: &str,
+        token: SharedAccessToken,
+        lookahead: Duration,
+    ) -> Self {
+        GraphSource {
+            client,
+            graph_url: graph_url.into(),
+            token,
+            lookahead,
+            events: None,
+        }
+    }
+
+    pub fn with_event_store(mut self, events: EventStore) -> Self {
+        self.events = Some(events);
+        self
+    }
+}
+
+#[async_trait]
+impl StatusSource for GraphSource {
+    fn name(&self) -> &str {
+        "graph"
+    }
+
+    /// Presence and the first calendar request share one batch. Only the
+    /// pages after that take another round trip.
+    async fn snapshot(&self) -> Result<SourceSnapshot> {
+        let now = Utc::now();
+        let mut batch = Batch::new();
+        let presence = batch.get::<Presence>(status::PRESENCE_PATH);
+        match &self.events {
+            Some(events) => {
+                let start = events.next_sync(&self.graph_url, now);
+                let page = batch.get_with_headers(&start.path, &[status::DELTA_PREFER]);
+                let mut responses = batch
+                    .send(&self.client, &self.token, &self.graph_url)
+                    .await?;
+                let presence = responses.take(presence)?;
+                let calendar = events
+                    .finish_sync(
+                        &self.client,
+                        &self.token,
+                        &self.graph_url,
+                        now,
+                        start,
+                        responses.take(page),
+                    )
+                    .await?;
+                Ok(to_snapshot(&presence, &calendar))
+            }
+            None => {
+                let path = status::calendar_view_path(now, self.lookahead);
+                let first_page = batch.get::<CalendarViewPage>(&path);
+                let mut responses = batch
+                    .send(&self.client, &self.token, &self.graph_url)
+                    .await?;
+                let presence = responses.take(presence)?;
+                let calendar = status::finish_calendar_view(
+                    &self.client,
+                    &self.token,
+                    responses.take(first_page)?,
+                )
+                .await?;
+                Ok(to_snapshot(&presence, &calendar))
+            }
+        }
+    }
+}
+
+pub fn to_snapshot(presence: &Presence, calendar: &status::CalendarView) -> SourceSnapshot {
+    SourceSnapshot {
+        availability: Some(presence.availability),
+        activity: Some(presence.activity),
+        events: calendar
+            .value
+            .iter()
+            .map(|evt| SourceEvent {
+                subject: evt.subject.clone(),
+                start: evt.start,
+                end: evt.end,
+                attendee_count: evt.attendee_count(),
+            })
+            .collect(),
+        status_message: None,
+        overriding: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        extract::Extension,
+        routing::{get, post},
+        Json, Router,
+    };
+
+    use super::*;
+    use crate::http::build_graph_client;
+    use crate::oauth::test_token;
+    use crate::test_support::{graph_event, spawn_fake_graph, GraphUrl};
+
+    fn event(subject: &str, hours: i64) -> serde_json::Value {
+        graph_event(subject, subject, Utc::now() + Duration::hours(hours))
+    }
+
+    /// Answers the batch with the first calendarView page, which links to the
+    /// second one
+    async fn fake_batch(
+        Extension(GraphUrl(base)): Extension<GraphUrl>,
+        Json(body): Json<serde_json::Value>,
+    ) -> Json<serde_json::Value> {
+        let requests = body["requests"].as_array().unwrap();
+        let calendar = requests[1]["url"].as_str().unwrap();
+        assert!(calendar.starts_with("/me/calendarview?"));
+        assert!(calendar.contains("&$top=50"));
+        Json(serde_json::json!({ "responses": [
+            {
+                "id": requests[0]["id"],
+                "status": 200,
+                "body": { "id": "1", "availability": "Available", "activity": "Available" },
+            },
+            {
+                "id": requests[1]["id"],
+                "status": 200,
+                "body": {
+                    "value": [event("Standup", 1)],
+                    "@odata.nextLink": format!("{}/me/calendarview?$skiptoken=page-2", base),
+                },
+            },
+        ]}))
+    }
+
+    async fn fake_second_page() -> Json<serde_json::Value> {
+        Json(serde_json::json!({ "value": [event("Retro", 30)] }))
+    }
+
+    #[tokio::test]
+    async fn test_follows_the_calendar_pages() {
+        let base = spawn_fake_graph(
+            Router::new()
+                .route("/$batch", post(fake_batch))
+                .route("/me/calendarview", get(fake_second_page)),
+        );
+        let source = GraphSource::new(build_graph_client(), &base, test_token(), Duration::days(7));
+        let snapshot = source.snapshot().await.unwrap();
+        let subjects: Vec<&str> = snapshot
+            .events
+            .iter()
+            .map(|evt| evt.subject.as_str())
+            .collect();
+        assert_eq!(subjects, vec!["Standup", "Retro"]);
+    }
+}
@@ -0,0 +1,382 @@
+use std::{collections::HashSet, path::PathBuf, str::FromStr};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+
+use crate::error::{Error, Result};
+use crate::http::DurableClient;
+use crate::source::{SourceEvent, SourceSnapshot, StatusSource};
+use crate::status::{Activity, Availability};
+
+mod parser;
+mod rrule;
+mod timezone;
+
+use parser::{Component, IcsTime};
+use rrule::RRule;
+use timezone::Zones;
+
+/// Where an iCalendar file is read from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IcsLocation {
+    Path(PathBuf),
+    Url(String),
+}
+
+impl FromStr for IcsLocation {
+    type Err = String;
+
+    /// `http(s)://` and `webcal://` values are URLs, everything else a path
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Some(rest) = s.strip_prefix("webcal://") {
+            return Ok(IcsLocation::Url(format!("https://{}", rest)));
+        }
+        if s.starts_with("http://") || s.starts_with("https://") {
+            return Ok(IcsLocation::Url(s.into()));
+        }
+        Ok(IcsLocation::Path(s.into()))
+    }
+}
+
+/// An exported calendar, read again on every poll. You're busy while an
+/// opaque event is going on and available otherwise.
+#[derive(Clone, Debug)]
+pub struct IcsSource {
+    client: DurableClient,
+    location: IcsLocation,
+    lookahead: Duration,
+}
+
+impl IcsSource {
+    pub fn new(client: DurableClient, location: IcsLocation, lookahead: Duration) -> Self {
+        IcsSource {
+            client,
+            location,
+            lookahead,
+        }
+    }
+
+    async fn fetch(&self) -> Result<String> {
+        match &self.location {
+            IcsLocation::Path(path) => tokio::fs::read_to_string(path)
+                .await
+                .map_err(|err| Error::Calendar(format!("Could not read {:?}: {}", path, err))),
+            IcsLocation::Url(url) => {
+                let fetch_err = |err: reqwest_middleware::Error| {
+                    Error::Calendar(format!("Could not fetch {}: {}", url, err))
+                };
+                self.client
+                    .get(url)
+                    .send()
+                    .await
+                    .and_then(|res| res.error_for_status().map_err(Into::into))
+                    .map_err(fetch_err)?
+                    .text()
+                    .await
+                    .map_err(|err| fetch_err(err.into()))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl StatusSource for IcsSource {
+    fn name(&self) -> &str {
+        "ics"
+    }
+
+    #[tracing::instrument]
+    async fn snapshot(&self) -> Result<SourceSnapshot> {
+        let text = self.fetch().await?;
+        let calendar = Calendar::parse(&text).map_err(Error::Calendar)?;
+        let now = Utc::now();
+        let events = calendar.events_between(now, now + self.lookahead);
+        Ok(to_snapshot(events, now))
+    }
+}
+
+/// You're busy while an opaque event is going on and available otherwise
+pub fn to_snapshot(events: Vec<CalendarEvent>, now: DateTime<Utc>) -> SourceSnapshot {
+    let busy = events
+        .iter()
+        .any(|evt| evt.opaque && evt.event.start <= now && evt.event.end > now);
+    let (availability, activity) = match busy {
+        true => (Availability::Busy, Activity::InAMeeting),
+        false => (Availability::Available, Activity::Available),
+    };
+    SourceSnapshot {
+        availability: Some(availability),
+        activity: Some(activity),
+        events: events.into_iter().map(|evt| evt.event).collect(),
+        status_message: None,
+        overriding: false,
+    }
+}
+
+/// An occurrence of a VEVENT
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CalendarEvent {
+    pub event: SourceEvent,
+    /// Whether the event blocks time, i.e. TRANSP isn't TRANSPARENT
+    pub opaque: bool,
+}
+
+/// The events of a VCALENDAR and the time zones they refer to
+#[derive(Clone, Debug)]
+pub struct Calendar {
+    events: Vec<Component>,
+    zones: Zones,
+}
+
+impl Calendar {
+    pub fn parse(text: &str) -> std::result::Result<Self, String> {
+        let calendar = parser::parse(text)?;
+        Ok(Calendar {
+            events: calendar.children("VEVENT").cloned().collect(),
+            zones: Zones::from_calendar(&calendar),
+        })
+    }
+
+    /// Every occurrence that overlaps `start..end`, ordered by start.
+    /// Events that can't be read are logged and skipped.
+    pub fn events_between(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Vec<CalendarEvent> {
+        // Modified occurrences of a recurring event replace the original one
+        let overridden: HashSet<(String, DateTime<Utc>)> = self
+            .events
+            .iter()
+            .filter_map(|evt| {
+                let uid = evt.value("UID")?;
+                let recurrence_id = IcsTime::from_property(evt.property("RECURRENCE-ID")?).ok()?;
+                Some((uid.to_string(), self.zones.to_utc(&recurrence_id)))
+            })
+            .collect();
+
+        let mut occurrences = vec![];
+        for evt in &self.events {
+            if evt
+                .value("STATUS")
+                .is_some_and(|status| status.eq_ignore_ascii_case("CANCELLED"))
+            {
+                continue;
+            }
+            match self.expand(evt, &overridden, start, end) {
+                Ok(found) => occurrences.extend(found),
+                Err(err) => tracing::warn!(
+                    "Skipping event {:?}: {}",
+                    evt.value("SUMMARY").unwrap_or_default(),
+                    err
+                ),
+            }
+        }
+        occurrences.sort_by_key(|evt| evt.event.start);
+        occurrences
+    }
+
+    fn expand(
+        &self,
+        evt: &Component,
+        overridden: &HashSet<(String, DateTime<Utc>)>,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> std::result::Result<Vec<CalendarEvent>, String> {
+        let dtstart = IcsTime::from_property(evt.property("DTSTART").ok_or("No DTSTART")?)?;
+        let length = self.length(evt, &dtstart)?;
+        let tzid = match &dtstart {
+            IcsTime::Local(_, tzid) => tzid.as_deref(),
+            _ => None,
+        };
+
+        let mut starts = vec![dtstart.clone()];
+        if let Some(rule) = evt.value("RRULE") {
+            let rule: RRule = rule.parse()?;
+            // UNTIL is compared with the wall clock time of the occurrences
+            let until = rule.until.as_ref().map(|until| match (until, &dtstart) {
+                (IcsTime::Utc(utc), IcsTime::Local(..)) => {
+                    self.zones.to_wall_clock(Utc.from_utc_datetime(utc), tzid)
+                }
+                (IcsTime::Date(date), _) => date.and_hms_opt(23, 59, 59).unwrap_or_default(),
+                (until, _) => until.naive(),
+            });
+            // A day of slack covers the difference between UTC and the wall clock
+            let limit = self.zones.to_wall_clock(end, tzid) + Duration::days(1);
+            // Occurrences that end before the window are never expanded
+            let from = self.zones.to_wall_clock(start, tzid) - length - Duration::days(1);
+            starts = rule
+                .expand_between(dtstart.naive(), until, from, limit)
+                .into_iter()
+                .map(|naive| dtstart.with_naive(naive))
+                .collect();
+        }
+        for prop in evt.properties("RDATE") {
+            if prop.param("VALUE") == Some("PERIOD") {
+                continue;
+            }
+            for value in prop.value.split(',') {
+                starts.push(IcsTime::parse(value, prop.param("TZID"))?);
+            }
+        }
+
+        let mut excluded = HashSet::new();
+        for prop in evt.properties("EXDATE") {
+            for value in prop.value.split(',') {
+                excluded.insert(
+                    self.zones
+                        .to_utc(&IcsTime::parse(value, prop.param("TZID"))?),
+                );
+            }
+        }
+        let uid = evt.value("UID").unwrap_or_default();
+        let is_override = evt.property("RECURRENCE-ID").is_some();
+
+        let subject = parser::unescape_text(evt.value("SUMMARY").unwrap_or_default());
+        let attendee_count = evt.properties("ATTENDEE").count();
+        let opaque = !evt
+            .value("TRANSP")
+            .is_some_and(|transp| transp.eq_ignore_ascii_case("TRANSPARENT"));
+
+        let mut occurrences = vec![];
+        for occurrence in starts {
+            let occurrence_start = self.zones.to_utc(&occurrence);
+            let occurrence_end = self
+                .zones
+                .to_utc(&occurrence.with_naive(occurrence.naive() + length));
+            let replaced =
+                !is_override && overridden.contains(&(uid.to_string(), occurrence_start));
+            if excluded.contains(&occurrence_start) || replaced {
+                continue;
+            }
+            if occurrence_end <= start || occurrence_start >= end {
+                continue;
+            }
+            occurrences.push(CalendarEvent {
+                event: SourceEvent {
+                    subject: subject.clone(),
+                    start: occurrence_start,
+                    end: occurrence_end,
+                    attendee_count,
+                },
+                opaque,
+            });
+        }
+        Ok(occurrences)
+    }
+
+    /// How long each occurrence lasts in wall clock time, from DTEND or DURATION
+    fn length(&self, evt: &Component, dtstart: &IcsTime) -> std::result::Result<Duration, String> {
+        if let Some(dtend) = evt.property("DTEND") {
+            let dtend = IcsTime::from_property(dtend)?;
+            let same_clock = match (dtstart, &dtend) {
+                (IcsTime::Local(_, start_tz), IcsTime::Local(_, end_tz)) => start_tz == end_tz,
+                (start, end) => start.is_date() == end.is_date(),
+            };
+            return Ok(match same_clock {
+                true => dtend.naive() - dtstart.naive(),
+                false => self.zones.to_utc(&dtend) - self.zones.to_utc(dtstart),
+            });
+        }
+        if let Some(duration) = evt.value("DURATION") {
+            return parser::parse_duration(duration);
+        }
+        // All day events without an end last the whole day
+        Ok(match dtstart.is_date() {
+            true => Duration::days(1),
+            false => Duration::zero(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CALENDAR: &str = "BEGIN:VCALENDAR
+VERSION:2.0
+PRODID:-//Example//Calendar//EN
+BEGIN:VEVENT
+UID:standup@example.com
+SUMMARY:Standup
+DTSTART;TZID=America/New_York:20240304T093000
+DTEND;TZID=America/New_York:20240304T094500
+RRULE:FREQ=DAILY;BYDAY=MO,TU,WE,TH,FR
+EXDATE;TZID=America/New_York:20240306T093000
+ATTENDEE:mailto:a@example.com
+ATTENDEE:mailto:b@example.com
+END:VEVENT
+BEGIN:VEVENT
+UID:standup@example.com
+RECURRENCE-ID;TZID=America/New_York:20240307T093000
+SUMMARY:Standup (moved)
+DTSTART;TZID=America/New_York:20240307T110000
+DURATION:PT15M
+END:VEVENT
+BEGIN:VEVENT
+UID:offsite@example.com
+SUMMARY:Offsite
+DTSTART;VALUE=DATE:20240305
+TRANSP:TRANSPARENT
+END:VEVENT
+BEGIN:VEVENT
+UID:cancelled@example.com
+SUMMARY:Cancelled
+STATUS:CANCELLED
+DTSTART:20240305T150000Z
+DTEND:20240305T160000Z
+END:VEVENT
+END:VCALENDAR
+";
+
+    fn utc(value: &str) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&IcsTime::parse(value, None).unwrap().naive())
+    }
+
+    #[test]
+    fn test_events_between() {
+        let calendar = Calendar::parse(CALENDAR).unwrap();
+        // Monday to Friday, in UTC. New York is at -05:00 until March 10th.
+        let events = calendar.events_between(utc("20240304T000000"), utc("20240309T000000"));
+        let got: Vec<_> = events
+            .iter()
+            .map(|evt| (evt.event.subject.as_str(), evt.event.start))
+            .collect();
+
+        let offsite = IcsTime::parse("20240305", None).unwrap();
+        assert_eq!(
+            got,
+            vec![
+                ("Standup", utc("20240304T143000")),
+                ("Offsite", Zones::default().to_utc(&offsite)),
+                ("Standup", utc("20240305T143000")),
+                ("Standup (moved)", utc("20240307T160000")),
+                ("Standup", utc("20240308T143000")),
+            ]
+        );
+        assert_eq!(events[0].event.end, utc("20240304T144500"));
+        assert_eq!(events[0].event.attendee_count, 2);
+        assert!(!events[1].opaque);
+    }
+
+    #[test]
+    fn test_snapshot_is_busy_during_opaque_events() {
+        let calendar = Calendar::parse(CALENDAR).unwrap();
+        let events = calendar.events_between(utc("20240305T000000"), utc("20240306T000000"));
+
+        let during = to_snapshot(events.clone(), utc("20240305T144000"));
+        assert_eq!(during.availability, Some(Availability::Busy));
+        let after = to_snapshot(events, utc("20240305T150000"));
+        assert_eq!(after.availability, Some(Availability::Available));
+    }
+
+    #[test]
+    fn test_location() {
+        assert_eq!(
+            "webcal://example.com/cal.ics"
+                .parse::<IcsLocation>()
+                .unwrap(),
+            IcsLocation::Url("https://example.com/cal.ics".into())
+        );
+        assert_eq!(
+            "./cal.ics".parse::<IcsLocation>().unwrap(),
+            IcsLocation::Path("./cal.ics".into())
+        );
+    }
+}
@@ -0,0 +1,303 @@
+//! A small RFC 5545 reader: content lines, components and the value types the
+//! ICS source needs
+
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+
+/// A `NAME;PARAM=VALUE:value` content line
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Property {
+    pub name: String,
+    pub params: Vec<(String, String)>,
+    pub value: String,
+}
+
+impl Property {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A `BEGIN:NAME` ... `END:NAME` block, e.g. a VEVENT
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Component {
+    pub name: String,
+    pub properties: Vec<Property>,
+    pub components: Vec<Component>,
+}
+
+impl Component {
+    pub fn property(&self, name: &str) -> Option<&Property> {
+        self.properties
+            .iter()
+            .find(|prop| prop.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn properties<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Property> {
+        self.properties
+            .iter()
+            .filter(move |prop| prop.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.property(name).map(|prop| prop.value.as_str())
+    }
+
+    pub fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Component> {
+        self.components
+            .iter()
+            .filter(move |comp| comp.name.eq_ignore_ascii_case(name))
+    }
+}
+
+/// Parses a whole iCalendar stream and returns the VCALENDAR
+pub fn parse(text: &str) -> Result<Component, String> {
+    let mut stack: Vec<Component> = vec![];
+    let mut calendar = None;
+    for line in unfold(text) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let prop = parse_property(&line)?;
+        if prop.name.eq_ignore_ascii_case("BEGIN") {
+            stack.push(Component {
+                name: prop.value.to_ascii_uppercase(),
+                ..Default::default()
+            });
+        } else if prop.name.eq_ignore_ascii_case("END") {
+            let comp = stack
+                .pop()
+                .filter(|comp| comp.name.eq_ignore_ascii_case(&prop.value))
+                .ok_or_else(|| format!("Unexpected END:{}", prop.value))?;
+            match stack.last_mut() {
+                Some(parent) => parent.components.push(comp),
+                None => calendar = Some(comp),
+            }
+        } else {
+            stack
+                .last_mut()
+                .ok_or_else(|| format!("Property {} outside of a component", prop.name))?
+                .properties
+                .push(prop);
+        }
+    }
+    if let Some(open) = stack.last() {
+        return Err(format!("Missing END:{}", open.name));
+    }
+    calendar
+        .filter(|cal| cal.name == "VCALENDAR")
+        .ok_or_else(|| "No VCALENDAR found".into())
+}
+
+/// Joins folded lines. A line that starts with a space or tab continues the
+/// previous one.
+fn unfold(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    for line in text.lines() {
+        let line = line.trim_end_matches('\r');
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(line.into()),
+        }
+    }
+    lines
+}
+
+fn parse_property(line: &str) -> Result<Property, String> {
+    // The value starts at the first colon that isn't inside a quoted param
+    let mut quoted = false;
+    let colon = line
+        .char_indices()
+        .find(|(_, c)| {
+            if *c == '"' {
+                quoted = !quoted;
+            }
+            *c == ':' && !quoted
+        })
+        .map(|(idx, _)| idx)
+        .ok_or_else(|| format!("Invalid content line '{}'", line))?;
+    let (head, value) = (&line[..colon], &line[colon + 1..]);
+
+    let mut parts = split_unquoted(head, ';').into_iter();
+    let name = parts.next().unwrap_or_default().to_ascii_uppercase();
+    let params = parts
+        .filter_map(|param| {
+            let (key, value) = param.split_once('=')?;
+            Some((
+                key.to_ascii_uppercase(),
+                value.trim_matches('"').to_string(),
+            ))
+        })
+        .collect();
+    Ok(Property {
+        name,
+        params,
+        value: value.into(),
+    })
+}
+
+fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut quoted = false;
+    let mut start = 0;
+    for (idx, c) in s.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c == sep && !quoted {
+            parts.push(&s[start..idx]);
+            start = idx + 1;
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// Undoes the TEXT escaping of e.g. SUMMARY
+pub fn unescape_text(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => out.push(' '),
+            Some(other) => out.push(other),
+            None => {}
+        }
+    }
+    out
+}
+
+/// A DATE or DATE-TIME value, before its time zone is applied
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IcsTime {
+    /// An all day value
+    Date(NaiveDate),
+    /// `...Z` values
+    Utc(NaiveDateTime),
+    /// Wall clock time in the `TZID` zone, or in the local zone when `None`
+    Local(NaiveDateTime, Option<String>),
+}
+
+impl IcsTime {
+    pub fn from_property(prop: &Property) -> Result<Self, String> {
+        Self::parse(&prop.value, prop.param("TZID"))
+    }
+
+    pub fn parse(value: &str, tzid: Option<&str>) -> Result<Self, String> {
+        let value = value.trim();
+        if value.len() == 8 {
+            return NaiveDate::parse_from_str(value, "%Y%m%d")
+                .map(IcsTime::Date)
+                .map_err(|err| format!("Invalid date '{}': {}", value, err));
+        }
+        let (value, utc) = match value.strip_suffix(['Z', 'z']) {
+            Some(value) => (value, true),
+            None => (value, false),
+        };
+        let naive = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S")
+            .map_err(|err| format!("Invalid date-time '{}': {}", value, err))?;
+        Ok(match utc {
+            true => IcsTime::Utc(naive),
+            false => IcsTime::Local(naive, tzid.map(Into::into)),
+        })
+    }
+
+    pub fn is_date(&self) -> bool {
+        matches!(self, IcsTime::Date(_))
+    }
+
+    /// The wall clock value, with midnight for dates
+    pub fn naive(&self) -> NaiveDateTime {
+        match self {
+            IcsTime::Date(date) => date.and_hms_opt(0, 0, 0).unwrap_or_default(),
+            IcsTime::Utc(naive) | IcsTime::Local(naive, _) => *naive,
+        }
+    }
+
+    /// The same kind of value (and zone) at another wall clock time
+    pub fn with_naive(&self, naive: NaiveDateTime) -> Self {
+        match self {
+            IcsTime::Date(_) => IcsTime::Date(naive.date()),
+            IcsTime::Utc(_) => IcsTime::Utc(naive),
+            IcsTime::Local(_, tzid) => IcsTime::Local(naive, tzid.clone()),
+        }
+    }
+}
+
+/// Parses a DURATION such as `PT1H30M`, `P1D` or `-PT15M`
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration '{}'", value);
+    let (sign, rest) = match value.trim().strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, value.trim().trim_start_matches('+')),
+    };
+    let rest = rest.strip_prefix('P').ok_or_else(invalid)?;
+
+    let mut total = Duration::zero();
+    let mut number = String::new();
+    let mut in_time = false;
+    for c in rest.chars() {
+        match c {
+            'T' => in_time = true,
+            '0'..='9' => number.push(c),
+            unit => {
+                let n: i64 = number.parse().map_err(|_| invalid())?;
+                number.clear();
+                total += match (unit, in_time) {
+                    ('W', false) => Duration::weeks(n),
+                    ('D', false) => Duration::days(n),
+                    ('H', true) => Duration::hours(n),
+                    ('M', true) => Duration::minutes(n),
+                    ('S', true) => Duration::seconds(n),
+                    _ => return Err(invalid()),
+                };
+            }
+        }
+    }
+    if !number.is_empty() {
+        return Err(invalid());
+    }
+    Ok(total * sign)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_unfolds_and_nests() {
+        let text = "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nSUMMARY:Weekly\r\n  sync\\, with everyone\r\nATTENDEE;CN=\"Doe; Jane\":mailto:jane@example.com\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n";
+        let cal = parse(text).unwrap();
+        let event = cal.children("VEVENT").next().unwrap();
+
+        assert_eq!(
+            unescape_text(event.value("SUMMARY").unwrap()),
+            "Weekly sync, with everyone"
+        );
+        let attendee = event.property("ATTENDEE").unwrap();
+        assert_eq!(attendee.param("cn"), Some("Doe; Jane"));
+        assert_eq!(attendee.value, "mailto:jane@example.com");
+
+        assert!(parse("BEGIN:VCALENDAR\nBEGIN:VEVENT\nEND:VCALENDAR\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_values() {
+        assert_eq!(
+            IcsTime::parse("20240310", None).unwrap(),
+            IcsTime::Date(NaiveDate::from_ymd_opt(2024, 3, 10).unwrap())
+        );
+        assert!(matches!(
+            IcsTime::parse("20240310T090000Z", Some("Europe/Berlin")).unwrap(),
+            IcsTime::Utc(_)
+        ));
+        assert_eq!(parse_duration("PT1H30M").unwrap(), Duration::minutes(90));
+        assert_eq!(parse_duration("-P1DT2H").unwrap(), -Duration::hours(26));
+        assert!(parse_duration("PT1X").is_err());
+    }
+}
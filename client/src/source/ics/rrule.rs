@@ -0,0 +1,438 @@
+//! Expands RRULE recurrences in wall clock time. Covers what calendar apps
+//! export: every FREQ from DAILY to YEARLY with INTERVAL, COUNT, UNTIL,
+//! BYDAY, BYMONTHDAY, BYMONTH, BYSETPOS and WKST.
+
+use std::str::FromStr;
+
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Weekday};
+
+use super::parser::IcsTime;
+
+/// Guards against rules that never produce an occurrence, e.g. Feb 30th
+const MAX_PERIODS: u32 = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RRule {
+    pub freq: Frequency,
+    pub interval: u32,
+    pub count: Option<u32>,
+    pub until: Option<IcsTime>,
+    /// The weekday, and for MONTHLY and YEARLY rules which one of the
+    /// period, e.g. `-1` for the last
+    pub by_day: Vec<(Option<i32>, Weekday)>,
+    pub by_month_day: Vec<i32>,
+    pub by_month: Vec<u32>,
+    pub by_set_pos: Vec<i32>,
+    pub week_start: Weekday,
+}
+
+impl FromStr for RRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rule = RRule {
+            freq: Frequency::Daily,
+            interval: 1,
+            count: None,
+            until: None,
+            by_day: vec![],
+            by_month_day: vec![],
+            by_month: vec![],
+            by_set_pos: vec![],
+            week_start: Weekday::Mon,
+        };
+        let mut freq = None;
+        let invalid = |part: &str| format!("Invalid RRULE part '{}' in '{}'", part, s);
+        for part in s.split(';').filter(|part| !part.is_empty()) {
+            let (key, value) = part.split_once('=').ok_or_else(|| invalid(part))?;
+            match key.to_ascii_uppercase().as_str() {
+                "FREQ" => {
+                    freq = Some(match value.to_ascii_uppercase().as_str() {
+                        "DAILY" => Frequency::Daily,
+                        "WEEKLY" => Frequency::Weekly,
+                        "MONTHLY" => Frequency::Monthly,
+                        "YEARLY" => Frequency::Yearly,
+                        _ => return Err(format!("Unsupported RRULE frequency '{}'", value)),
+                    })
+                }
+                "INTERVAL" => rule.interval = value.parse().map_err(|_| invalid(part))?,
+                "COUNT" => rule.count = Some(value.parse().map_err(|_| invalid(part))?),
+                "UNTIL" => rule.until = Some(IcsTime::parse(value, None)?),
+                "BYDAY" => {
+                    rule.by_day = value
+                        .split(',')
+                        .map(parse_by_day)
+                        .collect::<Option<_>>()
+                        .ok_or_else(|| invalid(part))?
+                }
+                "BYMONTHDAY" => {
+                    rule.by_month_day = parse_list(value).ok_or_else(|| invalid(part))?
+                }
+                "BYMONTH" => rule.by_month = parse_list(value).ok_or_else(|| invalid(part))?,
+                "BYSETPOS" => rule.by_set_pos = parse_list(value).ok_or_else(|| invalid(part))?,
+                "WKST" => rule.week_start = parse_weekday(value).ok_or_else(|| invalid(part))?,
+                other => tracing::warn!("Ignoring unsupported RRULE part {}", other),
+            }
+        }
+        rule.freq = freq.ok_or_else(|| format!("RRULE '{}' has no FREQ", s))?;
+        rule.interval = rule.interval.max(1);
+        Ok(rule)
+    }
+}
+
+fn parse_list<T: FromStr>(value: &str) -> Option<Vec<T>> {
+    value.split(',').map(|item| item.parse().ok()).collect()
+}
+
+fn parse_weekday(value: &str) -> Option<Weekday> {
+    match value.to_ascii_uppercase().as_str() {
+        "MO" => Some(Weekday::Mon),
+        "TU" => Some(Weekday::Tue),
+        "WE" => Some(Weekday::Wed),
+        "TH" => Some(Weekday::Thu),
+        "FR" => Some(Weekday::Fri),
+        "SA" => Some(Weekday::Sat),
+        "SU" => Some(Weekday::Sun),
+        _ => None,
+    }
+}
+
+/// Parses `MO`, `2TU` or `-1FR`
+fn parse_by_day(value: &str) -> Option<(Option<i32>, Weekday)> {
+    let split = value.len().checked_sub(2)?;
+    let (ordinal, day) = value.split_at(split);
+    let ordinal = match ordinal.trim_start_matches('+') {
+        "" => None,
+        ordinal => Some(ordinal.parse().ok()?),
+    };
+    Some((ordinal, parse_weekday(day)?))
+}
+
+impl RRule {
+    /// Every occurrence from `dtstart` on that starts no later than `limit`.
+    /// `until` is the rule's UNTIL already moved into the wall clock of
+    /// `dtstart`. `dtstart` itself is always the first occurrence.
+    pub fn expand(
+        &self,
+        dtstart: NaiveDateTime,
+        until: Option<NaiveDateTime>,
+        limit: NaiveDateTime,
+    ) -> Vec<NaiveDateTime> {
+        self.expand_between(dtstart, until, dtstart, limit)
+    }
+
+    /// Like `expand`, but only the occurrences from `from` on. Without a
+    /// COUNT the periods before `from` are skipped rather than expanded, so
+    /// an old `dtstart` costs nothing.
+    pub fn expand_between(
+        &self,
+        dtstart: NaiveDateTime,
+        until: Option<NaiveDateTime>,
+        from: NaiveDateTime,
+        limit: NaiveDateTime,
+    ) -> Vec<NaiveDateTime> {
+        // COUNT numbers the occurrences from `dtstart`, so none can be skipped
+        let first_period = match self.count {
+            Some(_) => 0,
+            None => self.periods_before(dtstart.date(), from.date()),
+        };
+        let mut occurrences = vec![dtstart];
+        let count = self.count.unwrap_or(u32::MAX) as usize;
+        let last = until.map_or(limit, |until| until.min(limit));
+        let mut truncated = true;
+        'periods: for period in first_period..first_period.saturating_add(MAX_PERIODS) {
+            let (period_start, dates) = self.period_dates(dtstart.date(), period * self.interval);
+            if period_start > last.date() {
+                truncated = false;
+                break;
+            }
+            for date in dates {
+                let occurrence = date.and_time(dtstart.time());
+                if occurrence <= dtstart {
+                    continue;
+                }
+                if occurrence > last || occurrences.len() >= count {
+                    truncated = false;
+                    break 'periods;
+                }
+                occurrences.push(occurrence);
+            }
+        }
+        if truncated {
+            tracing::warn!(
+                "Gave up on a {:?} RRULE from {} after {} periods. Later occurrences are missing.",
+                self.freq,
+                dtstart,
+                MAX_PERIODS
+            );
+        }
+        occurrences.retain(|occurrence| *occurrence >= from);
+        occurrences
+    }
+
+    /// How many whole periods of the rule pass between `start` and `date`,
+    /// less one so the period holding `date` is never skipped
+    fn periods_before(&self, start: NaiveDate, date: NaiveDate) -> u32 {
+        let elapsed = match self.freq {
+            Frequency::Daily => (date - start).num_days(),
+            Frequency::Weekly => (date - start).num_weeks(),
+            Frequency::Monthly => {
+                (date.year() - start.year()) as i64 * 12 + date.month() as i64
+                    - start.month() as i64
+            }
+            Frequency::Yearly => (date.year() - start.year()) as i64,
+        };
+        (elapsed / self.interval as i64 - 1).clamp(0, u32::MAX as i64) as u32
+    }
+
+    /// The first day of the `offset`th period after the one holding `start`,
+    /// and the sorted dates the rule selects in it
+    fn period_dates(&self, start: NaiveDate, offset: u32) -> (NaiveDate, Vec<NaiveDate>) {
+        let offset = offset as i64;
+        let (period_start, mut dates) = match self.freq {
+            Frequency::Daily => {
+                let day = start + Duration::days(offset);
+                let keep = (self.by_month.is_empty() || self.by_month.contains(&day.month()))
+                    && (self.by_month_day.is_empty()
+                        || self
+                            .by_month_day
+                            .iter()
+                            .any(|md| month_day(day.year(), day.month(), *md) == Some(day)))
+                    && (self.by_day.is_empty()
+                        || self.by_day.iter().any(|(_, wd)| *wd == day.weekday()));
+                (day, if keep { vec![day] } else { vec![] })
+            }
+            Frequency::Weekly => {
+                let since_week_start = days_since(start.weekday(), self.week_start);
+                let week = start - Duration::days(since_week_start) + Duration::weeks(offset);
+                let weekdays = match self.by_day.is_empty() {
+                    true => vec![start.weekday()],
+                    false => self.by_day.iter().map(|(_, wd)| *wd).collect(),
+                };
+                let dates = weekdays
+                    .into_iter()
+                    .map(|wd| week + Duration::days(days_since(wd, self.week_start)))
+                    .filter(|day| self.by_month.is_empty() || self.by_month.contains(&day.month()))
+                    .collect();
+                (week, dates)
+            }
+            Frequency::Monthly => {
+                let months = start.year() as i64 * 12 + start.month0() as i64 + offset;
+                let (year, month) = ((months / 12) as i32, (months % 12) as u32 + 1);
+                let first = NaiveDate::from_ymd_opt(year, month, 1).unwrap_or(start);
+                let dates = match self.by_month.is_empty() || self.by_month.contains(&month) {
+                    true => self.month_dates(year, month, start.day()),
+                    false => vec![],
+                };
+                (first, dates)
+            }
+            Frequency::Yearly => {
+                let year = start.year() + offset as i32;
+                let first = NaiveDate::from_ymd_opt(year, 1, 1).unwrap_or(start);
+                let dates = if self.by_month.is_empty()
+                    && self.by_month_day.is_empty()
+                    && self.by_day.iter().any(|(ordinal, _)| ordinal.is_some())
+                {
+                    // e.g. the 20th Monday of the year
+                    let days = first.iter_days().take_while(|day| day.year() == year);
+                    self.select_weekdays(days.collect())
+                } else {
+                    let months = match self.by_month.is_empty() {
+                        true => vec![start.month()],
+                        false => self.by_month.clone(),
+                    };
+                    months
+                        .into_iter()
+                        .flat_map(|month| self.month_dates(year, month, start.day()))
+                        .collect()
+                };
+                (first, dates)
+            }
+        };
+        dates.sort();
+        dates.dedup();
+        (period_start, self.apply_set_pos(dates))
+    }
+
+    fn month_dates(&self, year: i32, month: u32, default_day: u32) -> Vec<NaiveDate> {
+        if !self.by_month_day.is_empty() {
+            return self
+                .by_month_day
+                .iter()
+                .filter_map(|md| month_day(year, month, *md))
+                .filter(|day| {
+                    self.by_day.is_empty() || self.by_day.iter().any(|(_, wd)| *wd == day.weekday())
+                })
+                .collect();
+        }
+        if !self.by_day.is_empty() {
+            let days = match NaiveDate::from_ymd_opt(year, month, 1) {
+                Some(first) => first
+                    .iter_days()
+                    .take_while(|day| day.month() == month)
+                    .collect(),
+                None => vec![],
+            };
+            return self.select_weekdays(days);
+        }
+        NaiveDate::from_ymd_opt(year, month, default_day)
+            .into_iter()
+            .collect()
+    }
+
+    /// Picks the BYDAY matches out of `days`, honoring ordinals like `-1FR`
+    fn select_weekdays(&self, days: Vec<NaiveDate>) -> Vec<NaiveDate> {
+        let mut selected = vec![];
+        for (ordinal, weekday) in &self.by_day {
+            let matching: Vec<NaiveDate> = days
+                .iter()
+                .copied()
+                .filter(|day| day.weekday() == *weekday)
+                .collect();
+            match ordinal {
+                None => selected.extend(matching),
+                Some(ordinal) => selected.extend(nth(&matching, *ordinal)),
+            }
+        }
+        selected
+    }
+
+    fn apply_set_pos(&self, dates: Vec<NaiveDate>) -> Vec<NaiveDate> {
+        if self.by_set_pos.is_empty() {
+            return dates;
+        }
+        let mut selected: Vec<NaiveDate> = self
+            .by_set_pos
+            .iter()
+            .filter_map(|pos| nth(&dates, *pos))
+            .collect();
+        selected.sort();
+        selected.dedup();
+        selected
+    }
+}
+
+/// 1-based from the start, or from the end when negative
+fn nth<T: Copy>(items: &[T], ordinal: i32) -> Option<T> {
+    let idx = match ordinal {
+        0 => return None,
+        n if n > 0 => n as usize - 1,
+        n => items.len().checked_sub(n.unsigned_abs() as usize)?,
+    };
+    items.get(idx).copied()
+}
+
+/// Resolves a BYMONTHDAY, which counts from the end of the month when negative
+fn month_day(year: i32, month: u32, day: i32) -> Option<NaiveDate> {
+    if day > 0 {
+        return NaiveDate::from_ymd_opt(year, month, day as u32);
+    }
+    let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+    let days: Vec<NaiveDate> = first
+        .iter_days()
+        .take_while(|date| date.month() == month)
+        .collect();
+    nth(&days, day)
+}
+
+fn days_since(day: Weekday, week_start: Weekday) -> i64 {
+    (day.num_days_from_monday() as i64 - week_start.num_days_from_monday() as i64).rem_euclid(7)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(y: i32, m: u32, d: u32, h: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, m, d)
+            .unwrap()
+            .and_hms_opt(h, 0, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_weekly_by_day_with_count() {
+        let rule: RRule = "FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,WE;COUNT=5"
+            .parse()
+            .unwrap();
+        // Monday, Jan 1st 2024
+        let got = rule.expand(at(2024, 1, 1, 9), None, at(2024, 12, 31, 0));
+        assert_eq!(
+            got,
+            vec![
+                at(2024, 1, 1, 9),
+                at(2024, 1, 3, 9),
+                at(2024, 1, 15, 9),
+                at(2024, 1, 17, 9),
+                at(2024, 1, 29, 9),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_monthly_last_weekday_and_until() {
+        let rule: RRule = "FREQ=MONTHLY;BYDAY=MO,TU,WE,TH,FR;BYSETPOS=-1"
+            .parse()
+            .unwrap();
+        let got = rule.expand(
+            at(2024, 1, 31, 15),
+            Some(at(2024, 4, 1, 0)),
+            at(2025, 1, 1, 0),
+        );
+        // Feb 29th is a Thursday and March 31st a Sunday
+        assert_eq!(
+            got,
+            vec![
+                at(2024, 1, 31, 15),
+                at(2024, 2, 29, 15),
+                at(2024, 3, 29, 15)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_yearly_nth_weekday_of_month() {
+        // The last Sunday of March, like a DST rule
+        let rule: RRule = "FREQ=YEARLY;BYMONTH=3;BYDAY=-1SU".parse().unwrap();
+        let got = rule.expand(at(2022, 3, 27, 2), None, at(2024, 12, 31, 0));
+        assert_eq!(
+            got,
+            vec![at(2022, 3, 27, 2), at(2023, 3, 26, 2), at(2024, 3, 31, 2)]
+        );
+        assert!("FREQ=HOURLY".parse::<RRule>().is_err());
+    }
+
+    #[test]
+    fn test_old_rules_skip_to_the_window() {
+        // More days than MAX_PERIODS have passed since DTSTART
+        let rule: RRule = "FREQ=DAILY;INTERVAL=3".parse().unwrap();
+        let got = rule.expand_between(
+            at(1990, 1, 1, 9),
+            None,
+            at(2024, 6, 1, 0),
+            at(2024, 6, 8, 0),
+        );
+        assert_eq!(
+            got,
+            vec![at(2024, 6, 1, 9), at(2024, 6, 4, 9), at(2024, 6, 7, 9)]
+        );
+
+        // COUNT is numbered from DTSTART, so nothing is skipped
+        let rule: RRule = "FREQ=DAILY;COUNT=3".parse().unwrap();
+        let got = rule.expand_between(
+            at(2024, 1, 1, 9),
+            None,
+            at(2024, 1, 2, 0),
+            at(2024, 2, 1, 0),
+        );
+        assert_eq!(got, vec![at(2024, 1, 2, 9), at(2024, 1, 3, 9)]);
+    }
+}
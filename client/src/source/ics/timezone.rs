@@ -0,0 +1,308 @@
+//! Turns wall clock times into instants, using either an IANA zone or the
+//! VTIMEZONE definitions shipped in the calendar (Exchange and Outlook use
+//! Windows zone names such as "W. Europe Standard Time")
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Local, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+
+use super::parser::{Component, IcsTime};
+use super::rrule::RRule;
+
+/// The zones a calendar can refer to by TZID
+#[derive(Clone, Debug, Default)]
+pub struct Zones {
+    custom: HashMap<String, VTimezone>,
+}
+
+impl Zones {
+    pub fn from_calendar(calendar: &Component) -> Self {
+        let custom = calendar
+            .children("VTIMEZONE")
+            .filter_map(|comp| {
+                let tzid = comp.value("TZID")?;
+                match VTimezone::from_component(comp) {
+                    Ok(zone) => Some((tzid.to_string(), zone)),
+                    Err(err) => {
+                        tracing::warn!("Ignoring VTIMEZONE {}: {}", tzid, err);
+                        None
+                    }
+                }
+            })
+            .collect();
+        Zones { custom }
+    }
+
+    pub fn to_utc(&self, time: &IcsTime) -> DateTime<Utc> {
+        match time {
+            IcsTime::Utc(naive) => Utc.from_utc_datetime(naive),
+            IcsTime::Date(_) => local_to_utc(&Local, time.naive()),
+            IcsTime::Local(naive, tzid) => match self.zone(tzid.as_deref()) {
+                Zone::Iana(tz) => local_to_utc(&tz, *naive),
+                Zone::Custom(zone) => {
+                    let offset = zone.offset_at(*naive, false);
+                    Utc.from_utc_datetime(&(*naive - offset))
+                }
+                Zone::Local => local_to_utc(&Local, *naive),
+            },
+        }
+    }
+
+    /// The wall clock time in the zone `tzid` at the given instant
+    pub fn to_wall_clock(&self, instant: DateTime<Utc>, tzid: Option<&str>) -> NaiveDateTime {
+        match self.zone(tzid) {
+            Zone::Iana(tz) => instant.with_timezone(&tz).naive_local(),
+            Zone::Custom(zone) => {
+                let utc = instant.naive_utc();
+                utc + zone.offset_at(utc, true)
+            }
+            Zone::Local => instant.with_timezone(&Local).naive_local(),
+        }
+    }
+
+    fn zone(&self, tzid: Option<&str>) -> Zone<'_> {
+        let tzid = match tzid {
+            Some(tzid) => tzid,
+            None => return Zone::Local,
+        };
+        // Some exporters prefix the IANA name, e.g. /mozilla.org/20050126_1/Europe/Berlin
+        let iana = tzid.rsplitn(3, '/').collect::<Vec<_>>();
+        let iana = match iana.as_slice() {
+            [city, region, _] => format!("{}/{}", region, city),
+            _ => tzid.to_string(),
+        };
+        if let Ok(tz) = tzid.parse::<Tz>().or_else(|_| iana.parse::<Tz>()) {
+            return Zone::Iana(tz);
+        }
+        match self.custom.get(tzid) {
+            Some(zone) => Zone::Custom(zone),
+            None => {
+                tracing::warn!("Unknown time zone {}. Using the local time zone.", tzid);
+                Zone::Local
+            }
+        }
+    }
+}
+
+enum Zone<'a> {
+    Iana(Tz),
+    Custom(&'a VTimezone),
+    Local,
+}
+
+/// Wall clock times in a DST gap don't exist, so they're moved past it
+fn local_to_utc<Z: TimeZone>(tz: &Z, naive: NaiveDateTime) -> DateTime<Utc> {
+    tz.from_local_datetime(&naive)
+        .earliest()
+        .or_else(|| {
+            tz.from_local_datetime(&(naive + Duration::hours(1)))
+                .earliest()
+        })
+        .map(|time| time.with_timezone(&Utc))
+        .unwrap_or_else(|| Utc.from_utc_datetime(&naive))
+}
+
+/// The STANDARD and DAYLIGHT observances of a VTIMEZONE
+#[derive(Clone, Debug)]
+struct VTimezone {
+    observances: Vec<Observance>,
+}
+
+#[derive(Clone, Debug)]
+struct Observance {
+    /// The first onset, in the wall clock time before it
+    start: NaiveDateTime,
+    offset_from: Duration,
+    offset_to: Duration,
+    rrule: Option<RRule>,
+    rdates: Vec<NaiveDateTime>,
+}
+
+impl VTimezone {
+    fn from_component(comp: &Component) -> Result<Self, String> {
+        let observances = comp
+            .components
+            .iter()
+            .filter(|child| child.name == "STANDARD" || child.name == "DAYLIGHT")
+            .map(Observance::from_component)
+            .collect::<Result<Vec<_>, _>>()?;
+        if observances.is_empty() {
+            return Err("No STANDARD or DAYLIGHT observance".into());
+        }
+        Ok(VTimezone { observances })
+    }
+
+    /// The UTC offset in effect at `time`, which is a UTC time when `is_utc`
+    /// and a wall clock time otherwise
+    fn offset_at(&self, time: NaiveDateTime, is_utc: bool) -> Duration {
+        let latest = self
+            .observances
+            .iter()
+            .filter_map(|obs| {
+                let onset = obs.last_onset(time, is_utc)?;
+                Some((onset, obs.offset_to))
+            })
+            .max_by_key(|(onset, _)| *onset);
+        match latest {
+            Some((_, offset)) => offset,
+            // Before the first onset, the zone was on the earliest offset_from
+            None => self
+                .observances
+                .iter()
+                .min_by_key(|obs| obs.start)
+                .map(|obs| obs.offset_from)
+                .unwrap_or_else(Duration::zero),
+        }
+    }
+}
+
+impl Observance {
+    fn from_component(comp: &Component) -> Result<Self, String> {
+        let required = |name: &str| {
+            comp.value(name)
+                .ok_or_else(|| format!("{} has no {}", comp.name, name))
+        };
+        let start = IcsTime::parse(required("DTSTART")?, None)?.naive();
+        let rdates = comp
+            .properties("RDATE")
+            .flat_map(|prop| prop.value.split(','))
+            .map(|value| IcsTime::parse(value, None).map(|time| time.naive()))
+            .collect::<Result<_, _>>()?;
+        Ok(Observance {
+            start,
+            offset_from: parse_offset(required("TZOFFSETFROM")?)?,
+            offset_to: parse_offset(required("TZOFFSETTO")?)?,
+            rrule: comp.value("RRULE").map(str::parse).transpose()?,
+            rdates,
+        })
+    }
+
+    /// The latest onset at or before `time`, in the same clock as `time`
+    fn last_onset(&self, time: NaiveDateTime, is_utc: bool) -> Option<NaiveDateTime> {
+        // Onsets are given in the wall clock time that was in effect before them
+        let shift = match is_utc {
+            true => self.offset_from,
+            false => Duration::zero(),
+        };
+        let wall_limit = time + shift;
+        let mut onsets = self.rdates.clone();
+        match &self.rrule {
+            Some(rule) => {
+                let until = rule.until.as_ref().map(|until| match until {
+                    IcsTime::Utc(utc) => *utc + self.offset_from,
+                    other => other.naive(),
+                });
+                // Windows zones start in 1601, so only the last couple of
+                // years are expanded, unless the rule ended before them
+                let recent = wall_limit - Duration::days(2 * 366);
+                let expanded = match rule.expand_between(self.start, until, recent, wall_limit) {
+                    onsets if onsets.is_empty() => rule.expand(self.start, until, wall_limit),
+                    onsets => onsets,
+                };
+                onsets.extend(expanded);
+            }
+            None => onsets.push(self.start),
+        }
+        onsets
+            .into_iter()
+            .filter(|onset| *onset <= wall_limit)
+            .max()
+            .map(|onset| onset - shift)
+    }
+}
+
+/// Parses a UTC offset such as `+0100`, `-0530` or `+013000`
+fn parse_offset(value: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid UTC offset '{}'", value);
+    let (sign, digits) = match value.split_at(1) {
+        ("+", digits) => (1, digits),
+        ("-", digits) => (-1, digits),
+        _ => return Err(invalid()),
+    };
+    if !(digits.len() == 4 || digits.len() == 6) || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let part = |range: std::ops::Range<usize>| {
+        digits
+            .get(range)
+            .and_then(|s| s.parse::<i64>().ok())
+            .unwrap_or(0)
+    };
+    let secs = part(0..2) * 3600 + part(2..4) * 60 + part(4..6);
+    Ok(Duration::seconds(sign * secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::ics::parser;
+
+    const VTIMEZONE: &str = "BEGIN:VCALENDAR
+BEGIN:VTIMEZONE
+TZID:W. Europe Standard Time
+BEGIN:STANDARD
+DTSTART:16010101T030000
+TZOFFSETFROM:+0200
+TZOFFSETTO:+0100
+RRULE:FREQ=YEARLY;INTERVAL=1;BYDAY=-1SU;BYMONTH=10
+END:STANDARD
+BEGIN:DAYLIGHT
+DTSTART:16010101T020000
+TZOFFSETFROM:+0100
+TZOFFSETTO:+0200
+RRULE:FREQ=YEARLY;INTERVAL=1;BYDAY=-1SU;BYMONTH=3
+END:DAYLIGHT
+END:VTIMEZONE
+END:VCALENDAR
+";
+
+    fn local(value: &str, tzid: &str) -> IcsTime {
+        IcsTime::parse(value, Some(tzid)).unwrap()
+    }
+
+    fn utc(value: &str) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&IcsTime::parse(value, None).unwrap().naive())
+    }
+
+    #[test]
+    fn test_custom_vtimezone() {
+        let zones = Zones::from_calendar(&parser::parse(VTIMEZONE).unwrap());
+        let tzid = "W. Europe Standard Time";
+
+        // Winter, summer, and the day after the switch to summer time
+        assert_eq!(
+            zones.to_utc(&local("20240115T090000", tzid)),
+            utc("20240115T080000")
+        );
+        assert_eq!(
+            zones.to_utc(&local("20240715T090000", tzid)),
+            utc("20240715T070000")
+        );
+        assert_eq!(
+            zones.to_utc(&local("20240401T090000", tzid)),
+            utc("20240401T070000")
+        );
+        assert_eq!(
+            zones.to_wall_clock(utc("20241028T080000"), Some(tzid)),
+            IcsTime::parse("20241028T090000", None).unwrap().naive()
+        );
+    }
+
+    #[test]
+    fn test_iana_zone() {
+        let zones = Zones::default();
+        assert_eq!(
+            zones.to_utc(&local("20240715T090000", "America/New_York")),
+            utc("20240715T130000")
+        );
+        assert_eq!(
+            zones.to_utc(&local(
+                "20240715T090000",
+                "/mozilla.org/20050126_1/America/New_York"
+            )),
+            utc("20240715T130000")
+        );
+        assert_eq!(parse_offset("-0530").unwrap(), -Duration::minutes(330));
+    }
+}
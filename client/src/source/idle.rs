@@ -0,0 +1,343 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+
+use crate::error::{Error, Result};
+use crate::source::{SourceSnapshot, StatusSource};
+use crate::status::{Activity, Availability};
+
+const LOGIND_DESTINATION: &str = "org.freedesktop.login1";
+const LOGIND_SESSION_INTERFACE: &str = "org.freedesktop.login1.Session";
+
+/// What the desktop session says about you
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IdleHints {
+    /// No input for a while
+    pub idle: bool,
+    /// The screen is locked
+    pub locked: bool,
+}
+
+/// Reads the idle and lock state of the desktop session. Implemented over
+/// D-Bus for logind, and by test doubles.
+#[async_trait]
+pub trait SessionHints: std::fmt::Debug + Send + Sync {
+    async fn hints(&self) -> Result<IdleHints>;
+}
+
+/// Notices you walked off well before Graph does. A locked screen is reported
+/// as `Away` and overrides the other sources, so `Status::is_late` lights up
+/// promptly. Being idle is reported as `BeRightBack`, which only shows when no
+/// other source knows better.
+#[derive(Debug)]
+pub struct IdleSource {
+    /// Tried in order until one answers
+    hints: Vec<Box<dyn SessionHints>>,
+}
+
+impl IdleSource {
+    pub fn new(hints: Vec<Box<dyn SessionHints>>) -> Self {
+        IdleSource { hints }
+    }
+}
+
+#[async_trait]
+impl StatusSource for IdleSource {
+    fn name(&self) -> &str {
+        "idle"
+    }
+
+    fn is_primary(&self) -> bool {
+        false
+    }
+
+    #[tracing::instrument]
+    async fn snapshot(&self) -> Result<SourceSnapshot> {
+        let mut last_err = None;
+        for hints in &self.hints {
+            match hints.hints().await {
+                Ok(hints) => return Ok(to_snapshot(hints)),
+                Err(err) => {
+                    tracing::debug!("{:?} failed: {}", hints, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| Error::Session("No session hints configured".into())))
+    }
+}
+
+fn to_snapshot(hints: IdleHints) -> SourceSnapshot {
+    if hints.locked {
+        return SourceSnapshot {
+            availability: Some(Availability::Away),
+            activity: Some(Activity::Away),
+            overriding: true,
+            ..Default::default()
+        };
+    }
+    if hints.idle {
+        return SourceSnapshot {
+            availability: Some(Availability::BeRightBack),
+            activity: Some(Activity::BeRightBack),
+            ..Default::default()
+        };
+    }
+    SourceSnapshot::default()
+}
+
+/// `IdleHint` and `LockedHint` of a systemd-logind session, read from the
+/// system bus
+#[derive(Clone, Debug)]
+pub struct LogindHints {
+    /// The session ID, or `auto` for the session of the calling user
+    session: String,
+}
+
+impl LogindHints {
+    pub fn new(session: &str) -> Self {
+        LogindHints {
+            session: session.into(),
+        }
+    }
+
+    fn object_path(&self) -> String {
+        format!(
+            "/org/freedesktop/login1/session/{}",
+            escape_bus_label(&self.session)
+        )
+    }
+}
+
+#[async_trait]
+impl SessionHints for LogindHints {
+    async fn hints(&self) -> Result<IdleHints> {
+        let dbus_err = |err: zbus::Error| Error::Session(format!("logind: {}", err));
+        let conn = zbus::Connection::system().await.map_err(dbus_err)?;
+        let proxy = zbus::ProxyBuilder::<zbus::Proxy>::new_bare(&conn)
+            .destination(LOGIND_DESTINATION)
+            .and_then(|builder| builder.path(self.object_path()))
+            .and_then(|builder| builder.interface(LOGIND_SESSION_INTERFACE))
+            .map_err(dbus_err)?
+            .cache_properties(zbus::CacheProperties::No)
+            .build()
+            .await
+            .map_err(dbus_err)?;
+        Ok(IdleHints {
+            idle: proxy.get_property("IdleHint").await.map_err(dbus_err)?,
+            locked: proxy.get_property("LockedHint").await.map_err(dbus_err)?,
+        })
+    }
+}
+
+/// Escapes a session ID like sd-bus does for object paths, e.g. `2` becomes
+/// `_32`
+fn escape_bus_label(label: &str) -> String {
+    let mut escaped = String::new();
+    for (idx, byte) in label.bytes().enumerate() {
+        if byte.is_ascii_alphabetic() || (byte.is_ascii_digit() && idx > 0) {
+            escaped.push(byte as char);
+        } else {
+            escaped.push_str(&format!("_{:02x}", byte));
+        }
+    }
+    escaped
+}
+
+/// The fallback when logind isn't reachable: you're idle when no input
+/// device under `dev_root/input` produced an event for `idle_after`. Reading
+/// the devices usually takes membership of the `input` group. The screen lock
+/// can't be seen this way.
+#[derive(Clone, Debug)]
+pub struct InputIdleHints {
+    dev_root: PathBuf,
+    idle_after: Duration,
+    watch: Arc<Mutex<InputWatch>>,
+}
+
+#[derive(Debug)]
+struct InputWatch {
+    /// The devices a reader thread is blocked on
+    devices: HashSet<PathBuf>,
+    /// The last event of any device, or when watching started
+    last_input: Instant,
+}
+
+impl InputIdleHints {
+    pub fn new(dev_root: &Path, idle_after: Duration) -> Self {
+        InputIdleHints {
+            dev_root: dev_root.into(),
+            idle_after,
+            watch: Arc::new(Mutex::new(InputWatch {
+                devices: HashSet::new(),
+                last_input: Instant::now(),
+            })),
+        }
+    }
+
+    /// Starts reading the devices that aren't read yet, e.g. because they
+    /// were plugged in since, and returns the time of the last event
+    fn last_input(&self) -> Result<Instant> {
+        let dir = self.dev_root.join("input");
+        let mut watch = self.watch.lock().unwrap();
+        let mut opened = false;
+        for entry in fs::read_dir(&dir)?.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            let is_event = entry.file_name().to_string_lossy().starts_with("event");
+            if !is_event || watch.devices.contains(&path) {
+                continue;
+            }
+            match File::open(&path) {
+                Ok(device) => {
+                    watch.devices.insert(path.clone());
+                    read_events(path, device, self.watch.clone());
+                    opened = true;
+                }
+                Err(err) => tracing::debug!("Can't read {:?}: {}", path, err),
+            }
+        }
+        if watch.devices.is_empty() && !opened {
+            return Err(Error::Session(format!(
+                "No readable input devices in {:?}",
+                dir
+            )));
+        }
+        Ok(watch.last_input)
+    }
+}
+
+/// Blocks a thread on `device` and records when it produces events, until it
+/// goes away
+fn read_events(path: PathBuf, mut device: File, watch: Arc<Mutex<InputWatch>>) {
+    std::thread::spawn(move || {
+        // Room for a few dozen `struct input_event`s per read
+        let mut buf = [0u8; 1024];
+        while let Ok(1..) = device.read(&mut buf) {
+            watch.lock().unwrap().last_input = Instant::now();
+        }
+        tracing::debug!("Stopped reading {:?}", path);
+        watch.lock().unwrap().devices.remove(&path);
+    });
+}
+
+#[async_trait]
+impl SessionHints for InputIdleHints {
+    async fn hints(&self) -> Result<IdleHints> {
+        Ok(IdleHints {
+            idle: self.last_input()?.elapsed() >= self.idle_after,
+            locked: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stands in for logind
+    #[derive(Debug)]
+    struct FakeHints(Option<IdleHints>);
+
+    #[async_trait]
+    impl SessionHints for FakeHints {
+        async fn hints(&self) -> Result<IdleHints> {
+            self.0.ok_or_else(|| Error::Session("no bus".into()))
+        }
+    }
+
+    fn source(hints: Vec<Option<IdleHints>>) -> IdleSource {
+        IdleSource::new(
+            hints
+                .into_iter()
+                .map(|hints| Box::new(FakeHints(hints)) as Box<dyn SessionHints>)
+                .collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_locked_overrides_and_idle_does_not() {
+        let locked = IdleHints {
+            idle: true,
+            locked: true,
+        };
+        let snapshot = source(vec![Some(locked)]).snapshot().await.unwrap();
+        assert_eq!(snapshot.availability, Some(Availability::Away));
+        assert!(snapshot.overriding);
+
+        let idle = IdleHints {
+            idle: true,
+            locked: false,
+        };
+        let snapshot = source(vec![Some(idle)]).snapshot().await.unwrap();
+        assert_eq!(snapshot.availability, Some(Availability::BeRightBack));
+        assert!(!snapshot.overriding);
+
+        let snapshot = source(vec![Some(IdleHints::default())])
+            .snapshot()
+            .await
+            .unwrap();
+        assert_eq!(snapshot.availability, None);
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_when_logind_fails() {
+        let idle = IdleHints {
+            idle: true,
+            locked: false,
+        };
+        let snapshot = source(vec![None, Some(idle)]).snapshot().await.unwrap();
+        assert_eq!(snapshot.activity, Some(Activity::BeRightBack));
+        assert!(source(vec![None]).snapshot().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_input_events_reset_idle() {
+        let dev_root = std::env::temp_dir().join(format!("pico-client-dev-{}", std::process::id()));
+        let hints = InputIdleHints::new(&dev_root, Duration::from_millis(200));
+        assert!(hints.hints().await.is_err());
+
+        // Only event devices count, and an empty one never produces input
+        fs::create_dir_all(dev_root.join("input")).unwrap();
+        fs::write(dev_root.join("input/mice"), [1u8; 24]).unwrap();
+        fs::write(dev_root.join("input/event0"), []).unwrap();
+        assert!(!hints.hints().await.unwrap().idle);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(hints.hints().await.unwrap().idle);
+
+        // One `struct input_event`, as the kernel hands it out
+        fs::write(dev_root.join("input/event0"), [1u8; 24]).unwrap();
+        let active = async {
+            while hints.hints().await.unwrap().idle {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), active)
+            .await
+            .unwrap();
+
+        fs::remove_dir_all(dev_root).unwrap();
+    }
+
+    #[test]
+    fn test_object_path() {
+        assert_eq!(
+            LogindHints::new("auto").object_path(),
+            "/org/freedesktop/login1/session/auto"
+        );
+        assert_eq!(
+            LogindHints::new("c2").object_path(),
+            "/org/freedesktop/login1/session/c2"
+        );
+        assert_eq!(
+            LogindHints::new("2").object_path(),
+            "/org/freedesktop/login1/session/_32"
+        );
+    }
+}
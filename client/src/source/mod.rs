@@ -0,0 +1,339 @@
+use std::{fmt, sync::Mutex};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use futures::future::join_all;
+
+use crate::control::OverrideStore;
+use crate::error::{Error, Result};
+use crate::rules::Rules;
+use crate::status::{Activity, Availability, Status};
+
+pub mod caldav;
+pub mod device;
+pub mod event_store;
+pub mod graph;
+pub mod ics;
+pub mod idle;
+pub mod slack;
+
+/// Something that knows whether you're available, e.g. Graph presence or a
+/// calendar. Sources only report what they know; everything else is `None`
+/// or empty and left to the other sources.
+#[async_trait]
+pub trait StatusSource: fmt::Debug + Send + Sync {
+    /// A short name for logs, e.g. "graph"
+    fn name(&self) -> &str;
+
+    /// Whether the status is wrong without this source, as with presence and
+    /// calendars. A failing primary source fails the poll, while the others
+    /// only refine the status and are skipped.
+    fn is_primary(&self) -> bool {
+        true
+    }
+
+    async fn snapshot(&self) -> Result<SourceSnapshot>;
+}
+
+/// The normalized view a `StatusSource` has of you right now
+#[derive(Clone, Debug, Default)]
+pub struct SourceSnapshot {
+    pub availability: Option<Availability>,
+    pub activity: Option<Activity>,
+    /// Upcoming and current events, in any order
+    pub events: Vec<SourceEvent>,
+    /// A free text status, e.g. a Slack custom status
+    pub status_message: Option<String>,
+    /// The availability wins regardless of the `MergeRule`, e.g. because the
+    /// screen is locked
+    pub overriding: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceEvent {
+    pub subject: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub attendee_count: usize,
+}
+
+/// How the availability of several sources is merged. Sources are always
+/// consulted in the order they were added, which is their priority.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum MergeRule {
+    /// A busy availability from any source wins. Otherwise the highest
+    /// priority source that knows your availability wins.
+    #[default]
+    BusyWins,
+    /// The highest priority source that knows your availability wins
+    Priority,
+}
+
+/// Merges the snapshots of every source into one `Status`
+#[derive(Debug)]
+pub struct Aggregator {
+    sources: Vec<Box<dyn StatusSource>>,
+    rule: MergeRule,
+    overrides: Option<OverrideStore>,
+    rules: Rules,
+    /// What the sources said at the last successful poll
+    last: Mutex<SourceSnapshot>,
+}
+
+impl Aggregator {
+    pub fn new(rule: MergeRule) -> Self {
+        Aggregator {
+            sources: vec![],
+            rule,
+            overrides: None,
+            rules: Rules::default(),
+            last: Mutex::new(SourceSnapshot::default()),
+        }
+    }
+
+    /// Adds a source with a lower priority than every source added before it
+    pub fn with_source(mut self, source: impl StatusSource + 'static) -> Self {
+        self.sources.push(Box::new(source));
+        self
+    }
+
+    /// Lets a manual override beat every source while it lasts
+    pub fn with_overrides(mut self, overrides: OverrideStore) -> Self {
+        self.overrides = Some(overrides);
+        self
+    }
+
+    /// Replaces the built in rules that pick the colors and texts
+    pub fn with_rules(mut self, rules: Rules) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    /// Asks every source concurrently and merges what they know. A failing
+    /// source is skipped, unless it is a primary source, its error is one the
+    /// run loop reacts to (an expired token or throttling) or every source
+    /// failed. The run loop counts those errors against `--err-tolerance`.
+    #[tracing::instrument]
+    pub async fn get_status(&self) -> Result<Status> {
+        let results = join_all(self.sources.iter().map(|source| source.snapshot())).await;
+
+        let mut snapshots = vec![];
+        let mut first_err: Option<Error> = None;
+        for (source, res) in self.sources.iter().zip(results) {
+            match res {
+                Ok(snapshot) => snapshots.push(snapshot),
+                Err(err)
+                    if source.is_primary()
+                        || err.is_unauthorized()
+                        || err.throttled_for().is_some() =>
+                {
+                    return Err(err)
+                }
+                Err(err) => {
+                    tracing::warn!("Status source {} failed: {}", source.name(), err);
+                    first_err.get_or_insert(err);
+                }
+            }
+        }
+        if snapshots.is_empty() {
+            if let Some(err) = first_err {
+                return Err(err);
+            }
+        }
+
+        *self.last.lock().unwrap() = merge(&snapshots, self.rule);
+        let status = self.render();
+        tracing::info!("Status: {:#?}", status);
+        Ok(status)
+    }
+
+    /// The status as of now from what the sources said at the last poll.
+    /// Time moves on without the sources, e.g. when a meeting starts.
+    pub fn render(&self) -> Status {
+        let mut merged = self.last.lock().unwrap().clone();
+        if let Some(overrides) = &self.overrides {
+            overrides.apply(&mut merged, Utc::now());
+        }
+        Status::from_snapshot(&merged, &self.rules)
+    }
+
+    /// `render`, but only while a manual override is active. The override is
+    /// shown even when the sources can't be asked.
+    pub fn render_override(&self) -> Option<Status> {
+        let overriding = self
+            .overrides
+            .as_ref()
+            .is_some_and(|overrides| overrides.current().is_some());
+        overriding.then(|| self.render())
+    }
+}
+
+/// Merges snapshots given in priority order. The activity always comes from
+/// the same source as the winning availability, so the two stay consistent.
+pub fn merge(snapshots: &[SourceSnapshot], rule: MergeRule) -> SourceSnapshot {
+    let known = || snapshots.iter().filter(|snap| snap.availability.is_some());
+    let overriding = known().find(|snap| snap.overriding);
+    let winner = overriding.or_else(|| match rule {
+        MergeRule::BusyWins => known()
+            .find(|snap| snap.availability.is_some_and(|avail| avail.is_busy()))
+            .or_else(|| known().next()),
+        MergeRule::Priority => known().next(),
+    });
+
+    let mut events: Vec<SourceEvent> = snapshots
+        .iter()
+        .flat_map(|snap| snap.events.iter().cloned())
+        .collect();
+    events.sort_by_key(|evt| evt.start);
+    events.dedup();
+
+    SourceSnapshot {
+        availability: winner.and_then(|snap| snap.availability),
+        activity: winner.and_then(|snap| snap.activity),
+        events,
+        status_message: snapshots
+            .iter()
+            .find_map(|snap| snap.status_message.clone()),
+        overriding: overriding.is_some(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{OverrideColor, Until};
+    use crate::pico::Route;
+
+    #[derive(Debug)]
+    struct FailingSource {
+        primary: bool,
+    }
+
+    #[async_trait]
+    impl StatusSource for FailingSource {
+        fn name(&self) -> &str {
+            "failing"
+        }
+
+        fn is_primary(&self) -> bool {
+            self.primary
+        }
+
+        async fn snapshot(&self) -> Result<SourceSnapshot> {
+            Err(Error::Calendar("unreachable".into()))
+        }
+    }
+
+    #[derive(Debug)]
+    struct FixedSource(SourceSnapshot);
+
+    #[async_trait]
+    impl StatusSource for FixedSource {
+        fn name(&self) -> &str {
+            "fixed"
+        }
+
+        fn is_primary(&self) -> bool {
+            false
+        }
+
+        async fn snapshot(&self) -> Result<SourceSnapshot> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn snapshot(availability: Availability, activity: Activity) -> SourceSnapshot {
+        SourceSnapshot {
+            availability: Some(availability),
+            activity: Some(activity),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_merge_rules() {
+        let snapshots = vec![
+            SourceSnapshot::default(),
+            snapshot(Availability::Available, Activity::Available),
+            snapshot(Availability::Busy, Activity::InACall),
+        ];
+
+        let merged = merge(&snapshots, MergeRule::BusyWins);
+        assert_eq!(merged.availability, Some(Availability::Busy));
+        assert_eq!(merged.activity, Some(Activity::InACall));
+
+        let merged = merge(&snapshots, MergeRule::Priority);
+        assert_eq!(merged.availability, Some(Availability::Available));
+        assert_eq!(merged.activity, Some(Activity::Available));
+
+        assert_eq!(merge(&[], MergeRule::BusyWins).availability, None);
+
+        let locked = SourceSnapshot {
+            overriding: true,
+            ..snapshot(Availability::Away, Activity::Away)
+        };
+        let merged = merge(&[snapshots, vec![locked]].concat(), MergeRule::BusyWins);
+        assert_eq!(merged.availability, Some(Availability::Away));
+    }
+
+    #[test]
+    fn test_merge_sorts_events() {
+        let now = Utc::now();
+        let event = |subject: &str, hours: i64| SourceEvent {
+            subject: subject.into(),
+            start: now + chrono::Duration::hours(hours),
+            end: now + chrono::Duration::hours(hours + 1),
+            attendee_count: 0,
+        };
+        let snapshots = vec![
+            SourceSnapshot {
+                events: vec![event("later", 3), event("soon", 1)],
+                ..Default::default()
+            },
+            SourceSnapshot {
+                events: vec![event("between", 2), event("soon", 1)],
+                ..Default::default()
+            },
+        ];
+
+        let subjects: Vec<_> = merge(&snapshots, MergeRule::BusyWins)
+            .events
+            .into_iter()
+            .map(|evt| evt.subject)
+            .collect();
+        assert_eq!(subjects, vec!["soon", "between", "later"]);
+    }
+
+    #[tokio::test]
+    async fn test_only_a_failing_primary_source_fails_the_poll() {
+        let idle = || FixedSource(snapshot(Availability::BeRightBack, Activity::BeRightBack));
+        let aggregator = Aggregator::new(MergeRule::BusyWins)
+            .with_source(FailingSource { primary: true })
+            .with_source(idle());
+        assert!(aggregator.get_status().await.is_err());
+
+        let aggregator = Aggregator::new(MergeRule::BusyWins)
+            .with_source(FailingSource { primary: false })
+            .with_source(idle());
+        let status = aggregator.get_status().await.unwrap();
+        assert_eq!(status.screen_color(), Route::Yellow);
+    }
+
+    #[tokio::test]
+    async fn test_override_renders_when_every_source_fails() {
+        let overrides = OverrideStore::load(None);
+        let aggregator = Aggregator::new(MergeRule::BusyWins)
+            .with_source(FailingSource { primary: false })
+            .with_overrides(overrides.clone());
+        assert!(aggregator.get_status().await.is_err());
+        assert!(aggregator.render_override().is_none());
+
+        let until = Utc::now() + chrono::Duration::minutes(30);
+        overrides
+            .set(OverrideColor::Red, Some("Focus".into()), Until::At(until))
+            .unwrap();
+        let status = aggregator.render_override().unwrap();
+        assert_eq!(status.screen_color(), Route::Red);
+    }
+}
@@ -0,0 +1,322 @@
+use std::{fmt, str::FromStr};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::de::DeserializeOwned;
+
+use crate::error::{Error, Result};
+use crate::http::DurableClient;
+use crate::source::{SourceSnapshot, StatusSource};
+use crate::status::{Activity, Availability};
+
+pub const DEFAULT_SLACK_API_URL: &str = "https://slack.com/api";
+
+/// A Slack user token (`xoxp-...`) with the `users:read`, `dnd:read` and
+/// `users.profile:read` scopes. The value never appears in `Debug` output.
+#[derive(Clone)]
+pub struct SlackToken(String);
+
+impl fmt::Debug for SlackToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+impl FromStr for SlackToken {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(SlackToken(s.into()))
+    }
+}
+
+/// Presence, Do Not Disturb and the custom status of the Slack user the
+/// token belongs to
+#[derive(Clone, Debug)]
+pub struct SlackSource {
+    client: DurableClient,
+    api_url: String,
+    token: SlackToken,
+}
+
+impl SlackSource {
+    pub fn new(client: DurableClient, api_url: &str, token: SlackToken) -> Self {
+        SlackSource {
+            client,
+            api_url: api_url.trim_end_matches('/').into(),
+            token,
+        }
+    }
+
+    /// Calls a Web API method. Slack answers most failures with a 200 and
+    /// `"ok": false`, so both are turned into `Error::Slack`.
+    async fn call<T: DeserializeOwned>(&self, method: &str) -> Result<T> {
+        let url = format!("{}/{}", self.api_url, method);
+        let res = self
+            .client
+            .get(&url)
+            .bearer_auth(&self.token.0)
+            .send()
+            .await
+            .map_err(|err| Error::Slack(format!("Could not reach {}: {}", method, err)))?;
+        let status = res.status();
+        let body = res
+            .text()
+            .await
+            .map_err(|err| Error::Slack(format!("Could not read {}: {}", method, err)))?;
+        if !status.is_success() {
+            return Err(Error::Slack(format!(
+                "{} responded with {}",
+                method, status
+            )));
+        }
+        let unexpected = |err: serde_json::Error| {
+            Error::Slack(format!("Unexpected {} response: {}", method, err))
+        };
+        // The payloads are mostly optional fields, so `ok` has to be checked
+        // before one is parsed
+        let envelope = serde_json::from_str::<SlackEnvelope>(&body).map_err(unexpected)?;
+        if !envelope.ok {
+            return Err(Error::Slack(format!(
+                "{} failed: {}",
+                method,
+                envelope.error.as_deref().unwrap_or("unknown error")
+            )));
+        }
+        serde_json::from_str(&body).map_err(unexpected)
+    }
+}
+
+#[async_trait]
+impl StatusSource for SlackSource {
+    fn name(&self) -> &str {
+        "slack"
+    }
+
+    #[tracing::instrument]
+    async fn snapshot(&self) -> Result<SourceSnapshot> {
+        let (presence, dnd, profile) = tokio::join!(
+            self.call::<SlackPresence>("users.getPresence"),
+            self.call::<SlackDnd>("dnd.info"),
+            self.call::<SlackProfileResponse>("users.profile.get")
+        );
+        let snapshot = to_snapshot(&presence?, &dnd?, &profile?.profile, Utc::now().timestamp());
+        tracing::trace!("Slack snapshot: {:?}", snapshot);
+        Ok(snapshot)
+    }
+}
+
+/// What every Web API response has, next to the method's payload
+#[derive(Debug, Deserialize)]
+struct SlackEnvelope {
+    ok: bool,
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SlackPresence {
+    /// `active` or `away`
+    presence: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct SlackDnd {
+    #[serde(default)]
+    dnd_enabled: bool,
+    #[serde(default)]
+    next_dnd_start_ts: i64,
+    #[serde(default)]
+    next_dnd_end_ts: i64,
+    #[serde(default)]
+    snooze_enabled: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct SlackProfileResponse {
+    profile: SlackProfile,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct SlackProfile {
+    #[serde(default)]
+    status_text: String,
+    #[serde(default)]
+    status_emoji: String,
+    /// Unix time when the custom status clears, or 0 if it doesn't
+    #[serde(default)]
+    status_expiration: i64,
+    /// `in_a_huddle` while in a huddle
+    #[serde(default)]
+    huddle_state: Option<String>,
+}
+
+/// Maps Slack onto Graph's vocabulary. Do Not Disturb beats a huddle, which
+/// beats being away, so `Status::screen_color` works unchanged.
+fn to_snapshot(
+    presence: &SlackPresence,
+    dnd: &SlackDnd,
+    profile: &SlackProfile,
+    now: i64,
+) -> SourceSnapshot {
+    let in_dnd = dnd.snooze_enabled
+        || (dnd.dnd_enabled && dnd.next_dnd_start_ts <= now && now < dnd.next_dnd_end_ts);
+    let status_set = !profile.status_text.is_empty() || !profile.status_emoji.is_empty();
+    let status_current =
+        status_set && (profile.status_expiration == 0 || profile.status_expiration > now);
+    let in_huddle = profile.huddle_state.as_deref() == Some("in_a_huddle")
+        || (status_current
+            && profile.status_emoji == ":headphones:"
+            && profile.status_text.to_lowercase().contains("huddle"));
+
+    let (availability, activity) = if in_dnd {
+        (Availability::DoNotDisturb, Activity::DoNotDisturb)
+    } else if in_huddle {
+        (Availability::Busy, Activity::InACall)
+    } else if presence.presence == "away" {
+        (Availability::Away, Activity::Away)
+    } else {
+        match profile.status_emoji.as_str() {
+            ":spiral_calendar_pad:" | ":calendar:" if status_current => {
+                (Availability::Busy, Activity::InAMeeting)
+            }
+            ":palm_tree:" if status_current => (Availability::Away, Activity::OutOfOffice),
+            _ => (Availability::Available, Activity::Available),
+        }
+    };
+
+    SourceSnapshot {
+        availability: Some(availability),
+        activity: Some(activity),
+        events: vec![],
+        // The LCD font can't draw emoji, so only the text is shown
+        status_message: Some(profile.status_text.trim())
+            .filter(|text| status_current && !text.is_empty())
+            .map(Into::into),
+        overriding: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::HeaderMap, routing::get, Json, Router};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::http::build_durable_client;
+    use crate::test_support::spawn_server;
+
+    fn presence(value: &str) -> SlackPresence {
+        SlackPresence {
+            presence: value.into(),
+        }
+    }
+
+    fn profile(emoji: &str, text: &str) -> SlackProfile {
+        SlackProfile {
+            status_emoji: emoji.into(),
+            status_text: text.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_mapping() {
+        let now = 1_700_000_000;
+        let dnd = SlackDnd {
+            dnd_enabled: true,
+            next_dnd_start_ts: now - 60,
+            next_dnd_end_ts: now + 60,
+            ..Default::default()
+        };
+        let snap = to_snapshot(&presence("active"), &dnd, &SlackProfile::default(), now);
+        assert_eq!(snap.availability, Some(Availability::DoNotDisturb));
+
+        let huddle = SlackProfile {
+            huddle_state: Some("in_a_huddle".into()),
+            ..Default::default()
+        };
+        let snap = to_snapshot(&presence("active"), &SlackDnd::default(), &huddle, now);
+        assert_eq!(snap.activity, Some(Activity::InACall));
+
+        let snap = to_snapshot(
+            &presence("away"),
+            &SlackDnd::default(),
+            &profile("", ""),
+            now,
+        );
+        assert_eq!(snap.availability, Some(Availability::Away));
+        assert_eq!(snap.status_message, None);
+
+        let lunch = profile(":hamburger:", "Lunch ");
+        let snap = to_snapshot(&presence("active"), &SlackDnd::default(), &lunch, now);
+        assert_eq!(snap.availability, Some(Availability::Available));
+        assert_eq!(snap.status_message.as_deref(), Some("Lunch"));
+
+        let expired = SlackProfile {
+            status_expiration: now - 1,
+            ..profile(":spiral_calendar_pad:", "In a meeting")
+        };
+        let snap = to_snapshot(&presence("active"), &SlackDnd::default(), &expired, now);
+        assert_eq!(snap.activity, Some(Activity::Available));
+        assert_eq!(snap.status_message, None);
+    }
+
+    async fn fake_method(headers: HeaderMap, method: &'static str) -> Json<Value> {
+        match headers.get("authorization").unwrap().to_str().unwrap() {
+            "Bearer xoxp-test" => {}
+            // A token without the dnd:read scope
+            "Bearer xoxp-no-dnd" if method == "dnd.info" => {
+                return Json(json!({"ok": false, "error": "missing_scope"}))
+            }
+            "Bearer xoxp-no-dnd" => {}
+            _ => return Json(json!({"ok": false, "error": "invalid_auth"})),
+        }
+        Json(match method {
+            "users.getPresence" => json!({"ok": true, "presence": "active"}),
+            "dnd.info" => json!({"ok": true, "dnd_enabled": false, "snooze_enabled": false}),
+            _ => json!({"ok": true, "profile": {
+                "status_text": "In a huddle",
+                "status_emoji": ":headphones:",
+                "status_expiration": 0
+            }}),
+        })
+    }
+
+    async fn serve_fake_slack() -> String {
+        let app = Router::new()
+            .route(
+                "/api/users.getPresence",
+                get(|h| fake_method(h, "users.getPresence")),
+            )
+            .route("/api/dnd.info", get(|h| fake_method(h, "dnd.info")))
+            .route(
+                "/api/users.profile.get",
+                get(|h| fake_method(h, "users.profile.get")),
+            );
+        format!("http://{}/api/", spawn_server(app))
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_against_fake_api() {
+        let url = serve_fake_slack().await;
+
+        let source = SlackSource::new(build_durable_client(), &url, "xoxp-test".parse().unwrap());
+        let snapshot = source.snapshot().await.unwrap();
+        assert_eq!(snapshot.availability, Some(Availability::Busy));
+        assert_eq!(snapshot.activity, Some(Activity::InACall));
+        assert_eq!(snapshot.status_message.as_deref(), Some("In a huddle"));
+
+        let source = SlackSource::new(build_durable_client(), &url, "xoxp-wrong".parse().unwrap());
+        let err = source.snapshot().await.unwrap_err();
+        assert!(err.to_string().contains("invalid_auth"));
+    }
+
+    #[tokio::test]
+    async fn test_failed_dnd_info_is_an_error() {
+        let url = serve_fake_slack().await;
+
+        let source = SlackSource::new(build_durable_client(), &url, "xoxp-no-dnd".parse().unwrap());
+        let err = source.snapshot().await.unwrap_err();
+        assert!(err.to_string().contains("dnd.info failed: missing_scope"));
+    }
+}
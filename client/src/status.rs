@@ -1,133 +1,225 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Instant};
 
-use chrono::{DateTime, Duration, Local, NaiveDateTime, Utc};
-use serde::{de, Deserialize, Deserializer};
+use chrono::{DateTime, Duration, Local, NaiveDateTime, TimeZone, Utc};
+use reqwest::header::RETRY_AFTER;
+use reqwest_middleware::RequestBuilder;
+use serde::{de, de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
 
+use crate::error::{Error, Result};
 use crate::http::DurableClient;
-use crate::oauth::SharedAccessToken;
+use crate::oauth::{DeviceCode, SharedAccessToken};
+use crate::pico::{self, DeliveredScreens, PicoScreen, PicoTarget, Route, ScreenLayout};
+use crate::rules::{Facts, Rendering, Rules};
+use crate::source::{SourceEvent, SourceSnapshot};
+use crate::template::{Templates, Values};
+
+/// Graph v1.0, unless `--graph-url` points elsewhere, e.g. at a fake
+pub const DEFAULT_GRAPH_URL: &str = "https://graph.microsoft.com/v1.0";
+/// Relative to the Graph URL, like the paths below, so the requests can go
+/// in a `Batch`
+pub const PRESENCE_PATH: &str = "/me/presence";
+/// Asks for 50 events in each page of a delta response
+pub const DELTA_PREFER: (&str, &str) = ("Prefer", "odata.maxpagesize=50");
+/// Events per calendarView page. Graph sends 10 unless asked for more.
+const CALENDAR_PAGE_SIZE: usize = 50;
+
+/// The calendarView from `now` until `lookahead` from now. Every poll and
+/// `debug_calendar` use this query.
+pub fn calendar_view_path(now: DateTime<Utc>, lookahead: Duration) -> String {
+    format!(
+        "/me/calendarview?startDateTime={}&endDateTime={}&$select={}&$orderby={}&$top={}",
+        now.format("%Y-%m-%dT%H:%M:%SZ"),
+        (now + lookahead).format("%Y-%m-%dT%H:%M:%SZ"),
+        "id,createdDateTime,lastModifiedDateTime,subject,start,end,attendees",
+        "start/dateTime",
+        CALENDAR_PAGE_SIZE
+    )
+}
 
-#[tracing::instrument]
-pub async fn get_status(
+/// Follows the `nextLink`s from the `first` page of a calendarView until the
+/// window is exhausted
+#[tracing::instrument(skip(first))]
+pub async fn finish_calendar_view(
     client: &DurableClient,
     token: &SharedAccessToken,
-) -> Result<Status, Box<dyn std::error::Error>> {
-    let (pres_result, cal_result) =
-        tokio::join!(get_presence(client, token), get_calendar(client, token));
-
-    let presence = match pres_result {
-        Ok(pres) => pres,
-        Err(err) => return Err(err),
-    };
-    let calendar = match cal_result {
-        Ok(cal) => cal,
-        Err(err) => return Err(err),
-    };
+    first: CalendarViewPage,
+) -> Result<CalendarView> {
+    let mut value = first.value;
+    let mut next_link = first.next_link;
+    while let Some(url) = next_link {
+        tracing::info!("Calendar has more events, fetching {}", url);
+        let page = graph_send::<CalendarViewPage>(client.get(&url), token).await?;
+        value.extend(page.value);
+        next_link = page.next_link;
+    }
+    tracing::trace!("Calendar response: {:?}", value);
+    Ok(CalendarView { value })
+}
 
-    let status = Status::new(&presence, &calendar);
-    tracing::info!("Status: {:#?}", status);
-    Ok(status)
+/// Starts a delta sync of the calendarView between `start` and `end`. The
+/// window is fixed for every delta that follows.
+pub fn calendar_delta_path(start: DateTime<Utc>, end: DateTime<Utc>) -> String {
+    format!(
+        "/me/calendarView/delta?startDateTime={}&endDateTime={}",
+        start.format("%Y-%m-%dT%H:%M:%SZ"),
+        end.format("%Y-%m-%dT%H:%M:%SZ"),
+    )
 }
 
 #[tracing::instrument]
-pub async fn get_calendar(
+pub async fn get_calendar_delta_page(
     client: &DurableClient,
     token: &SharedAccessToken,
-) -> Result<CalendarView, Box<dyn std::error::Error>> {
-    let today = Utc::now();
-    let soon = today + Duration::days(7);
-    let cal_url = format!(
-        "{}?startDateTime={}&endDateTime={}&$select={}&$orderby={}",
-        "https://graph.microsoft.com/v1.0/me/calendarview",
-        today.format("%Y-%m-%dT%H:%M:%S"),
-        soon.format("%Y-%m-%d"),
-        "id,createdDateTime,lastModifiedDateTime,subject,start,end,attendees",
-        "start/dateTime"
-    );
-    tracing::info!("Calendar URL: {:#?}", cal_url);
-    let cal = client
-        .get(cal_url)
-        .header(
-            "Authorization",
-            format!("Bearer {}", token.get_access_token()),
-        )
-        .send()
-        .await?
-        .json::<CalendarView>()
-        .await?;
-    tracing::trace!("Calendar response: {:?}", cal);
-    Ok(cal)
+    url: &str,
+) -> Result<DeltaPage> {
+    let (name, value) = DELTA_PREFER;
+    graph_send(client.get(url).header(name, value), token).await
 }
 
-#[tracing::instrument]
-pub async fn get_presence(
+/// Follows the `nextLink`s from the `first` page of a calendar delta,
+/// collecting the changes up to the `deltaLink` of the next sync
+#[tracing::instrument(skip(first))]
+pub async fn finish_calendar_delta(
     client: &DurableClient,
     token: &SharedAccessToken,
-) -> Result<Presence, Box<dyn std::error::Error>> {
-    let pres = client
-        .get("https://graph.microsoft.com/v1.0/me/presence")
-        .header(
-            "Authorization",
-            format!("Bearer {}", token.get_access_token()),
-        )
-        .send()
-        .await?
-        .json::<Presence>()
-        .await?;
-    tracing::trace!("Presence Response: {:#?}", pres);
-    Ok(pres)
+    first: DeltaPage,
+) -> Result<CalendarDelta> {
+    let mut changes = vec![];
+    let mut page = first;
+    loop {
+        tracing::trace!("Calendar delta page: {:?}", page);
+        for item in page.value {
+            changes.push(EventChange::from_json(item)?);
+        }
+        match (page.next_link, page.delta_link) {
+            (Some(next_link), _) => {
+                page = get_calendar_delta_page(client, token, &next_link).await?;
+            }
+            (None, Some(delta_link)) => {
+                return Ok(CalendarDelta {
+                    changes,
+                    delta_link,
+                })
+            }
+            (None, None) => {
+                return Err(Error::GraphDeserialize(de::Error::custom(
+                    "The calendar delta has neither a nextLink nor a deltaLink",
+                )))
+            }
+        }
+    }
 }
 
-#[tracing::instrument]
+/// Pushes the status to every target whose screen would change, or that is
+/// due for a refresh. The results are in the order of `targets`, with `None`
+/// for a target that was skipped.
+#[tracing::instrument(skip(delivered))]
 pub async fn set_status(
     client: &DurableClient,
     status: &Status,
-    pi_ip_addr: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let pico_url = format!("http://{}/{}", pi_ip_addr, status.uri());
-    tracing::info!("Pi URL {:#?}", pico_url);
-    let pires = client.get(pico_url).send().await?.text().await?;
-    tracing::info!("Pi Response {:#?}", pires);
-    Ok(pires)
+    templates: &Templates,
+    targets: &[PicoTarget],
+    delivered: &mut DeliveredScreens,
+) -> Vec<Option<Result<String>>> {
+    let now = Instant::now();
+    let screens: Vec<PicoScreen> = targets
+        .iter()
+        .map(|target| status.screen_for(target.layout, templates))
+        .collect();
+    let mut pushes = vec![];
+    for (idx, target) in targets.iter().enumerate() {
+        match delivered.push_reason(target, &screens[idx], now) {
+            Some(reason) => {
+                tracing::info!("Pushing to Pico {} because {}", target.name, reason);
+                pushes.push(idx);
+            }
+            None => tracing::info!("Skipping Pico {}, its screen is unchanged", target.name),
+        }
+    }
+
+    let pushed: Vec<PicoTarget> = pushes.iter().map(|&idx| targets[idx].clone()).collect();
+    let results = pico::broadcast(client, &pushed, |target| {
+        let idx = targets.iter().position(|t| t.name == target.name);
+        idx.map(|idx| screens[idx].clone()).unwrap_or_default()
+    })
+    .await;
+
+    let mut outcomes: Vec<Option<Result<String>>> = targets.iter().map(|_| None).collect();
+    for (idx, res) in pushes.into_iter().zip(results) {
+        if res.is_ok() {
+            delivered.record(&targets[idx], screens[idx].clone(), now);
+        }
+        outcomes[idx] = Some(res);
+    }
+    outcomes
 }
 
 #[tracing::instrument]
-pub async fn set_graceful_shutdown(
-    client: &DurableClient,
-    pi_ip_addr: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let url = format!(
-        "http://{}/yellow?line3=  Good bye&line4=    for now...",
-        pi_ip_addr
-    );
-    tracing::info!("Graceful shutdown URL: {:#?}", url);
-    let pires = client.get(url).send().await?.text().await?;
-    tracing::info!("Pi Response {:#?}", pires);
-    Ok(pires)
+pub async fn set_graceful_shutdown(client: &DurableClient, targets: &[PicoTarget]) -> Result<()> {
+    let results = pico::broadcast(client, targets, |_| {
+        PicoScreen::new(Route::Yellow)
+            .with_line(3, "  Good bye")
+            .with_line(4, "    for now...")
+    })
+    .await;
+    pico::all_delivered(results)
+}
+
+#[tracing::instrument]
+pub async fn set_fatal_error(client: &DurableClient, targets: &[PicoTarget]) -> Result<()> {
+    let results = pico::broadcast(client, targets, |_| {
+        PicoScreen::new(Route::Late)
+            .with_line(2, " FATAL ERROR!")
+            .with_line(3, "   FATAL ERROR!")
+            .with_line(5, "  We can't go on")
+    })
+    .await;
+    pico::all_delivered(results)
 }
 
 #[tracing::instrument]
-pub async fn set_fatal_error(
+pub async fn set_sign_in_required(client: &DurableClient, targets: &[PicoTarget]) -> Result<()> {
+    let results = pico::broadcast(client, targets, |_| {
+        PicoScreen::new(Route::Yellow)
+            .with_line(3, "  Sign-in required")
+            .with_line(5, "  Check pico-client")
+    })
+    .await;
+    pico::all_delivered(results)
+}
+
+#[tracing::instrument]
+pub async fn set_device_code(
     client: &DurableClient,
-    pi_ip_addr: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let url = format!(
-        "http://{}/late?line2= FATAL ERROR!&line3=   FATAL ERROR!&line5=  We can't go on",
-        pi_ip_addr
-    );
-    tracing::info!("Graceless shutdown URL: {:#?}", url);
-    let pires = client.get(url).send().await?.text().await?;
-    tracing::info!("Pi Response {:#?}", pires);
-    Ok(pires)
+    targets: &[PicoTarget],
+    device_code: &DeviceCode,
+) -> Result<()> {
+    let verification_uri = device_code
+        .verification_uri
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+    let results = pico::broadcast(client, targets, |_| {
+        PicoScreen::new(Route::Yellow)
+            .with_line(2, " Sign in at:")
+            .with_line(3, &format!("  {}", verification_uri))
+            .with_line(5, " And enter the code:")
+            .with_line(6, &format!("  {}", device_code.user_code))
+    })
+    .await;
+    pico::all_delivered(results)
 }
+
 #[tracing::instrument]
 pub async fn debug_status(
     client: &DurableClient,
     token: &SharedAccessToken,
-) -> Result<(), Box<dyn std::error::Error>> {
+    graph_url: &str,
+    lookahead: Duration,
+) -> Result<()> {
     tracing::trace!("Debugging status GETs");
-    let presence = debug_presence(client, token).await?;
+    let presence = debug_presence(client, token, graph_url).await?;
     tracing::trace!("Presence: {:?}", presence);
-    let calendar = debug_calendar(client, token).await?;
+    let calendar = debug_calendar(client, token, graph_url, lookahead).await?;
     tracing::trace!("Calendar: {:?}", calendar);
     Ok(())
 }
@@ -136,200 +228,221 @@ pub async fn debug_status(
 pub async fn debug_presence(
     client: &DurableClient,
     token: &SharedAccessToken,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let pres = client
-        .get("https://graph.microsoft.com/v1.0/me/presence")
+    graph_url: &str,
+) -> Result<String> {
+    let pres = graph_get_text(client, token, &format!("{}{}", graph_url, PRESENCE_PATH)).await?;
+    tracing::info!("Presence as text: {:#?}", pres);
+    Ok(pres)
+}
+
+#[tracing::instrument]
+pub async fn debug_calendar(
+    client: &DurableClient,
+    token: &SharedAccessToken,
+    graph_url: &str,
+    lookahead: Duration,
+) -> Result<String> {
+    let cal_url = format!("{}{}", graph_url, calendar_view_path(Utc::now(), lookahead));
+    tracing::trace!("Calendar URL: {:?}", cal_url);
+    let cal = graph_get_text(client, token, &cal_url).await?;
+    tracing::info!("Calendar as text: {:#?}", cal);
+    Ok(cal)
+}
+
+/// Sends a Graph request with the access token and deserializes the response.
+/// Non-success responses become `Error::GraphStatus` so callers can tell
+/// throttling from an expired token.
+pub async fn graph_send<T: DeserializeOwned>(
+    request: RequestBuilder,
+    token: &SharedAccessToken,
+) -> Result<T> {
+    let res = request
         .header(
             "Authorization",
             format!("Bearer {}", token.get_access_token()),
         )
         .send()
-        .await?
+        .await
+        .map_err(Error::GraphTransport)?;
+
+    let status = res.status();
+    if !status.is_success() {
+        let retry_after = res
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|val| val.to_str().ok())
+            .and_then(|val| val.parse::<u64>().ok())
+            .map(std::time::Duration::from_secs);
+        let body = res.text().await.unwrap_or_default();
+        return Err(Error::GraphStatus {
+            status,
+            retry_after,
+            body,
+        });
+    }
+
+    let body = res
         .text()
-        .await?;
-    tracing::info!("Presence as text: {:#?}", pres);
-    Ok(pres)
+        .await
+        .map_err(|err| Error::GraphTransport(err.into()))?;
+    serde_json::from_str(&body).map_err(Error::GraphDeserialize)
 }
 
-#[tracing::instrument]
-pub async fn debug_calendar(
+/// GETs a Graph resource as text, whatever the response status. Only used to
+/// debug unexpected responses.
+async fn graph_get_text(
     client: &DurableClient,
     token: &SharedAccessToken,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let today = Utc::now();
-    let soon = today + Duration::days(7);
-    let cal_url = format!(
-        "{}?startDateTime={}&endDateTime={}&$select={}&$orderby={}",
-        "https://graph.microsoft.com/v1.0/me/calendarview",
-        today.format("%Y-%m-%dT%H:%M:%S"),
-        soon.format("%Y-%m-%d"),
-        "id,createdDateTime,lastModifiedDateTime,subject,start,end,attendees",
-        "start/dateTime"
-    );
-    tracing::trace!("Calendar URL: {:?}", cal_url);
-    let cal = client
-        .get(cal_url)
+    url: &str,
+) -> Result<String> {
+    client
+        .get(url)
         .header(
             "Authorization",
             format!("Bearer {}", token.get_access_token()),
         )
         .send()
-        .await?
+        .await
+        .map_err(Error::GraphTransport)?
         .text()
-        .await?;
-    tracing::info!("Calendar as text: {:#?}", cal);
-    Ok(cal)
+        .await
+        .map_err(|err| Error::GraphTransport(err.into()))
 }
 
 #[derive(Clone, Debug)]
 pub struct Status {
     availability: Availability,
     activity: Activity,
-    event_start: DateTime<Utc>,
-    event_end: DateTime<Utc>,
-    event_subject: String,
-    event_attendee_count: usize,
+    /// The event that is going on, or else the next one
+    event: Option<SourceEvent>,
+    /// The next event that hasn't started yet
+    next: Option<SourceEvent>,
+    status_message: Option<String>,
+    rendering: Rendering,
 }
 
 impl Status {
-    pub fn new(presence: &Presence, calendar: &CalendarView) -> Self {
-        // This assumes that the CalendarView is ordered by start/dateTime
-        let next_event = calendar.value.iter().find(|evt| evt.end > Utc::now());
-        Self {
-            event_attendee_count: next_event
-                .map(|mtg| mtg.attendees.len())
-                .unwrap_or_default(),
-            availability: presence.availability.clone(),
-            activity: presence.activity.clone(),
-            event_start: next_event.map(|mtg| mtg.start).unwrap_or_default(),
-            event_end: next_event.map(|mtg| mtg.end).unwrap_or_default(),
-            event_subject: next_event
-                .map(|mtg| mtg.subject.clone())
+    pub fn from_snapshot(snapshot: &SourceSnapshot, rules: &Rules) -> Self {
+        let now = Utc::now();
+        // This assumes that the events are ordered by start
+        let mut status = Self {
+            availability: snapshot
+                .availability
+                .unwrap_or(Availability::PresenceUnknown),
+            activity: snapshot.activity.unwrap_or(Activity::PresenceUnknown),
+            event: snapshot.events.iter().find(|evt| evt.end > now).cloned(),
+            next: snapshot.events.iter().find(|evt| evt.start > now).cloned(),
+            status_message: snapshot.status_message.clone(),
+            // Picked below, once the status can tell whether you're busy
+            rendering: Rendering::default(),
+        };
+        status.rendering = rules.evaluate(&Facts {
+            availability: status.availability,
+            activity: status.activity,
+            busy: status.is_busy(),
+            late: status.is_late(),
+            subject: status
+                .event
+                .as_ref()
+                .map(|evt| evt.subject.as_str())
                 .unwrap_or_default(),
-        }
-    }
-
-    pub fn uri(&self) -> String {
-        format!(
-            "{}?line1={}&line2={}&line3={}&line5={}&line6={}&line7={}",
-            self.screen_color(),
-            self.line1(),
-            self.line2(),
-            self.line3(),
-            self.line5(),
-            self.line6(),
-            self.line7(),
-        )
+            time: Local::now().time(),
+        });
+        status
     }
 
-    pub fn is_busy(&self) -> bool {
-        let now = Utc::now();
-        now > self.event_start && now < self.event_end
+    /// Every line the `templates` render to something
+    pub fn screen(&self, templates: &Templates) -> PicoScreen {
+        let mut screen = PicoScreen::new(self.screen_color());
+        for (slot, line) in screen
+            .lines
+            .iter_mut()
+            .zip(templates.render(&self.values()))
+        {
+            *slot = Some(line).filter(|line| !line.is_empty());
+        }
+        screen
     }
 
-    pub fn is_late(&self) -> bool {
-        if !self.is_busy() {
-            return false;
+    pub fn screen_for(&self, layout: ScreenLayout, templates: &Templates) -> PicoScreen {
+        match layout {
+            ScreenLayout::Full => self.screen(templates),
+            ScreenLayout::Summary => self.summary_screen(),
         }
-        matches!(self.availability, Availability::Away)
     }
 
-    pub fn screen_color(&self) -> String {
-        let color = match self.availability {
-            // Green
-            Availability::Available => "green".into(),
-            // Yeller
-            Availability::AvailableIdle => "yellow".into(),
-            Availability::Away => "yellow".into(),
-            Availability::BeRightBack => "yellow".into(),
-            Availability::Offline => "yellow".into(),
-            Availability::PresenceUnknown => "yellow".into(),
-            // Red
-            Availability::Busy => "red".into(),
-            Availability::BusyIdle => "red".into(),
-            Availability::DoNotDisturb => "red".into(),
-        };
-        if self.is_late() {
-            "late".into()
-        } else {
-            color
-        }
+    /// Only the color and a single line, e.g. "In a meeting until 03:30 pm"
+    pub fn summary_screen(&self) -> PicoScreen {
+        PicoScreen::new(self.screen_color()).with_line(4, &self.summary_line())
     }
 
-    fn line1(&self) -> String {
-        format!("{:>28}", Local::now().format("%I:%M %P"))
+    fn summary_line(&self) -> String {
+        match &self.event {
+            Some(evt) if self.is_busy() => format!(
+                " In a meeting until {}",
+                evt.end.with_timezone(&Local).format("%I:%M %P")
+            ),
+            Some(evt)
+                if evt.start.with_timezone(&Local).date_naive() == Local::now().date_naive() =>
+            {
+                format!(
+                    " Free until {}",
+                    evt.start.with_timezone(&Local).format("%I:%M %P")
+                )
+            }
+            _ => format!(" {}", self.rendering.availability_text),
+        }
     }
 
-    fn line2(&self) -> String {
-        let value = match self.availability {
-            Availability::Available => "Available",
-            Availability::AvailableIdle => "Available (Idle)",
-            Availability::Away => "Away from Computer",
-            Availability::BeRightBack => "Be Right Back",
-            Availability::Busy => "Busy",
-            Availability::BusyIdle => "Busy (Idle)",
-            Availability::DoNotDisturb => "Do Not Disturb",
-            Availability::Offline => "Offline",
-            Availability::PresenceUnknown => "Dono",
-        };
-        format!(" {}", value)
+    pub fn is_busy(&self) -> bool {
+        let now = Utc::now();
+        self.event
+            .as_ref()
+            .is_some_and(|evt| now > evt.start && now < evt.end)
     }
 
-    fn line3(&self) -> String {
-        let value = match self.activity {
-            Activity::Available => "(Available)",
-            Activity::Away => "(Away)",
-            Activity::BeRightBack => "(Be Right Back)",
-            Activity::Busy => "(Busy)",
-            Activity::DoNotDisturb => "(Do Not Disturb)",
-            Activity::InACall => "(In a Call)",
-            Activity::InAConferenceCall => "(In a Conference Call)",
-            Activity::Inactive => "(Inactive)",
-            Activity::InAMeeting => "(In a Meeting)",
-            Activity::Offline => "(Offline)",
-            Activity::OffWork => "(Off Work!)",
-            Activity::OutOfOffice => "(Out of Office!)",
-            Activity::PresenceUnknown => "(Presence Unknown ??)",
-            Activity::Presenting => "(Presenting)",
-            Activity::UrgentInterruptionsOnly => "(Urgent Interruptions ONLY)",
-        };
-        format!(" {}", value)
+    /// The next moment the status changes by itself, i.e. an event starts or
+    /// ends, even when no source has anything new to say
+    pub fn next_change(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let event = self.event.iter().flat_map(|evt| [evt.start, evt.end]);
+        let next = self.next.iter().map(|evt| evt.start);
+        event.chain(next).filter(|at| *at > now).min()
     }
 
-    fn line5(&self) -> String {
-        let value: String = if self.is_busy() {
-            "Event goes until:".into()
-        } else {
-            format!("Next Event ({}):", self.event_start.format("%m/%d"))
-        };
-        format!(" {}", value)
+    pub fn is_late(&self) -> bool {
+        if !self.is_busy() {
+            return false;
+        }
+        matches!(self.availability, Availability::Away)
     }
 
-    fn line6(&self) -> String {
-        let time = match self.is_busy() {
-            true => self.event_end,
-            false => self.event_start,
-        };
-        format!(
-            "  {} ({})",
-            time.with_timezone(&Local).format("%I:%M %P"),
-            self.event_subject
-        )
+    pub fn screen_color(&self) -> Route {
+        self.rendering.route
     }
 
-    fn line7(&self) -> String {
-        format!("  {} attendees", self.event_attendee_count)
+    fn values(&self) -> Values<'_> {
+        Values {
+            now: Local::now(),
+            availability: &self.rendering.availability_text,
+            activity: &self.rendering.activity_text,
+            message: self.status_message.as_deref(),
+            event: self.event.as_ref(),
+            next: self.next.as_ref(),
+            busy: self.is_busy(),
+            late: self.is_late(),
+        }
     }
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Presence {
+    #[allow(dead_code)]
     pub id: String,
     pub availability: Availability,
     pub activity: Activity,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 struct Attendee {
     #[serde(rename = "type")]
     pub _type: String,
@@ -340,17 +453,84 @@ pub struct CalendarView {
     pub value: Vec<Event>,
 }
 
+/// One page of a calendarView response
 #[derive(Clone, Debug, Deserialize)]
+pub struct CalendarViewPage {
+    value: Vec<Event>,
+    #[serde(rename = "@odata.nextLink")]
+    next_link: Option<String>,
+}
+
+/// Serializes in Graph's own shape, so a stored event reads back like a
+/// fetched one
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Event {
-    subject: String,
-    #[serde(deserialize_with = "deser_msgraph_datetimezone_utc")]
-    start: DateTime<Utc>,
-    #[serde(deserialize_with = "deser_msgraph_datetimezone_utc")]
-    end: DateTime<Utc>,
+    pub subject: String,
+    #[serde(
+        deserialize_with = "deser_msgraph_datetimezone_utc",
+        serialize_with = "ser_msgraph_datetimezone_utc"
+    )]
+    pub start: DateTime<Utc>,
+    #[serde(
+        deserialize_with = "deser_msgraph_datetimezone_utc",
+        serialize_with = "ser_msgraph_datetimezone_utc"
+    )]
+    pub end: DateTime<Utc>,
     attendees: Vec<Attendee>,
 }
 
+/// The changes since the last `calendarView/delta` sync
+#[derive(Clone, Debug)]
+pub struct CalendarDelta {
+    pub changes: Vec<EventChange>,
+    /// Where to ask for the changes after these
+    pub delta_link: String,
+}
+
+#[derive(Clone, Debug)]
+pub enum EventChange {
+    /// The event was created or updated
+    Upserted { id: String, event: Event },
+    /// The event was deleted, or moved out of the window
+    Removed { id: String },
+}
+
+impl EventChange {
+    fn from_json(item: serde_json::Value) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct Tombstone {
+            id: String,
+            #[serde(rename = "@removed")]
+            removed: Option<serde_json::Value>,
+        }
+        let tombstone = Tombstone::deserialize(&item).map_err(Error::GraphDeserialize)?;
+        if tombstone.removed.is_some() {
+            return Ok(EventChange::Removed { id: tombstone.id });
+        }
+        Ok(EventChange::Upserted {
+            id: tombstone.id,
+            event: serde_json::from_value(item).map_err(Error::GraphDeserialize)?,
+        })
+    }
+}
+
+/// One page of a `calendarView/delta` response
 #[derive(Clone, Debug, Deserialize)]
+pub struct DeltaPage {
+    value: Vec<serde_json::Value>,
+    #[serde(rename = "@odata.nextLink")]
+    next_link: Option<String>,
+    #[serde(rename = "@odata.deltaLink")]
+    delta_link: Option<String>,
+}
+
+impl Event {
+    pub fn attendee_count(&self) -> usize {
+        self.attendees.len()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
 pub enum Availability {
     Available,
     AvailableIdle,
@@ -363,7 +543,19 @@ pub enum Availability {
     PresenceUnknown,
 }
 
-fn deser_msgraph_datetimezone_utc<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+impl Availability {
+    /// Whether this availability means you shouldn't be interrupted
+    pub fn is_busy(&self) -> bool {
+        matches!(
+            self,
+            Availability::Busy | Availability::BusyIdle | Availability::DoNotDisturb
+        )
+    }
+}
+
+fn deser_msgraph_datetimezone_utc<'de, D>(
+    deserializer: D,
+) -> std::result::Result<DateTime<Utc>, D::Error>
 where
     D: Deserializer<'de>,
 {
@@ -375,10 +567,26 @@ where
     };
     NaiveDateTime::parse_from_str(datetime, "%Y-%m-%dT%H:%M:%S%.f")
         .map_err(de::Error::custom)
-        .map(|val| DateTime::<Utc>::from_utc(val, Utc))
+        .map(|val| Utc.from_utc_datetime(&val))
 }
 
-#[derive(Clone, Debug, Deserialize)]
+fn ser_msgraph_datetimezone_utc<S>(
+    datetime: &DateTime<Utc>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let mut map_datetime_obj = HashMap::new();
+    map_datetime_obj.insert(
+        "dateTime",
+        datetime.format("%Y-%m-%dT%H:%M:%S%.f").to_string(),
+    );
+    map_datetime_obj.insert("timeZone", "UTC".into());
+    map_datetime_obj.serialize(serializer)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
 pub enum Activity {
     Available,
     Away,
@@ -402,6 +610,7 @@ mod tests {
     use std::vec;
 
     use super::*;
+    use crate::source::graph;
 
     #[test]
     fn test_uri_availabile_future_event() {
@@ -411,23 +620,28 @@ mod tests {
             value: vec![future_event.clone()],
         };
 
-        let status = Status::new(&presence, &calendar);
-        println!("{:?}", status.uri());
+        let status =
+            Status::from_snapshot(&graph::to_snapshot(&presence, &calendar), &Rules::default());
+        let screen = status.screen(&Templates::default());
+        println!("{:?}", screen.path_and_query());
 
         assert!(!status.is_busy());
         assert_eq!(
-            status.uri(),
-            format!(
-                "{}?line1={:>28}&line2= {}&line3= ({})&line5= Next Event ({}):&line6=  {} ({})&line7=  {} attendees",
-                "green",
-                Local::now().format("%I:%M %P"),
-                "Available",
-                "Available",
-                next_start.format("%m/%d"),
-                next_start.format("%I:%M %P"),
-                future_event.subject,
-                future_event.attendees.len()
-           )
+            screen,
+            PicoScreen::new(Route::Green)
+                .with_line(1, &format!("{:>28}", Local::now().format("%I:%M %P")))
+                .with_line(2, " Available")
+                .with_line(3, " (Available)")
+                .with_line(5, &format!(" Next Event ({}):", next_start.format("%m/%d")))
+                .with_line(
+                    6,
+                    &format!(
+                        "  {} ({})",
+                        next_start.format("%I:%M %P"),
+                        future_event.subject
+                    )
+                )
+                .with_line(7, &format!("  {} attendees", future_event.attendees.len()))
         );
     }
 
@@ -439,22 +653,23 @@ mod tests {
             value: vec![event.clone()],
         };
 
-        let status = Status::new(&presence, &cal);
-        println!("{:?}", status.uri());
+        let status = Status::from_snapshot(&graph::to_snapshot(&presence, &cal), &Rules::default());
+        let screen = status.screen(&Templates::default());
+        println!("{:?}", screen.path_and_query());
         assert!(status.is_busy());
 
         assert_eq!(
-            status.uri(),
-            format!(
-                "{}?line1={:>28}&line2= {}&line3= ({})&line5= Event goes until:&line6=  {} ({})&line7=  {} attendees",
-                "red",
-                Local::now().format("%I:%M %P"),
-                "Busy",
-                "In a Call",
-                end.format("%I:%M %P"),
-                event.subject,
-                event.attendees.len()
-           )
+            screen,
+            PicoScreen::new(Route::Red)
+                .with_line(1, &format!("{:>28}", Local::now().format("%I:%M %P")))
+                .with_line(2, " Busy")
+                .with_line(3, " (In a Call)")
+                .with_line(5, " Event goes until:")
+                .with_line(
+                    6,
+                    &format!("  {} ({})", end.format("%I:%M %P"), event.subject)
+                )
+                .with_line(7, &format!("  {} attendees", event.attendees.len()))
         );
     }
 
@@ -466,24 +681,76 @@ mod tests {
             value: vec![event.clone()],
         };
 
-        let status = Status::new(&presence, &cal);
-        println!("{:?}", status.uri());
+        let status = Status::from_snapshot(&graph::to_snapshot(&presence, &cal), &Rules::default());
+        let screen = status.screen(&Templates::default());
+        println!("{:?}", screen.path_and_query());
         assert!(status.is_busy());
         assert!(status.is_late());
 
         assert_eq!(
-            status.uri(),
+            screen,
+            PicoScreen::new(Route::Late)
+                .with_line(1, &format!("{:>28}", Local::now().format("%I:%M %P")))
+                .with_line(2, " Away from Computer")
+                .with_line(3, " (Away)")
+                .with_line(5, " Event goes until:")
+                .with_line(
+                    6,
+                    &format!("  {} ({})", end.format("%I:%M %P"), event.subject)
+                )
+                .with_line(7, &format!("  {} attendees", event.attendees.len()))
+        );
+    }
+
+    #[test]
+    fn test_summary_uri_busy_curr_event() {
+        let presence = build_presence(Availability::Busy, Activity::InAMeeting);
+        let (event, _, end) = build_current_cal_event("Current Events");
+        let cal = CalendarView { value: vec![event] };
+
+        let status = Status::from_snapshot(&graph::to_snapshot(&presence, &cal), &Rules::default());
+        assert_eq!(
+            status
+                .screen_for(ScreenLayout::Summary, &Templates::default())
+                .path_and_query(),
             format!(
-                "{}?line1={:>28}&line2= {}&line3= ({})&line5= Event goes until:&line6=  {} ({})&line7=  {} attendees",
-                "late",
-                Local::now().format("%I:%M %P"),
-                "Away from Computer",
-                "Away",
-                end.format("%I:%M %P"),
-                event.subject,
-                event.attendees.len()
-           )
+                "red?line4=%20In%20a%20meeting%20until%20{}",
+                end.format("%I%%3A%M%%20%P")
+            )
+        );
+    }
+
+    #[test]
+    fn test_uri_shows_status_message() {
+        let presence = build_presence(Availability::Available, Activity::Available);
+        let (event, _, _) = build_future_cal_event("Test One");
+        let mut snapshot = graph::to_snapshot(&presence, &CalendarView { value: vec![event] });
+        snapshot.status_message = Some("Lunch & Learn".into());
+
+        let status = Status::from_snapshot(&snapshot, &Rules::default());
+        let screen = status.screen(&Templates::default());
+        assert_eq!(screen.lines[3].as_deref(), Some(" \"Lunch & Learn\""));
+        assert!(screen
+            .path_and_query()
+            .contains("&line4=%20%22Lunch%20%26%20Learn%22&line5="));
+    }
+
+    #[test]
+    fn test_next_change() {
+        let presence = build_presence(Availability::Busy, Activity::InAMeeting);
+        let (current, _, _) = build_current_cal_event("Current Events");
+        let (future, _, _) = build_future_cal_event("Test One");
+        let cal = CalendarView {
+            value: vec![current.clone(), future.clone()],
+        };
+
+        let status = Status::from_snapshot(&graph::to_snapshot(&presence, &cal), &Rules::default());
+        // The current event ends before the next one starts
+        assert_eq!(
+            status.next_change(Utc::now()),
+            Some(current.end.min(future.start))
         );
+        assert_eq!(status.next_change(future.end), None);
     }
 
     fn build_presence(availability: Availability, activity: Activity) -> Presence {
@@ -0,0 +1,563 @@
+use std::{iter::Peekable, str::Chars, str::FromStr};
+
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, Local, Utc,
+};
+
+use crate::layout::{self, Align, Line, ROWS};
+use crate::source::SourceEvent;
+
+/// How times are shown unless a template asks otherwise, e.g. "03:30 pm"
+const DEFAULT_TIME_FORMAT: &str = "%I:%M %P";
+
+/// The templates of the full layout, matching the original fixed screen
+const DEFAULT_LINES: [&str; ROWS] = [
+    "{align right}{now}",
+    " {availability}",
+    " {activity}",
+    "{if message} \"{message}\"{end}",
+    " {if busy}Event goes until:{else}{if event}Next Event ({event.start:%m/%d}):{else}No upcoming events{end}{end}",
+    "{if event}  {if busy}{event.end}{else}{event.start}{end} ({event.subject}){end}",
+    "{if event}  {attendees} attendees{end}",
+];
+
+/// Everything a template can show
+#[derive(Clone, Debug)]
+pub struct Values<'a> {
+    pub now: DateTime<Local>,
+    pub availability: &'a str,
+    pub activity: &'a str,
+    pub message: Option<&'a str>,
+    /// The event that is going on, or else the next one
+    pub event: Option<&'a SourceEvent>,
+    /// The next event that hasn't started yet
+    pub next: Option<&'a SourceEvent>,
+    pub busy: bool,
+    pub late: bool,
+}
+
+/// One line of the Pico screen, e.g. `{now:%H:%M} {availability}`.
+///
+/// `{name}` or `{name:strftime}` shows a value, and `{name|>28}` pads it
+/// (`<` left, `>` right or `^` centered). `{align right}` aligns the whole
+/// line, `left` and `center` work too. `{if cond}..{else}..{end}` shows one
+/// branch or the other. `{{` and `}}` are literal braces.
+///
+/// Texts like the event subject are shortened when the line doesn't fit,
+/// see `layout::fit`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Text(String),
+    Value {
+        var: Var,
+        format: Option<String>,
+        align: Option<(Align, usize)>,
+    },
+    If {
+        cond: Cond,
+        then: Vec<Segment>,
+        otherwise: Vec<Segment>,
+    },
+    Align(Align),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Var {
+    Now,
+    Availability,
+    Activity,
+    Message,
+    EventSubject,
+    EventStart,
+    EventEnd,
+    Attendees,
+    NextSubject,
+    NextStart,
+    NextEnd,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Cond {
+    Busy,
+    Free,
+    Late,
+    Message,
+    Event,
+    Next,
+}
+
+impl Var {
+    fn parse(name: &str) -> Option<Self> {
+        let var = match name {
+            "now" => Var::Now,
+            "availability" => Var::Availability,
+            "activity" => Var::Activity,
+            "message" => Var::Message,
+            "event.subject" => Var::EventSubject,
+            "event.start" => Var::EventStart,
+            "event.end" => Var::EventEnd,
+            "attendees" => Var::Attendees,
+            "next.subject" => Var::NextSubject,
+            "next.start" => Var::NextStart,
+            "next.end" => Var::NextEnd,
+            _ => return None,
+        };
+        Some(var)
+    }
+
+    /// Free text that `layout::fit` may shorten
+    fn is_elastic(self) -> bool {
+        matches!(
+            self,
+            Var::Availability | Var::Activity | Var::Message | Var::EventSubject | Var::NextSubject
+        )
+    }
+
+    fn is_time(self) -> bool {
+        matches!(
+            self,
+            Var::Now | Var::EventStart | Var::EventEnd | Var::NextStart | Var::NextEnd
+        )
+    }
+}
+
+impl Cond {
+    fn parse(name: &str) -> Option<Self> {
+        let cond = match name {
+            "busy" => Cond::Busy,
+            "free" => Cond::Free,
+            "late" => Cond::Late,
+            "message" => Cond::Message,
+            "event" => Cond::Event,
+            "next" => Cond::Next,
+            _ => return None,
+        };
+        Some(cond)
+    }
+
+    fn holds(self, values: &Values) -> bool {
+        match self {
+            Cond::Busy => values.busy,
+            Cond::Free => !values.busy,
+            Cond::Late => values.late,
+            Cond::Message => values.message.is_some(),
+            Cond::Event => values.event.is_some(),
+            Cond::Next => values.next.is_some(),
+        }
+    }
+}
+
+impl Template {
+    /// Renders the line. Values that aren't there, e.g. `{next.subject}`
+    /// without a next event, render as nothing.
+    pub fn render(&self, values: &Values) -> Line {
+        let mut line = Line::default();
+        render_segments(&self.segments, values, &mut line);
+        line
+    }
+}
+
+fn render_segments(segments: &[Segment], values: &Values, line: &mut Line) {
+    for segment in segments {
+        match segment {
+            Segment::Text(text) => line.push(text, false),
+            Segment::Value { var, format, align } => {
+                let value = render_value(*var, format.as_deref(), values);
+                match align {
+                    Some((Align::Left, width)) => {
+                        line.push(&format!("{:<width$}", value, width = width), false)
+                    }
+                    Some((Align::Right, width)) => {
+                        line.push(&format!("{:>width$}", value, width = width), false)
+                    }
+                    Some((Align::Center, width)) => {
+                        line.push(&format!("{:^width$}", value, width = width), false)
+                    }
+                    None => line.push(&value, var.is_elastic()),
+                }
+            }
+            Segment::Align(align) => line.align = *align,
+            Segment::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let branch = if cond.holds(values) { then } else { otherwise };
+                render_segments(branch, values, line);
+            }
+        }
+    }
+}
+
+fn render_value(var: Var, format: Option<&str>, values: &Values) -> String {
+    let time = |time: Option<DateTime<Utc>>| {
+        time.map(|time| {
+            time.with_timezone(&Local)
+                .format(format.unwrap_or(DEFAULT_TIME_FORMAT))
+                .to_string()
+        })
+        .unwrap_or_default()
+    };
+    match var {
+        Var::Now => time(Some(values.now.with_timezone(&Utc))),
+        Var::Availability => values.availability.into(),
+        Var::Activity => values.activity.into(),
+        Var::Message => values.message.unwrap_or_default().into(),
+        Var::EventSubject => values
+            .event
+            .map(|evt| evt.subject.clone())
+            .unwrap_or_default(),
+        Var::EventStart => time(values.event.map(|evt| evt.start)),
+        Var::EventEnd => time(values.event.map(|evt| evt.end)),
+        Var::Attendees => values
+            .event
+            .map(|evt| evt.attendee_count.to_string())
+            .unwrap_or_default(),
+        Var::NextSubject => values
+            .next
+            .map(|evt| evt.subject.clone())
+            .unwrap_or_default(),
+        Var::NextStart => time(values.next.map(|evt| evt.start)),
+        Var::NextEnd => time(values.next.map(|evt| evt.end)),
+    }
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        let mut chars = template.chars().peekable();
+        let (segments, end) = parse_block(&mut chars)?;
+        match end {
+            Terminator::Eof => Ok(Template { segments }),
+            Terminator::Else => Err("{else} without {if}".into()),
+            Terminator::End => Err("{end} without {if}".into()),
+        }
+    }
+}
+
+enum Terminator {
+    Eof,
+    Else,
+    End,
+}
+
+fn parse_block(chars: &mut Peekable<Chars>) -> Result<(Vec<Segment>, Terminator), String> {
+    let mut segments = vec![];
+    let mut text = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '}' => return Err("Unexpected '}', write '}}' for a literal brace".into()),
+            '{' => {
+                let mut tag = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => tag.push(c),
+                        None => return Err("Unterminated '{'".into()),
+                    }
+                }
+                if !text.is_empty() {
+                    segments.push(Segment::Text(std::mem::take(&mut text)));
+                }
+                match tag.as_str() {
+                    "else" => return Ok((segments, Terminator::Else)),
+                    "end" => return Ok((segments, Terminator::End)),
+                    _ => {}
+                }
+                if let Some(align) = tag.strip_prefix("align ") {
+                    let align = match align.trim() {
+                        "left" => Align::Left,
+                        "center" => Align::Center,
+                        "right" => Align::Right,
+                        align => return Err(format!("Unknown alignment {:?}", align)),
+                    };
+                    segments.push(Segment::Align(align));
+                } else if let Some(cond) = tag.strip_prefix("if ") {
+                    let cond = Cond::parse(cond.trim())
+                        .ok_or_else(|| format!("Unknown condition {:?}", cond.trim()))?;
+                    let (then, end) = parse_block(chars)?;
+                    let otherwise = match end {
+                        Terminator::End => vec![],
+                        Terminator::Else => match parse_block(chars)? {
+                            (otherwise, Terminator::End) => otherwise,
+                            _ => return Err("{if} without {end}".into()),
+                        },
+                        Terminator::Eof => return Err("{if} without {end}".into()),
+                    };
+                    segments.push(Segment::If {
+                        cond,
+                        then,
+                        otherwise,
+                    });
+                } else {
+                    segments.push(parse_value(&tag)?);
+                }
+            }
+            c => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        segments.push(Segment::Text(text));
+    }
+    Ok((segments, Terminator::Eof))
+}
+
+/// Parses the inside of `{name:format|align}`
+fn parse_value(tag: &str) -> Result<Segment, String> {
+    let (tag, align) = match tag.rsplit_once('|') {
+        Some((tag, align)) => (tag, Some(parse_align(align)?)),
+        None => (tag, None),
+    };
+    let (name, format) = match tag.split_once(':') {
+        Some((name, format)) => (name, Some(format.to_string())),
+        None => (tag, None),
+    };
+    let var = Var::parse(name.trim()).ok_or_else(|| format!("Unknown variable {:?}", name))?;
+    if format.is_some() && !var.is_time() {
+        return Err(format!("{:?} is not a time and can't have a format", name));
+    }
+    // chrono panics while rendering an invalid format, so reject it up front
+    if let Some(format) = &format {
+        if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+            return Err(format!("{:?} is not a valid time format", format));
+        }
+    }
+    Ok(Segment::Value { var, format, align })
+}
+
+fn parse_align(spec: &str) -> Result<(Align, usize), String> {
+    let align = match spec.chars().next() {
+        Some('<') => Align::Left,
+        Some('>') => Align::Right,
+        Some('^') => Align::Center,
+        _ => return Err(format!("Expected an alignment like >28, got {:?}", spec)),
+    };
+    let width = spec[1..]
+        .parse()
+        .map_err(|_| format!("Expected an alignment like >28, got {:?}", spec))?;
+    Ok((align, width))
+}
+
+/// The templates of all seven lines. A line that renders empty is left off
+/// the screen, and may take the overflow of the line above it.
+#[derive(Clone, Debug)]
+pub struct Templates {
+    lines: [Template; ROWS],
+}
+
+impl Templates {
+    /// Replaces the template of line `number`, counting from 1
+    pub fn with_line(mut self, number: usize, template: Template) -> Self {
+        self.lines[number - 1] = template;
+        self
+    }
+
+    /// Whether any line shows `{now}`, so the screen changes every minute
+    pub fn shows_clock(&self) -> bool {
+        fn shows_clock(segments: &[Segment]) -> bool {
+            segments.iter().any(|segment| match segment {
+                Segment::Value { var, .. } => *var == Var::Now,
+                Segment::If {
+                    then, otherwise, ..
+                } => shows_clock(then) || shows_clock(otherwise),
+                Segment::Text(_) | Segment::Align(_) => false,
+            })
+        }
+        self.lines.iter().any(|line| shows_clock(&line.segments))
+    }
+
+    /// The rendered lines, fitted to the screen with `layout::fit`
+    pub fn render(&self, values: &Values) -> Vec<String> {
+        let lines: Vec<Line> = self.lines.iter().map(|line| line.render(values)).collect();
+        layout::fit(&lines)
+    }
+
+    /// Checks that every line fits the screen, whether or not you're busy,
+    /// once its texts are shortened
+    pub fn validate(&self) -> Result<(), String> {
+        let event = SourceEvent {
+            subject: "Subject".into(),
+            start: Utc::now(),
+            end: Utc::now(),
+            attendee_count: 10,
+        };
+        for busy in [false, true] {
+            let values = Values {
+                now: Local::now(),
+                availability: "Availability",
+                activity: "Activity",
+                message: Some("Message"),
+                event: Some(&event),
+                next: Some(&event),
+                busy,
+                late: busy,
+            };
+            for (idx, template) in self.lines.iter().enumerate() {
+                layout::validate(&template.render(&values))
+                    .map_err(|err| format!("Line {}: {}", idx + 1, err))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Templates {
+            lines: DEFAULT_LINES
+                .map(|line| line.parse().expect("The built in templates are invalid")),
+        }
+    }
+}
+
+/// `--line 4=TEMPLATE` on the command line
+#[derive(Clone, Debug)]
+pub struct LineTemplate {
+    pub number: usize,
+    pub template: Template,
+}
+
+impl FromStr for LineTemplate {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (number, template) = value
+            .split_once('=')
+            .ok_or_else(|| format!("Expected LINE=TEMPLATE, got {:?}", value))?;
+        let number = number
+            .trim()
+            .parse()
+            .ok()
+            .filter(|number| (1..=ROWS).contains(number))
+            .ok_or_else(|| format!("The line must be 1 to {}, got {:?}", ROWS, number))?;
+        Ok(LineTemplate {
+            number,
+            template: template.parse()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone};
+
+    use super::*;
+
+    fn values<'a>(event: Option<&'a SourceEvent>, busy: bool) -> Values<'a> {
+        Values {
+            now: Local.with_ymd_and_hms(2022, 9, 14, 15, 5, 0).unwrap(),
+            availability: "Busy",
+            activity: "(In a Call)",
+            message: None,
+            event,
+            next: None,
+            busy,
+            late: false,
+        }
+    }
+
+    fn render(template: &str, values: &Values) -> String {
+        template.parse::<Template>().unwrap().render(values).text()
+    }
+
+    #[test]
+    fn test_values_and_formats() {
+        let start = Local.with_ymd_and_hms(2022, 9, 14, 15, 0, 0).unwrap();
+        let event = SourceEvent {
+            subject: "Standup".into(),
+            start: start.with_timezone(&Utc),
+            end: (start + Duration::minutes(30)).with_timezone(&Utc),
+            attendee_count: 4,
+        };
+        let values = values(Some(&event), true);
+        assert_eq!(render("{now:%H:%M} {availability}", &values), "15:05 Busy");
+        assert_eq!(
+            render("{event.subject} ends {event.end:%I:%M %P}", &values),
+            "Standup ends 03:30 pm"
+        );
+        assert_eq!(
+            render("{attendees} people{next.start}", &values),
+            "4 people"
+        );
+        assert_eq!(render("[{now:%H:%M|>7}]", &values), "[  15:05]");
+        assert_eq!(render("{{literal}}", &values), "{literal}");
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let template =
+            "{if busy}Until {event.end:%H:%M}{else}{if event}Free{else}Nothing{end}{end}";
+        let start = Local.with_ymd_and_hms(2022, 9, 14, 15, 0, 0).unwrap();
+        let event = SourceEvent {
+            subject: "Standup".into(),
+            start: start.with_timezone(&Utc),
+            end: (start + Duration::minutes(30)).with_timezone(&Utc),
+            attendee_count: 4,
+        };
+        assert_eq!(render(template, &values(Some(&event), true)), "Until 15:30");
+        assert_eq!(render(template, &values(Some(&event), false)), "Free");
+        assert_eq!(render(template, &values(None, false)), "Nothing");
+    }
+
+    #[test]
+    fn test_shows_clock() {
+        assert!(Templates::default().shows_clock());
+        let plain = Templates::default().with_line(1, "{if busy}{now:%H:%M}{end}".parse().unwrap());
+        assert!(plain.shows_clock());
+        let plain = plain.with_line(1, " {availability}".parse().unwrap());
+        assert!(!plain.shows_clock());
+    }
+
+    #[test]
+    fn test_rejects_invalid_templates() {
+        assert!("{if busy}no end".parse::<Template>().is_err());
+        assert!("{end}".parse::<Template>().is_err());
+        assert!("{weather}".parse::<Template>().is_err());
+        assert!("{availability:%H}".parse::<Template>().is_err());
+        assert!("{now:%Q}".parse::<Template>().is_err());
+        assert!("{event.start:%H:%".parse::<Template>().is_err());
+        assert!("{now|28}".parse::<Template>().is_err());
+        assert!("{if sunny}x{end}".parse::<Template>().is_err());
+        assert!("{align top}".parse::<Template>().is_err());
+        assert!("8={now}".parse::<LineTemplate>().is_err());
+        assert_eq!(
+            "{now:%H:%M".parse::<Template>().unwrap_err(),
+            "Unterminated '{'"
+        );
+        assert!("{if busy}x".parse::<Template>().is_err());
+        assert!("{if busy".parse::<Template>().is_err());
+        assert_eq!("4={message}".parse::<LineTemplate>().unwrap().number, 4);
+    }
+
+    #[test]
+    fn test_validate_checks_the_screen_width() {
+        assert!(Templates::default().validate().is_ok());
+        let too_wide = "{now:%A, %d %B %Y at %H:%M}".parse().unwrap();
+        assert!(Templates::default()
+            .with_line(1, too_wide)
+            .validate()
+            .is_err());
+        let shortened = "{if busy}Until {event.end}: {event.subject}{end}"
+            .parse()
+            .unwrap();
+        assert!(Templates::default()
+            .with_line(5, shortened)
+            .validate()
+            .is_ok());
+    }
+}
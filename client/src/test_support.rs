@@ -0,0 +1,59 @@
+//! Fakes shared by the tests that talk to Graph and other HTTP services
+
+use std::net::{SocketAddr, TcpListener};
+
+use axum::{extract::Extension, Router};
+use chrono::{DateTime, Duration, Utc};
+
+/// The URL a `spawn_fake_graph` server is reached at, e.g.
+/// `http://127.0.0.1:1234/v1.0`, for handlers that hand out links
+#[derive(Clone, Debug)]
+pub struct GraphUrl(pub String);
+
+/// A local address nothing listens on yet
+pub fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Serves `app` on a free local port until the test ends
+pub fn spawn_server(app: Router) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener).unwrap();
+    tokio::spawn(server.serve(app.into_make_service()));
+    addr
+}
+
+/// Serves `app` under `/v1.0`, like Graph, and returns the Graph URL. The
+/// handlers can take it as an `Extension<GraphUrl>`.
+pub fn spawn_fake_graph(app: Router) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let graph_url = format!("http://{}/v1.0", listener.local_addr().unwrap());
+    let app = Router::new()
+        .nest("/v1.0", app)
+        .layer(Extension(GraphUrl(graph_url.clone())));
+    let server = axum::Server::from_tcp(listener).unwrap();
+    tokio::spawn(server.serve(app.into_make_service()));
+    graph_url
+}
+
+/// A half hour Graph event with no attendees, as calendarView and its delta
+/// return it
+pub fn graph_event(id: &str, subject: &str, start: DateTime<Utc>) -> serde_json::Value {
+    let time = |at: DateTime<Utc>| {
+        serde_json::json!({
+            "dateTime": at.format("%Y-%m-%dT%H:%M:%S%.f").to_string(),
+            "timeZone": "UTC",
+        })
+    };
+    serde_json::json!({
+        "id": id,
+        "subject": subject,
+        "start": time(start),
+        "end": time(start + Duration::minutes(30)),
+        "attendees": [],
+    })
+}
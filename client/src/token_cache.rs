@@ -0,0 +1,217 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+
+use crate::{
+    error::{Error, Result},
+    oauth::AccessToken,
+};
+
+const KEY_FILE: &str = "cache.key";
+const NONCE_LEN: usize = 12;
+
+/// An on-disk cache of the `AccessToken` for a single client ID and tenant
+/// pair. What keeps the token private are the owner-only permissions of the
+/// cache files. The token is also sealed with ChaCha20-Poly1305, but the key
+/// is generated on first use and stored right next to it, so that only
+/// detects corrupted or tampered files. Anyone who can read the cache
+/// directory can use the token.
+#[derive(Clone, Debug)]
+pub struct TokenCache {
+    dir: PathBuf,
+    client_id: String,
+    tenant_id: String,
+}
+
+impl TokenCache {
+    pub fn new(dir: &Path, client_id: &str, tenant_id: &str) -> Self {
+        TokenCache {
+            dir: dir.into(),
+            client_id: client_id.into(),
+            tenant_id: tenant_id.into(),
+        }
+    }
+
+    /// The platform cache directory, e.g. `~/.cache/pico-client` on Linux
+    pub fn default_dir() -> Option<PathBuf> {
+        dirs::cache_dir().map(|dir| dir.join("pico-client"))
+    }
+
+    /// Returns the cached token, or `None` if nothing has been cached yet or
+    /// the cache could not be read or decrypted.
+    #[tracing::instrument]
+    pub fn load(&self) -> Option<AccessToken> {
+        let sealed = match fs::read(self.token_path()) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                tracing::info!("No cached token available: {}", err);
+                return None;
+            }
+        };
+        if sealed.len() < NONCE_LEN {
+            tracing::warn!("Cached token is truncated. Ignoring it.");
+            return None;
+        }
+
+        let cipher = match self.cipher() {
+            Ok(cipher) => cipher,
+            Err(err) => {
+                tracing::warn!("Could not read the token cache key: {}", err);
+                return None;
+            }
+        };
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let plaintext = match cipher.decrypt(Nonce::from_slice(nonce), ciphertext) {
+            Ok(plaintext) => plaintext,
+            Err(_) => {
+                tracing::warn!("Could not decrypt the cached token. Ignoring it.");
+                return None;
+            }
+        };
+
+        match serde_json::from_slice(&plaintext) {
+            Ok(token) => Some(token),
+            Err(err) => {
+                tracing::warn!("Could not deserialize the cached token: {}", err);
+                None
+            }
+        }
+    }
+
+    #[tracing::instrument]
+    pub fn store(&self, token: &AccessToken) -> Result<()> {
+        let cipher = self.cipher()?;
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let plaintext =
+            serde_json::to_vec(token).map_err(|err| Error::TokenCache(err.to_string()))?;
+        let ciphertext = cipher
+            .encrypt(&nonce, plaintext.as_slice())
+            .map_err(|_| Error::TokenCache("Could not encrypt the token".into()))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        write_private(&self.token_path(), &sealed)?;
+        tracing::info!("Cached token at {:?}", self.token_path());
+        Ok(())
+    }
+
+    fn token_path(&self) -> PathBuf {
+        self.dir.join(format!(
+            "token-{}-{}.bin",
+            sanitize(&self.client_id),
+            sanitize(&self.tenant_id)
+        ))
+    }
+
+    fn cipher(&self) -> io::Result<ChaCha20Poly1305> {
+        let key_path = self.dir.join(KEY_FILE);
+        let key = match fs::read(&key_path) {
+            Ok(key) if key.len() == 32 => {
+                make_private(&key_path)?;
+                key
+            }
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{:?} is not a 32 byte key", key_path),
+                ))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+                write_private(&key_path, &key)?;
+                key.to_vec()
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+}
+
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn write_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    // The mode above only applies to files that don't exist yet
+    make_private(path)?;
+    file.write_all(bytes)
+}
+
+/// Takes away the access of everyone but the owner, e.g. from a file written
+/// by an older version
+#[cfg(unix)]
+fn make_private(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+}
+
+#[cfg(not(unix))]
+fn make_private(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oauth::test_token;
+
+    #[test]
+    fn test_store_and_load_round_trip() {
+        let dir = std::env::temp_dir().join(format!("pico-client-cache-{}", std::process::id()));
+        let cache = TokenCache::new(&dir, "client/1", "common");
+        assert!(cache.load().is_none());
+
+        cache.store(&test_token().snapshot()).unwrap();
+        let token = cache.load().unwrap();
+        assert_eq!(token.access_token, "access");
+
+        // A cache file with looser permissions is tightened on the next store
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            for path in [cache.token_path(), dir.join(KEY_FILE)] {
+                fs::set_permissions(path, fs::Permissions::from_mode(0o644)).unwrap();
+            }
+            cache.store(&test_token().snapshot()).unwrap();
+            assert_eq!(mode(&cache.token_path()), 0o600);
+            assert_eq!(mode(&dir.join(KEY_FILE)), 0o600);
+        }
+
+        // Tampering with the sealed token must be detected, not deserialized
+        let mut sealed = fs::read(cache.token_path()).unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 0xff;
+        fs::write(cache.token_path(), sealed).unwrap();
+        assert!(cache.load().is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -9,6 +9,11 @@ from secrets import ssid, password
 
 def connect(wait_for_conn_secs):
     # Connect to WLAN
+    # Announce ourselves over mDNS as pico-status.local for client discovery
+    try:
+        network.hostname("pico-status")
+    except Exception as e:
+        print(f"Could not set the hostname: {e}")
     wlan = network.WLAN(network.STA_IF)
     wlan.active(True)
     wlan.connect(ssid, password)